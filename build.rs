@@ -0,0 +1,12 @@
+fn main() {
+    // Header of C API is kept in repository, so that it may be used without building crate.
+    #[cfg(feature = "capi")]
+    {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rerun-if-changed=src/capi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        cbindgen::generate(&dir)
+            .expect("generate C header")
+            .write_to_file(format!("{dir}/include/ch57x_keyboard.h"));
+    }
+}
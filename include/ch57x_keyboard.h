@@ -0,0 +1,87 @@
+#ifndef CH57X_KEYBOARD_H
+#define CH57X_KEYBOARD_H
+
+/* Generated by cbindgen from src/capi.rs, don't edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of API call.
+ */
+typedef enum Ch57xStatus {
+  CH57X_STATUS_OK = 0,
+  /**
+   * Any other error.
+   */
+  CH57X_STATUS_FAILURE = 1,
+  /**
+   * Config can't be loaded or is invalid.
+   */
+  CH57X_STATUS_CONFIG = 2,
+  CH57X_STATUS_DEVICE_NOT_FOUND = 3,
+  /**
+   * No permission to access device.
+   */
+  CH57X_STATUS_PERMISSION = 4,
+  /**
+   * Communication with device failed.
+   */
+  CH57X_STATUS_TRANSFER = 5,
+  /**
+   * Several devices match, and it isn't known which one to use.
+   */
+  CH57X_STATUS_SEVERAL_DEVICES = 6,
+  /**
+   * Null pointer or string which isn't UTF-8 is passed.
+   */
+  CH57X_STATUS_INVALID_ARGUMENT = 64,
+} Ch57xStatus;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Renders config at `path` for host OS into JSON list of bindings like
+ * `{"layer": 0, "key": "button 0", "macro": "ctrl-c"}`, stored into `json`.
+ * String must be freed with `ch57x_free_string`.
+ *
+ * # Safety
+ *
+ * `path` must be NUL-terminated string, `json` must point to writable pointer.
+ */
+enum Ch57xStatus ch57x_render_config(const char *path, char **json);
+
+/**
+ * Uploads config at `path`, LED modes included, to the only connected keyboard.
+ * Unlike `upload` command, it doesn't keep backups and state of device.
+ *
+ * # Safety
+ *
+ * `path` must be NUL-terminated string.
+ */
+enum Ch57xStatus ch57x_upload_config(const char *path);
+
+/**
+ * Message of error returned by last call on this thread, null if it succeeded.
+ * Message is valid until next call on this thread.
+ */
+const char *ch57x_last_error(void);
+
+/**
+ * Frees string returned by API, null is ignored.
+ *
+ * # Safety
+ *
+ * `s` must be null or string returned by API which isn't freed yet.
+ */
+void ch57x_free_string(char *s);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CH57X_KEYBOARD_H */
@@ -0,0 +1,200 @@
+//! Backups of configs uploaded to devices.
+//!
+//! Keyboards can't be read back, so before each upload rendered config
+//! is saved to user data directory, one directory per device like in
+//! state cache. Backup is a regular config in normal orientation with
+//! all named macros and key names resolved, so it may be uploaded as is.
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, ensure, Context as _, Result};
+use serde_yaml::{Mapping, Value};
+
+use crate::config::{Config, FlatLayer};
+use crate::device::Device;
+use crate::keyboard::{Geometry, Macro};
+use crate::state::Bindings;
+
+/// Number of backups kept per device by default.
+pub const DEFAULT_KEEP: usize = 20;
+
+const EXTENSION: &str = "yaml";
+
+/// Renders layers back to config with given number of button columns,
+/// keyboard variant is kept so that keys are numbered the same way.
+pub fn render_config(columns: usize, variant: Geometry, led: Option<u8>, layers: &[FlatLayer]) -> String {
+    let first = layers.first();
+    let buttons = first.map_or(0, |l| l.buttons.len());
+    let rows = buttons.checked_div(columns).unwrap_or(0);
+    let knobs = first.map_or(0, |l| l.knobs.len());
+
+    let macro_value = |macro_: &Option<Macro>| macro_.as_ref().map_or(Value::Null, |m| m.to_string().into());
+    let layers = layers.iter().map(|layer| {
+        let buttons = layer.buttons.chunks(columns.max(1))
+            .map(|row| Value::Sequence(row.iter().map(macro_value).collect()))
+            .collect();
+        let knobs = layer.knobs.iter().map(|knob| {
+            let mut mapping = Mapping::new();
+            mapping.insert("ccw".into(), macro_value(&knob.ccw));
+            mapping.insert("press".into(), macro_value(&knob.press));
+            mapping.insert("cw".into(), macro_value(&knob.cw));
+            Value::Mapping(mapping)
+        }).collect();
+
+        let mut mapping = Mapping::new();
+        mapping.insert("buttons".into(), Value::Sequence(buttons));
+        mapping.insert("knobs".into(), Value::Sequence(knobs));
+        Value::Mapping(mapping)
+    }).collect();
+
+    let mut config = Mapping::new();
+    config.insert("orientation".into(), "normal".into());
+    config.insert("rows".into(), rows.into());
+    config.insert("columns".into(), columns.into());
+    config.insert("knobs".into(), knobs.into());
+    config.insert("variant".into(), variant.to_string().into());
+    if let Some(led) = led {
+        config.insert("led".into(), led.into());
+    }
+    config.insert("layers".into(), Value::Sequence(layers));
+    serde_yaml::to_string(&config).expect("config is always serializable")
+}
+
+/// Saves config uploaded to device and removes backups beyond `keep` newest ones.
+/// Returns timestamp backup may be restored by. Mock device has no backups.
+pub fn save(device: &Device, config: &str, keep: usize) -> Result<String> {
+    ensure!(!device.is_mock(), "mock device has no backups");
+    let dir = backups_dir(device)?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+
+    let timestamp = format_timestamp(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+    let path = dir.join(format!("{timestamp}.{EXTENSION}"));
+    std::fs::write(&path, config).with_context(|| format!("write {}", path.display()))?;
+
+    let backups = list(device)?;
+    for old in &backups[..backups.len().saturating_sub(keep)] {
+        let path = dir.join(format!("{old}.{EXTENSION}"));
+        std::fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+    }
+    Ok(timestamp)
+}
+
+/// Lists timestamps of backups saved for device, oldest first.
+pub fn list(device: &Device) -> Result<Vec<String>> {
+    if device.is_mock() {
+        return Ok(vec![]);
+    }
+    let dir = backups_dir(device)?;
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err).with_context(|| format!("read {}", dir.display())),
+    };
+    let mut timestamps = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == EXTENSION) {
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                timestamps.push(stem.to_owned());
+            }
+        }
+    }
+    // Timestamps are formatted so that they sort chronologically.
+    timestamps.sort();
+    Ok(timestamps)
+}
+
+/// Finds newest backup having given bindings and returns its layers. It is used
+/// to get config known to be uploaded successfully, since state only has bindings
+/// as they are displayed. Unreadable backups are skipped.
+pub fn find(device: &Device, bindings: &Bindings) -> Result<Option<Vec<FlatLayer>>> {
+    let dir = backups_dir(device)?;
+    for timestamp in list(device)?.iter().rev() {
+        let path = dir.join(format!("{timestamp}.{EXTENSION}"));
+        // Backups have OS-specific macros resolved already.
+        let Ok(layers) = Config::load(&path).and_then(Config::render) else {
+            continue;
+        };
+        if Bindings::from_layers(&layers) == *bindings {
+            return Ok(Some(layers));
+        }
+    }
+    Ok(None)
+}
+
+/// Path to backup with given timestamp.
+pub fn path(device: &Device, timestamp: &str) -> Result<PathBuf> {
+    let path = backups_dir(device)?.join(format!("{timestamp}.{EXTENSION}"));
+    if !path.exists() {
+        return Err(anyhow!("there is no backup {timestamp} for this device"));
+    }
+    Ok(path)
+}
+
+fn backups_dir(device: &Device) -> Result<PathBuf> {
+    let dir = dirs::data_dir().ok_or_else(|| anyhow!("can't determine data directory"))?;
+    let name = format!("{:04x}-{:04x}-{}", device.vendor_id(), device.product_id(), device.location());
+    Ok(dir.join("ch57x-keyboard-tool").join("backups").join(name))
+}
+
+/// Formats UNIX time as UTC `YYYYMMDD-HHMMSS`.
+pub fn format_timestamp(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Converts days since epoch to civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}{month:02}{day:02}-{:02}{:02}{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+    use crate::config::ConfigFormat;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "19700101-000000");
+        assert_eq!(format_timestamp(951_782_400 + 3661), "20000229-010101");
+        assert_eq!(format_timestamp(1_792_108_800), "20261016-000000");
+    }
+
+    #[test]
+    fn test_render_config() -> Result<()> {
+        let config = indoc! {"
+            orientation: clockwise
+            rows: 2
+            columns: 1
+            knobs: 1
+            keycodes:
+              mute_mic: <239>
+            macros:
+              copy: ctrl-c
+            layers:
+              - buttons: [[$copy, mute_mic]]
+                knobs: [{ccw: volumedown, press: ~, cw: volumeup}]
+        "};
+        let config = Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None)?;
+        let geometry = config.geometry();
+        let layers = config.render()?;
+
+        let backup = render_config(1, geometry, Some(2), &layers);
+        let restored = Config::from_reader(backup.as_bytes(), ConfigFormat::Yaml, None)?;
+        assert_eq!(restored.geometry(), geometry);
+        assert_eq!(restored.led, Some(2));
+        assert_eq!(Bindings::from_layers(&restored.render()?), Bindings::from_layers(&layers));
+        Ok(())
+    }
+}
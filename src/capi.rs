@@ -0,0 +1,178 @@
+//! C API, built with `capi` feature. Header `include/ch57x_keyboard.h` is generated
+//! by cbindgen when crate is built with this feature.
+//!
+//! All functions return `Ch57xStatus`, which has the same values as exit codes of
+//! command-line tool. Message of failure is got with `ch57x_last_error`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::path::Path;
+use std::ptr;
+
+use anyhow::{ensure, Context as _, Result};
+
+use crate::config::Config;
+use crate::device::{Device, DeviceFilter, EndpointOptions};
+use crate::exit::{ConfigError, ExitCode};
+
+/// Result of API call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ch57xStatus {
+    Ok = 0,
+    /// Any other error.
+    Failure = 1,
+    /// Config can't be loaded or is invalid.
+    Config = 2,
+    DeviceNotFound = 3,
+    /// No permission to access device.
+    Permission = 4,
+    /// Communication with device failed.
+    Transfer = 5,
+    /// Several devices match, and it isn't known which one to use.
+    SeveralDevices = 6,
+    /// Null pointer or string which isn't UTF-8 is passed.
+    InvalidArgument = 64,
+}
+
+impl From<ExitCode> for Ch57xStatus {
+    fn from(code: ExitCode) -> Self {
+        match code {
+            ExitCode::Failure => Self::Failure,
+            ExitCode::Config => Self::Config,
+            ExitCode::DeviceNotFound => Self::DeviceNotFound,
+            ExitCode::Permission => Self::Permission,
+            ExitCode::Transfer => Self::Transfer,
+            ExitCode::SeveralDevices => Self::SeveralDevices,
+            ExitCode::Usage => Self::InvalidArgument,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Invalid argument passed by caller, reported as `Ch57xStatus::InvalidArgument`.
+#[derive(Debug)]
+struct InvalidArgument(&'static str);
+
+impl std::fmt::Display for InvalidArgument {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid argument '{}'", self.0)
+    }
+}
+
+impl std::error::Error for InvalidArgument {}
+
+/// Runs call, remembering error message for `ch57x_last_error`.
+fn call(f: impl FnOnce() -> Result<()>) -> Ch57xStatus {
+    let result = f();
+    let status = match &result {
+        Ok(()) => Ch57xStatus::Ok,
+        Err(err) if err.downcast_ref::<InvalidArgument>().is_some() => Ch57xStatus::InvalidArgument,
+        Err(err) => ExitCode::of(err).into(),
+    };
+    // Messages can't contain NUL, but it's better to lose message than to panic.
+    let message = result.err().map(|err| CString::new(format!("{err:#}")).unwrap_or_default());
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    status
+}
+
+/// # Safety
+///
+/// `s` must be null or point to NUL-terminated string.
+unsafe fn path_arg<'a>(s: *const c_char, name: &'static str) -> Result<&'a Path> {
+    ensure!(!s.is_null(), InvalidArgument(name));
+    let s = CStr::from_ptr(s).to_str().map_err(|_| InvalidArgument(name))?;
+    Ok(Path::new(s))
+}
+
+fn load_config(path: &Path) -> Result<Config> {
+    Config::load(path).context(ConfigError("load mapping config"))
+}
+
+/// Renders config at `path` for host OS into JSON list of bindings like
+/// `{"layer": 0, "key": "button 0", "macro": "ctrl-c"}`, stored into `json`.
+/// String must be freed with `ch57x_free_string`.
+///
+/// # Safety
+///
+/// `path` must be NUL-terminated string, `json` must point to writable pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ch57x_render_config(path: *const c_char, json: *mut *mut c_char) -> Ch57xStatus {
+    call(|| {
+        let path = path_arg(path, "path")?;
+        ensure!(!json.is_null(), InvalidArgument("json"));
+        let config = load_config(path)?;
+        let secrets = config.secrets.clone();
+        let layers = config.render().context(ConfigError("render mapping config"))?;
+        let bindings = layers.iter().enumerate().flat_map(|(layer_idx, layer)| {
+            layer.bindings().map(move |(key, macro_)| (layer_idx, key, macro_))
+        }).map(|(layer_idx, key, macro_)| serde_json::json!({
+            "layer": layer_idx,
+            "key": key.to_string(),
+            "macro": secrets.show(macro_),
+        })).collect::<Vec<_>>();
+        let text = serde_json::to_string(&bindings)?;
+        *json = CString::new(text)?.into_raw();
+        Ok(())
+    })
+}
+
+/// Uploads config at `path`, LED modes included, to the only connected keyboard.
+/// Unlike `upload` command, it doesn't keep backups and state of device.
+///
+/// # Safety
+///
+/// `path` must be NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ch57x_upload_config(path: *const c_char) -> Ch57xStatus {
+    call(|| {
+        let config = load_config(path_arg(path, "path")?)?;
+        let device = Device::find(&DeviceFilter::default()).context("find USB device")?;
+        device.upload_config(&EndpointOptions::default(), config)?;
+        Ok(())
+    })
+}
+
+/// Message of error returned by last call on this thread, null if it succeeded.
+/// Message is valid until next call on this thread.
+#[no_mangle]
+pub extern "C" fn ch57x_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Frees string returned by API, null is ignored.
+///
+/// # Safety
+///
+/// `s` must be null or string returned by API which isn't freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ch57x_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_config() {
+        let path = CString::new("example-mapping.yaml").unwrap();
+        let mut json = ptr::null_mut();
+        let status = unsafe { ch57x_render_config(path.as_ptr(), &mut json) };
+        assert_eq!(status, Ch57xStatus::Ok);
+        assert!(ch57x_last_error().is_null());
+        let bindings: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap()).unwrap();
+        unsafe { ch57x_free_string(json) };
+        assert_eq!(bindings[0]["layer"], 0);
+
+        let missing = CString::new("missing.yaml").unwrap();
+        assert_eq!(unsafe { ch57x_render_config(missing.as_ptr(), &mut json) }, Ch57xStatus::Config);
+        assert!(!ch57x_last_error().is_null());
+        assert_eq!(unsafe { ch57x_render_config(ptr::null(), &mut json) }, Ch57xStatus::InvalidArgument);
+    }
+}
@@ -0,0 +1,303 @@
+//! Printable cheat sheet with what each key does, by layer.
+
+use itertools::Itertools as _;
+
+use ch57x_keyboard::config::{Binding, Config, Orientation, TargetOs};
+
+use crate::options::SheetFormat;
+
+/// Layer as seen on keyboard: rows of button descriptions and bound knob
+/// actions by physical knob name.
+struct Sheet {
+    orientation: Orientation,
+    buttons: Vec<Vec<String>>,
+    knobs: Vec<(String, Vec<(&'static str, String)>)>,
+}
+
+impl Sheet {
+    /// Knobs having at least one action bound.
+    fn bound_knobs(&self) -> impl Iterator<Item = &(String, Vec<(&'static str, String)>)> {
+        self.knobs.iter().filter(|(_, actions)| !actions.is_empty())
+    }
+}
+
+fn sheets(config: &Config, os: TargetOs) -> Vec<Sheet> {
+    let describe = |binding: &Option<Binding>| binding.as_ref().and_then(|b| b.describe(os)).unwrap_or_default();
+    config.layers.iter().map(|layer| {
+        // Buttons and knobs are written in config as they are seen in layer orientation.
+        let orientation = layer.orientation.unwrap_or(config.orientation);
+        let buttons = layer.buttons.iter().map(|row| row.iter().map(describe).collect()).collect();
+        let knobs = layer.knobs.iter().enumerate()
+            .map(|(i, knob)| {
+                let actions = [("ccw", &knob.ccw), ("press", &knob.press), ("cw", &knob.cw)].into_iter()
+                    .map(|(action, binding)| (action, describe(binding)))
+                    .filter(|(_, description)| !description.is_empty())
+                    .collect_vec();
+                (orientation.knob_label(i, layer.knobs.len()), actions)
+            })
+            .collect();
+        Sheet { orientation, buttons, knobs }
+    }).collect()
+}
+
+/// Renders cheat sheet for all layers of config.
+pub fn render(config: &Config, os: TargetOs, format: SheetFormat) -> String {
+    let sheets = sheets(config, os);
+    match format {
+        SheetFormat::Text => text(&sheets),
+        SheetFormat::Markdown => markdown(&sheets),
+        SheetFormat::Html => html(&sheets),
+        SheetFormat::Svg => svg(&sheets),
+    }
+}
+
+fn text(sheets: &[Sheet]) -> String {
+    let mut out = String::new();
+    for (i, sheet) in sheets.iter().enumerate() {
+        out += &format!("Layer {}\n", i + 1);
+        let cols = sheet.buttons.first().map_or(0, Vec::len);
+        let widths = (0..cols)
+            .map(|c| sheet.buttons.iter().map(|row| row[c].chars().count()).max().unwrap_or(0))
+            .collect_vec();
+        let border = format!("+{}+\n", widths.iter().map(|w| "-".repeat(w + 2)).join("+"));
+        out += &border;
+        for row in &sheet.buttons {
+            out += &format!("|{}|\n", row.iter().zip(&widths).map(|(cell, w)| format!(" {cell:w$} ")).join("|"));
+            out += &border;
+        }
+        for (name, actions) in sheet.bound_knobs() {
+            out += &format!("{name}: {}\n", actions.iter().map(|(action, description)| format!("{action} {description}")).join(", "));
+        }
+        out += "\n";
+    }
+    out
+}
+
+fn markdown(sheets: &[Sheet]) -> String {
+    let escape = |s: &str| s.replace('|', "\\|");
+    let mut out = String::new();
+    for (i, sheet) in sheets.iter().enumerate() {
+        out += &format!("## Layer {}\n\n", i + 1);
+        let cols = sheet.buttons.first().map_or(0, Vec::len);
+        // Tables need header, it is left empty since buttons have no names.
+        out += &format!("|{}\n", " |".repeat(cols));
+        out += &format!("|{}\n", "---|".repeat(cols));
+        for row in &sheet.buttons {
+            out += &format!("| {} |\n", row.iter().map(|cell| escape(cell)).join(" | "));
+        }
+        out += "\n";
+        for (name, actions) in sheet.bound_knobs() {
+            out += &format!("- **{name}**: {}\n", actions.iter().map(|(action, description)| format!("{action} {}", escape(description))).join(", "));
+        }
+        if sheet.bound_knobs().next().is_some() {
+            out += "\n";
+        }
+    }
+    out
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn html(sheets: &[Sheet]) -> String {
+    let mut out = String::new();
+    out += "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Keyboard cheat sheet</title>\n";
+    out += "<style>\n";
+    out += "body { font-family: sans-serif; }\n";
+    out += "table { border-collapse: collapse; }\n";
+    out += "td { border: 1px solid black; width: 6em; height: 3em; text-align: center; }\n";
+    out += "section { break-inside: avoid; }\n";
+    out += "</style>\n</head>\n<body>\n";
+    for (i, sheet) in sheets.iter().enumerate() {
+        out += &format!("<section>\n<h2>Layer {}</h2>\n<table>\n", i + 1);
+        for row in &sheet.buttons {
+            out += &format!("<tr>{}</tr>\n", row.iter().map(|cell| format!("<td>{}</td>", escape_html(cell))).join(""));
+        }
+        out += "</table>\n";
+        if sheet.bound_knobs().next().is_some() {
+            out += "<ul>\n";
+            for (name, actions) in sheet.bound_knobs() {
+                let actions = actions.iter().map(|(action, description)| format!("{action} {}", escape_html(description))).join(", ");
+                out += &format!("<li><b>{name}</b>: {actions}</li>\n");
+            }
+            out += "</ul>\n";
+        }
+        out += "</section>\n";
+    }
+    out += "</body>\n</html>\n";
+    out
+}
+
+/// Size of button square and gap between buttons, in pixels.
+const KEY_SIZE: usize = 80;
+const KEY_GAP: usize = 10;
+/// Knob is drawn as circle with its actions listed below.
+const KNOB_WIDTH: usize = 130;
+const KNOB_HEIGHT: usize = 120;
+const KNOB_RADIUS: usize = 30;
+const TITLE_HEIGHT: usize = 30;
+/// Characters fitting into button line at font size used.
+const KEY_LINE_CHARS: usize = 11;
+
+/// Splits text into lines of at most `width` characters, breaking at spaces
+/// and commas where possible.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split_inclusive([' ', ',']) {
+        if !line.is_empty() && line.chars().count() + word.trim_end().chars().count() > width {
+            lines.push(line.trim_end().to_owned());
+            line.clear();
+        }
+        line += word;
+        // Words longer than line are broken anywhere.
+        while line.chars().count() > width {
+            let rest = line.chars().skip(width).collect::<String>();
+            lines.push(line.chars().take(width).collect());
+            line = rest;
+        }
+    }
+    if !line.trim_end().is_empty() {
+        lines.push(line.trim_end().to_owned());
+    }
+    lines
+}
+
+fn svg(sheets: &[Sheet]) -> String {
+    let mut body = String::new();
+    let mut y = KEY_GAP;
+    let mut width = 0;
+    for (i, sheet) in sheets.iter().enumerate() {
+        let rows = sheet.buttons.len();
+        let cols = sheet.buttons.first().map_or(0, Vec::len);
+        let grid = (cols * (KEY_SIZE + KEY_GAP), rows * (KEY_SIZE + KEY_GAP));
+        let knob_count = sheet.knobs.len();
+
+        // Knobs are placed beside buttons as orientation says: on the right
+        // in normal one, on the left when upside down, below or above in vertical ones.
+        // Knobs go in a row from left to right in horizontal orientations
+        // and in a column from top to bottom in vertical ones, as in config.
+        let (knob_step, knobs_size) = if sheet.orientation.is_horizontal() {
+            ((KNOB_WIDTH, 0), (knob_count * KNOB_WIDTH, KNOB_HEIGHT))
+        } else {
+            ((0, KNOB_HEIGHT), (KNOB_WIDTH, knob_count * KNOB_HEIGHT))
+        };
+        let (grid_pos, knobs_pos, size) = match sheet.orientation {
+            _ if knob_count == 0 => ((0, 0), (0, 0), grid),
+            Orientation::Normal => ((0, 0), (grid.0, 0), (grid.0 + knobs_size.0, grid.1.max(knobs_size.1))),
+            Orientation::UpsideDown => ((knobs_size.0, 0), (0, 0), (grid.0 + knobs_size.0, grid.1.max(knobs_size.1))),
+            Orientation::Clockwise => ((0, 0), (0, grid.1), (grid.0.max(knobs_size.0), grid.1 + knobs_size.1)),
+            Orientation::CounterClockwise => ((0, knobs_size.1), (0, 0), (grid.0.max(knobs_size.0), grid.1 + knobs_size.1)),
+        };
+
+        body += &format!("<text x=\"{KEY_GAP}\" y=\"{}\" class=\"title\">Layer {}</text>\n", y + 20, i + 1);
+        y += TITLE_HEIGHT;
+        let (x0, y0) = (KEY_GAP, y);
+
+        for (r, row) in sheet.buttons.iter().enumerate() {
+            for (c, cell) in row.iter().enumerate() {
+                let x = x0 + grid_pos.0 + c * (KEY_SIZE + KEY_GAP);
+                let y = y0 + grid_pos.1 + r * (KEY_SIZE + KEY_GAP);
+                body += &format!("<rect x=\"{x}\" y=\"{y}\" width=\"{KEY_SIZE}\" height=\"{KEY_SIZE}\" rx=\"8\" class=\"key\"/>\n");
+                let lines = wrap(cell, KEY_LINE_CHARS);
+                let top = y + KEY_SIZE / 2 + 4 - (lines.len().saturating_sub(1) * 14) / 2;
+                for (n, line) in lines.iter().enumerate() {
+                    body += &format!("<text x=\"{}\" y=\"{}\" class=\"label\">{}</text>\n", x + KEY_SIZE / 2, top + n * 14, escape_html(line));
+                }
+            }
+        }
+
+        for (k, (_, actions)) in sheet.knobs.iter().enumerate() {
+            let x = x0 + knobs_pos.0 + k * knob_step.0 + KNOB_WIDTH / 2;
+            let y = y0 + knobs_pos.1 + k * knob_step.1 + KNOB_RADIUS;
+            body += &format!("<circle cx=\"{x}\" cy=\"{y}\" r=\"{KNOB_RADIUS}\" class=\"key\"/>\n");
+            for (n, (action, description)) in actions.iter().enumerate() {
+                let line = wrap(&format!("{action}: {description}"), 20).into_iter().next().unwrap_or_default();
+                body += &format!("<text x=\"{x}\" y=\"{}\" class=\"knob\">{}</text>\n", y + KNOB_RADIUS + 16 + n * 13, escape_html(&line));
+            }
+        }
+
+        width = width.max(size.0);
+        y += size.1 + KEY_GAP;
+    }
+
+    let mut out = String::new();
+    out += &format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{y}\" viewBox=\"0 0 {0} {y}\">\n", width + 2 * KEY_GAP);
+    out += "<style>\n";
+    out += ".title { font: bold 16px sans-serif; }\n";
+    out += ".key { fill: white; stroke: black; stroke-width: 2; }\n";
+    out += ".label { font: 12px sans-serif; text-anchor: middle; }\n";
+    out += ".knob { font: 10px sans-serif; text-anchor: middle; }\n";
+    out += "</style>\n";
+    out += &format!("<rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n{body}");
+    out += "</svg>\n";
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ch57x_keyboard::config::ConfigFormat;
+    use indoc::indoc;
+
+    fn config() -> Config {
+        let config = indoc! {"
+            orientation: clockwise
+            rows: 2
+            columns: 1
+            knobs: 2
+            layers:
+              - buttons:
+                  - [{ map: ctrl-shift-t, label: 'Reopen <tab>' }, ~]
+                knobs:
+                  - { ccw: volumedown, press: { map: mute, label: Mute }, cw: volumeup }
+                  - {}
+        "};
+        Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None).unwrap()
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(render(&config(), TargetOs::Linux, SheetFormat::Text), indoc! {"
+            Layer 1
+            +--------------+--+
+            | Reopen <tab> |  |
+            +--------------+--+
+            top knob: ccw volumedown, press Mute, cw volumeup
+
+        "});
+    }
+
+    #[test]
+    fn test_markdown() {
+        let sheet = render(&config(), TargetOs::Linux, SheetFormat::Markdown);
+        assert!(sheet.contains("| | |\n|---|---|\n| Reopen <tab> |  |\n"));
+        assert!(sheet.contains("- **top knob**: ccw volumedown, press Mute, cw volumeup\n"));
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("Reopen closed tab", 11), ["Reopen", "closed tab"]);
+        assert_eq!(wrap("ctrl-a,ctrl-b", 11), ["ctrl-a,", "ctrl-b"]);
+        assert_eq!(wrap("brightnessdown", 11), ["brightnessd", "own"]);
+        assert_eq!(wrap("", 11), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_svg() {
+        let sheet = render(&config(), TargetOs::Linux, SheetFormat::Svg);
+        assert!(sheet.starts_with("<svg "));
+        assert_eq!(sheet.matches("<rect x=").count(), 2);
+        assert_eq!(sheet.matches("<circle ").count(), 2);
+        assert!(sheet.contains(">Reopen</text>\n<text x=\"50\" y=\"91\" class=\"label\">&lt;tab&gt;</text>"));
+        assert!(sheet.contains(">press: Mute</text>"));
+    }
+
+    #[test]
+    fn test_html() {
+        let sheet = render(&config(), TargetOs::Linux, SheetFormat::Html);
+        assert!(sheet.contains("<tr><td>Reopen &lt;tab&gt;</td><td></td></tr>"));
+    }
+}
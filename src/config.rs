@@ -1,37 +1,336 @@
-use anyhow::{bail, ensure, Result};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+use itertools::Itertools as _;
+use serde::de::{DeserializeOwned, Deserializer, Error as _};
 use serde::Deserialize;
+use serde_with::DeserializeFromStr;
+use serde_yaml::{Mapping, Value};
+use strum_macros::{Display, EnumString};
 
-use crate::keyboard::Macro;
+use crate::device::Device;
+use crate::keyboard::{Code, Geometry, Key, KnobAction, Macro, MouseModifier};
+use crate::parse;
+use crate::secrets::{self, SecretNames, SecretProvider};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub orientation: Orientation,
     pub rows: u8,
     pub columns: u8,
     pub knobs: u8,
 
+    /// Keyboard variant, as number of buttons and knobs like `12+4`, for keyboards
+    /// having more keys than config binds. Variants of the same model may number
+    /// keys differently, by default variant is deduced from config geometry.
+    #[serde(default)]
+    pub variant: Option<Geometry>,
+
+    /// Custom key names which may be used in macros like well-known ones.
+    #[serde(default)]
+    pub keycodes: HashMap<String, Code>,
+
+    /// Named macros which may be referenced as `$name` from layers.
+    #[serde(default)]
+    pub macros: HashMap<String, PerOs<Macro>>,
+
+    /// LED mode programmed together with bindings, for the first layer only.
+    #[serde(default, deserialize_with = "deserialize_led")]
+    pub led: Option<u8>,
+
+    /// Commands run after config is validated or uploaded.
+    #[serde(default)]
+    pub hooks: Hooks,
+
     pub layers: Vec<Layer>,
+
+    /// Secrets substituted into config, filled when it is loaded.
+    #[serde(skip)]
+    pub secrets: SecretNames,
+}
+
+/// Shell commands run after successful `validate` or `upload`, summary
+/// is passed to them in environment variables.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    #[serde(default)]
+    pub validate: Option<String>,
+    #[serde(default)]
+    pub upload: Option<String>,
+}
+
+/// Config of one of devices described by config.
+#[derive(Debug)]
+pub struct DeviceConfig {
+    pub selector: DeviceSelector,
+    pub config: Config,
+}
+
+/// Criteria choosing device among connected ones, in addition to command line options.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSelector {
+    #[serde(default)]
+    pub product_id: Option<u16>,
+    /// USB bus number and address, like `1:5`.
+    #[serde(default, deserialize_with = "deserialize_address")]
+    pub address: Option<(u8, u8)>,
+    #[serde(default)]
+    pub serial: Option<String>,
+}
+
+impl DeviceSelector {
+    /// Whether found device matches all given criteria, serial number is read only if it is given.
+    pub fn matches(&self, device: &Device) -> bool {
+        self.product_id.is_none_or(|id| id == device.product_id())
+            && self.address.is_none_or(|address| address == device.address())
+            && self.serial.as_ref().is_none_or(|serial| device.serial_number().ok().flatten().as_ref() == Some(serial))
+    }
+
+    /// Whether all criteria given in `other` are the same in this selector.
+    fn includes(&self, other: &DeviceSelector) -> bool {
+        other.product_id.is_none_or(|id| self.product_id == Some(id))
+            && other.address.is_none_or(|address| self.address == Some(address))
+            && other.serial.as_ref().is_none_or(|serial| self.serial.as_ref() == Some(serial))
+    }
+}
+
+/// Device config chosen from config describing several devices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceChoice {
+    /// Index in `devices` list.
+    Index(usize),
+    /// Device config with selector having the same fields, like `serial=A1B2`.
+    Selector(DeviceSelector),
+}
+
+impl FromStr for DeviceChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(index) = s.parse() {
+            return Ok(Self::Index(index));
+        }
+        let (field, value) = s.split_once('=')
+            .ok_or_else(|| anyhow!("expected device index or selector like 'serial=A1B2', got '{s}'"))?;
+        // Product ID may be written in hex, like in config.
+        let value = match field {
+            "product_id" => serde_yaml::from_str(value)?,
+            _ => Value::String(value.to_owned()),
+        };
+        let mut mapping = Mapping::new();
+        mapping.insert(field.into(), value);
+        let selector = serde_yaml::from_value(Value::Mapping(mapping)).with_context(|| format!("invalid device selector '{s}'"))?;
+        Ok(Self::Selector(selector))
+    }
+}
+
+impl DeviceConfig {
+    /// Chooses config of single device, choice may only be omitted if there is one device.
+    pub fn choose(configs: Vec<DeviceConfig>, choice: Option<&DeviceChoice>) -> Result<DeviceConfig> {
+        let count = configs.len();
+        let mut chosen = match choice {
+            None => {
+                ensure!(count == 1, "config describes {count} devices, choose one with --device");
+                configs
+            }
+            Some(DeviceChoice::Index(index)) => {
+                ensure!(*index < count, "there is no device {index} in config, it describes {count} devices");
+                configs.into_iter().skip(*index).take(1).collect()
+            }
+            Some(DeviceChoice::Selector(selector)) => {
+                let matching = configs.into_iter().filter(|c| c.selector.includes(selector)).collect_vec();
+                ensure!(!matching.is_empty(), "no device in config matches selector");
+                ensure!(matching.len() == 1, "several devices in config match selector, choose one by index");
+                matching
+            }
+        };
+        Ok(chosen.remove(0))
+    }
+}
+
+fn deserialize_address<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<(u8, u8)>, D::Error> {
+    let Some(address) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse::from_str(parse::address, &address)
+        .map(Some)
+        .map_err(|_| D::Error::custom(format!("invalid address '{address}', expected bus:address")))
+}
+
+/// LED mode index (zero-based). List by layer is rejected, since messages setting
+/// LED mode for layers other than the first one aren't known.
+fn deserialize_led<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<u8>, D::Error> {
+    match Option::<Value>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Value::Sequence(_)) => Err(D::Error::custom("LED modes by layer aren't supported, give single mode")),
+        Some(value) => u8::deserialize(value).map(Some).map_err(D::Error::custom),
+    }
+}
+
+/// Options of config loading.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoadOptions {
+    /// Substitute `${NAME}` in macros with values of environment variables.
+    pub allow_env: bool,
+    /// Provider of secrets referenced as `secret:NAME`, they are rejected if it is `None`.
+    pub secrets: Option<&'static dyn SecretProvider>,
 }
 
 impl Config {
+    /// Keyboard variant, either given explicitly or deduced from geometry.
+    pub fn geometry(&self) -> Geometry {
+        self.variant.unwrap_or(Geometry {
+            buttons: self.rows as usize * self.columns as usize,
+            knobs: self.knobs as usize,
+        })
+    }
+
+    /// Loads config from file, resolving `extends` chain.
+    /// Format is detected by file extension.
+    pub fn load(path: &Path) -> Result<Config> {
+        Self::load_with(path, LoadOptions::default())
+    }
+
+    /// Same as `load`, with given options. Path may be a directory of config
+    /// fragments, see `read_fragments`.
+    pub fn load_with(path: &Path, options: LoadOptions) -> Result<Config> {
+        if path.is_dir() {
+            return Self::from_single_value(read_fragments(path)?, None, options);
+        }
+        let file = std::fs::File::open(path).context("open config file")?;
+        Self::from_reader_with(std::io::BufReader::new(file), ConfigFormat::from_path(path), Some(path), options)
+    }
+
+    /// Loads config from reader, resolving `extends` chain relative
+    /// to `origin` file path (or current directory if `None`).
+    pub fn from_reader(reader: impl std::io::Read, format: ConfigFormat, origin: Option<&Path>) -> Result<Config> {
+        Self::from_reader_with(reader, format, origin, LoadOptions::default())
+    }
+
+    /// Same as `from_reader`, with given options.
+    pub fn from_reader_with(
+        reader: impl std::io::Read,
+        format: ConfigFormat,
+        origin: Option<&Path>,
+        options: LoadOptions,
+    ) -> Result<Config> {
+        Self::from_single_value(format.read_value(reader)?, origin, options)
+    }
+
+    fn from_single_value(value: Value, origin: Option<&Path>, options: LoadOptions) -> Result<Config> {
+        ensure!(value.get("devices").is_none(), "config describes several devices, but config of single one is expected");
+        Self::from_value(value, origin, options)
+    }
+
+    /// Loads configs of all devices described by config. Config without
+    /// `devices` list describes single device, which is selected by command line options.
+    ///
+    /// Each entry of `devices` list is a config with additional `product_id`, `address`
+    /// and `serial` fields selecting device, other top-level fields are shared by all devices
+    /// like if each entry extended them.
+    pub fn load_devices(
+        reader: impl std::io::Read,
+        format: ConfigFormat,
+        origin: Option<&Path>,
+        options: LoadOptions,
+    ) -> Result<Vec<DeviceConfig>> {
+        Self::devices_from_value(format.read_value(reader)?, origin, options)
+    }
+
+    /// Same as `load_devices`, for directory of config fragments.
+    pub fn load_devices_from_dir(dir: &Path, options: LoadOptions) -> Result<Vec<DeviceConfig>> {
+        Self::devices_from_value(read_fragments(dir)?, None, options)
+    }
+
+    fn devices_from_value(mut value: Value, origin: Option<&Path>, options: LoadOptions) -> Result<Vec<DeviceConfig>> {
+        let Some(devices) = value.as_mapping_mut().and_then(|m| m.remove("devices")) else {
+            let config = Self::from_value(value, origin, options)?;
+            return Ok(vec![DeviceConfig { selector: DeviceSelector::default(), config }]);
+        };
+        let Value::Sequence(devices) = devices else {
+            bail!("'devices' must be a list of device configs");
+        };
+        let shared = resolve_extends(value, origin)?;
+
+        devices.into_iter().enumerate().map(|(i, mut device)| {
+            let mapping = device.as_mapping_mut().ok_or_else(|| anyhow!("device {i} config must be a mapping"))?;
+            let mut selector = Mapping::new();
+            for field in ["product_id", "address", "serial"] {
+                if let Some(value) = mapping.remove(field) {
+                    selector.insert(field.into(), value);
+                }
+            }
+            let selector = serde_yaml::from_value(Value::Mapping(selector))
+                .with_context(|| format!("parse selector of device {i}"))?;
+            let device = resolve_extends(device, origin)?;
+            let config = Self::from_value(merge_configs(shared.clone(), device), origin, options)
+                .with_context(|| format!("load config of device {i}"))?;
+            Ok(DeviceConfig { selector, config })
+        }).collect()
+    }
+
+    fn from_value(value: Value, origin: Option<&Path>, options: LoadOptions) -> Result<Config> {
+        let mut value = resolve_extends(value, origin)?;
+        substitute_env(&mut value, options.allow_env.then_some(&|name: &str| std::env::var(name).ok()))?;
+        let secrets = substitute_secrets(&mut value, options.secrets)?;
+        expand_scroll_knobs(&mut value)?;
+        let keycodes = read_keycodes(&value)?;
+        let config: Config = parse::with_keycodes(&keycodes, || serde_yaml::from_value(value))?;
+        Ok(Config { secrets, ..config })
+    }
+
     /// Validates config and renders it to flat list of macros for buttons
-    /// and knobs taking orientation into account.
+    /// and knobs taking orientation into account, for host OS.
     pub fn render(self) -> Result<Vec<FlatLayer>> {
+        self.render_for(TargetOs::host())
+    }
+
+    /// Same as `render`, with OS-specific macros chosen for given OS.
+    pub fn render_for(self, os: TargetOs) -> Result<Vec<FlatLayer>> {
         // 3x1 keys + 1 knob keyboard has some limitations we need to check.
         let is_limited = (self.rows == 1 || self.columns == 1) && self.knobs == 1;
 
+        if let Some(variant) = self.variant {
+            let (buttons, knobs) = (self.rows as usize * self.columns as usize, self.knobs as usize);
+            ensure!(buttons <= variant.buttons && knobs <= variant.knobs,
+                    "config has {buttons} buttons and {knobs} knobs, but keyboard variant {variant} has fewer");
+        }
+
+        let macros: HashMap<String, Option<Macro>> = self.macros.into_iter()
+            .map(|(name, macro_)| (name, macro_.select(os)))
+            .collect();
+        let resolve = |binding: Option<Binding>| binding.and_then(|b| b.macro_.select(os)).map(|m| m.resolve(&macros, os)).transpose();
+
         self.layers.into_iter().enumerate().map(|(i, layer)| {
-            let (orows, ocols) = if self.orientation.is_horizontal() {
-                (self.rows, self.columns)
-            } else {
-                (self.columns, self.rows)
-            };
-            ensure!(layer.buttons.len() == orows as usize, "Invalid number of button rows in layer {i}");
-            ensure!(layer.buttons.iter().all(|row| row.len() == ocols as usize), "Invalid number of button columns in layer {i}");
+            let orientation = layer.orientation.unwrap_or(self.orientation);
+            let (orows, ocols) = orientation.grid_size(self.rows as usize, self.columns as usize);
+            ensure!(layer.buttons.len() == orows, "Invalid number of button rows in layer {i}");
+            ensure!(layer.buttons.iter().all(|row| row.len() == ocols), "Invalid number of button columns in layer {i}");
             ensure!(layer.knobs.len() == self.knobs as usize, "Invalid number of knobs in layer {i}");
 
-            let buttons = reorient_grid(self.orientation, self.rows as usize, self.columns as usize, layer.buttons);
-            let knobs = reorient_row(self.orientation, layer.knobs);
+            let buttons = layer.buttons.into_iter()
+                .map(|row| row.into_iter().map(resolve).collect::<Result<Vec<_>>>())
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("resolve buttons in layer {i}"))?;
+            let knob_count = layer.knobs.len();
+            let knobs = layer.knobs.into_iter().enumerate()
+                .map(|(k, knob)| {
+                    let context = || format!("resolve {} in layer {i}", orientation.knob_label(k, knob_count));
+                    Ok(Knob {
+                        ccw: resolve(knob.ccw).with_context(context)?,
+                        press: resolve(knob.press).with_context(context)?,
+                        cw: resolve(knob.cw).with_context(context)?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let buttons = reorient_grid(orientation, self.rows as usize, self.columns as usize, buttons);
+            let knobs = reorient_row(orientation, knobs);
 
             if is_limited {
                 let macro_with_modifiers_beside_first_key = buttons.iter().flatten().find(|macro_| {
@@ -41,7 +340,7 @@ impl Config {
                     }
                 });
                 if let Some(macro_) = macro_with_modifiers_beside_first_key {
-                    bail!("1-row keyboard with 1 knob can handle modifiers for first key in sequence only: {}", macro_);
+                    bail!("1-row keyboard with 1 knob can handle modifiers for first key in sequence only: {}", self.secrets.show(macro_));
                 }
             }
 
@@ -50,8 +349,34 @@ impl Config {
     }
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all="lowercase")]
+#[strum(ascii_case_insensitive)]
+pub enum ConfigFormat {
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Detects format by file extension, YAML is used by default.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Yaml,
+        }
+    }
+
+    fn read_value(self, reader: impl std::io::Read) -> Result<Value> {
+        Ok(match self {
+            Self::Yaml => serde_yaml::from_reader(reader)?,
+            Self::Json => serde_json::from_reader(reader)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Display)]
 #[serde(rename_all="lowercase")]
+#[strum(serialize_all="lowercase")]
 pub enum Orientation {
     Normal,
     UpsideDown,
@@ -60,29 +385,286 @@ pub enum Orientation {
 }
 
 impl Orientation {
+    pub const ALL: [Orientation; 4] = [
+        Orientation::Normal,
+        Orientation::UpsideDown,
+        Orientation::Clockwise,
+        Orientation::CounterClockwise,
+    ];
+
     pub fn is_horizontal(self) -> bool {
         self == Orientation::Normal || self == Orientation::UpsideDown
     }
+
+    /// Number of button rows and columns as they are seen in this orientation,
+    /// given `rows` and `cols` in normal orientation.
+    pub fn grid_size(self, rows: usize, cols: usize) -> (usize, usize) {
+        if self.is_horizontal() { (rows, cols) } else { (cols, rows) }
+    }
+
+    /// Physical position of knob at `index` in config order among `count` knobs.
+    /// Single knob and knobs in the middle of long row have no position name.
+    pub fn knob_position(self, index: usize, count: usize) -> Option<KnobPosition> {
+        let (first, last) = if self.is_horizontal() {
+            (KnobPosition::Left, KnobPosition::Right)
+        } else {
+            (KnobPosition::Top, KnobPosition::Bottom)
+        };
+        match (count, index) {
+            (2, 0) | (3, 0) => Some(first),
+            (3, 1) => Some(KnobPosition::Middle),
+            (2, 1) | (3, 2) => Some(last),
+            _ => None,
+        }
+    }
+
+    /// Human-readable description of knob at `index` in config order, like "left knob".
+    pub fn knob_label(self, index: usize, count: usize) -> String {
+        match self.knob_position(index, count) {
+            Some(position) => format!("{position} knob"),
+            None if count == 1 => "knob".to_owned(),
+            None => format!("knob {} from {}", index + 1, if self.is_horizontal() { "left" } else { "top" }),
+        }
+    }
+
+    /// Converts knob index from config order to order knobs are bound and back.
+    pub fn knob_index(self, index: usize, count: usize) -> usize {
+        reorient_row(self, (0..count).collect())[index]
+    }
+
+    /// Index of button seen at (`row`, `col`) in this orientation, in the order
+    /// buttons are bound, given `rows` and `cols` in normal orientation.
+    pub fn button_index(self, rows: usize, cols: usize, row: usize, col: usize) -> Option<usize> {
+        let (orows, ocols) = self.grid_size(rows, cols);
+        let grid = (0..orows).map(|r| (0..ocols).map(|c| (r, c) == (row, col)).collect()).collect();
+        reorient_grid(self, rows, cols, grid).into_iter().position(|b| b)
+    }
+
+    /// Row and column button with given index is written at in config, reverse of `button_index`.
+    pub fn button_position(self, rows: usize, cols: usize, index: usize) -> Option<(usize, usize)> {
+        let (orows, ocols) = self.grid_size(rows, cols);
+        (0..orows).cartesian_product(0..ocols).find(|&(r, c)| self.button_index(rows, cols, r, c) == Some(index))
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// Physical position of knob in current orientation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all="lowercase")]
+#[strum(ascii_case_insensitive)]
+pub enum KnobPosition {
+    Left,
+    Middle,
+    Right,
+    Top,
+    Bottom,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Layer {
-    pub buttons: Vec<Vec<Option<Macro>>>,
-    pub knobs: Vec<Knob>,
+    /// Overrides config orientation for this layer.
+    #[serde(default)]
+    pub orientation: Option<Orientation>,
+    pub buttons: Vec<Vec<Option<Binding>>>,
+    pub knobs: Vec<Knob<Binding>>,
+}
+
+/// Macro bound to key in layer, written either as usual or as mapping
+/// with label describing it for cheat sheet: `{ map: ctrl-shift-t, label: Reopen tab }`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub macro_: PerOs<MacroRef>,
+    pub label: Option<String>,
+}
+
+impl Binding {
+    /// Text describing binding for given OS: label if there is one, macro otherwise.
+    pub fn describe(&self, os: TargetOs) -> Option<String> {
+        self.label.clone().or_else(|| self.macro_.clone().select(os).map(|m| m.to_string()))
+    }
+}
+
+impl FromStr for Binding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Binding { macro_: s.parse()?, label: None })
+    }
+}
+
+impl<'de> Deserialize<'de> for Binding {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Labeled {
+            map: PerOs<MacroRef>,
+            label: Option<String>,
+        }
+
+        // Mapping without `map` key is macro by OS.
+        let value = Value::deserialize(deserializer)?;
+        if value.get("map").is_some() {
+            let Labeled { map, label } = serde_yaml::from_value(value).map_err(D::Error::custom)?;
+            Ok(Binding { macro_: map, label })
+        } else {
+            let macro_ = serde_yaml::from_value(value).map_err(D::Error::custom)?;
+            Ok(Binding { macro_, label: None })
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Knob {
-    pub ccw: Option<Macro>,
-    pub press: Option<Macro>,
-    pub cw: Option<Macro>,
+/// OS keyboard is used with, selects OS-specific macros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all="lowercase")]
+#[strum(ascii_case_insensitive)]
+pub enum TargetOs {
+    MacOs,
+    Linux,
+    Windows,
 }
 
+impl TargetOs {
+    /// OS tool is running on, other Unix-like systems are treated as Linux.
+    pub fn host() -> Self {
+        if cfg!(target_os = "macos") {
+            Self::MacOs
+        } else if cfg!(windows) {
+            Self::Windows
+        } else {
+            Self::Linux
+        }
+    }
+}
+
+/// Macro which may differ by OS, written either as usual or as mapping
+/// like `{ macos: cmd-c, default: ctrl-c }`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PerOs<M> {
+    Any(M),
+    ByOs(ByOs<M>),
+}
+
+/// Macros by OS, `default` one is used for OSes not given,
+/// key is left unbound if there is no default.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ByOs<M> {
+    pub macos: Option<M>,
+    pub linux: Option<M>,
+    pub windows: Option<M>,
+    pub default: Option<M>,
+}
+
+impl<M> PerOs<M> {
+    /// Chooses macro for given OS, `None` if there is none.
+    pub fn select(self, os: TargetOs) -> Option<M> {
+        match self {
+            PerOs::Any(macro_) => Some(macro_),
+            PerOs::ByOs(by_os) => match os {
+                TargetOs::MacOs => by_os.macos,
+                TargetOs::Linux => by_os.linux,
+                TargetOs::Windows => by_os.windows,
+            }.or(by_os.default),
+        }
+    }
+}
+
+impl<M: FromStr> FromStr for PerOs<M> {
+    type Err = M::Err;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.parse().map(PerOs::Any)
+    }
+}
+
+impl<'de, M: DeserializeOwned> Deserialize<'de> for PerOs<M> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        // Untagged enum would hide errors of macro parsing, so kind is chosen explicitly.
+        let value = Value::deserialize(deserializer)?;
+        let result = if value.is_mapping() {
+            serde_yaml::from_value(value).map(PerOs::ByOs)
+        } else {
+            serde_yaml::from_value(value).map(PerOs::Any)
+        };
+        result.map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Knob<M = Macro> {
+    pub ccw: Option<M>,
+    pub press: Option<M>,
+    pub cw: Option<M>,
+}
+
+/// Macro as written in config: either macro itself or reference
+/// to named macro.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr)]
+pub enum MacroRef {
+    Macro(Macro),
+    Named(String),
+}
+
+impl MacroRef {
+    /// Resolves reference to named macro, which is `None` if it has no variant for `os`.
+    fn resolve(self, macros: &HashMap<String, Option<Macro>>, os: TargetOs) -> Result<Macro> {
+        match self {
+            MacroRef::Macro(macro_) => Ok(macro_),
+            MacroRef::Named(name) => match macros.get(&name) {
+                Some(Some(macro_)) => Ok(macro_.clone()),
+                Some(None) => bail!("macro '${name}' has no variant for {os}"),
+                None => bail!("unknown macro reference ${name}"),
+            },
+        }
+    }
+}
+
+impl FromStr for MacroRef {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse::from_str(parse::macro_ref, s).map_err(|err| {
+            // Point to misspelled or undefined key name if it is what stopped parser.
+            let word: String = err.rest().chars().take_while(|c| c.is_ascii_alphanumeric() || *c == '_').collect();
+            if !word.is_empty() && word.parse::<Macro>().is_err() && !parse::is_keycode_name(&word) {
+                anyhow!("unknown key name '{word}' in macro '{s}'")
+            } else {
+                anyhow!("invalid macro: {err}")
+            }
+        })
+    }
+}
+
+impl Display for MacroRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MacroRef::Macro(macro_) => write!(f, "{}", macro_),
+            MacroRef::Named(name) => write!(f, "${}", name),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct FlatLayer {
     pub buttons: Vec<Option<Macro>>,
     pub knobs: Vec<Knob>,
 }
 
+impl FlatLayer {
+    /// Iterates over bound buttons and knob actions with their macros.
+    pub fn bindings(&self) -> impl Iterator<Item = (Key, &Macro)> {
+        let buttons = self.buttons.iter().enumerate()
+            .filter_map(|(i, macro_)| Some((Key::Button(i as u8), macro_.as_ref()?)));
+        let knobs = self.knobs.iter().enumerate().flat_map(|(i, knob)| {
+            [
+                (KnobAction::RotateCCW, &knob.ccw),
+                (KnobAction::Press, &knob.press),
+                (KnobAction::RotateCW, &knob.cw),
+            ].into_iter().filter_map(move |(action, macro_)| Some((Key::Knob(i as u8, action), macro_.as_ref()?)))
+        });
+        buttons.chain(knobs)
+    }
+}
+
 fn reorient_grid<T: Clone>(orientation: Orientation, rows: usize, cols: usize, data: Vec<Vec<T>>) -> Vec<T> {
     // Transforms physical button position to virtual.
     let tr = match orientation {
@@ -97,6 +679,305 @@ fn reorient_grid<T: Clone>(orientation: Orientation, rows: usize, cols: usize, d
     }).collect()
 }
 
+/// Returns value of variable by name.
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Substitutes `${NAME}` in named macros and layers with values given by `lookup`.
+/// If it is `None`, references are rejected, so that they aren't reported as invalid macros.
+fn substitute_env(config: &mut Value, lookup: Option<Lookup>) -> Result<()> {
+    let Some(config) = config.as_mapping_mut() else {
+        return Ok(());
+    };
+    for field in ["macros", "layers"] {
+        if let Some(value) = config.get_mut(field) {
+            substitute_strings(value, &mut |s| match lookup {
+                Some(lookup) => interpolate(s, lookup),
+                None => {
+                    ensure!(!s.contains("${"), "'{s}' references environment variable, but substitution isn't enabled");
+                    Ok(s.to_owned())
+                }
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `secret:NAME` macros in named macros and layers with macros typing secret text,
+/// returns names of substituted secrets.
+fn substitute_secrets(config: &mut Value, secrets: Option<&dyn SecretProvider>) -> Result<SecretNames> {
+    let mut names = SecretNames::default();
+    let Some(config) = config.as_mapping_mut() else {
+        return Ok(names);
+    };
+    for field in ["macros", "layers"] {
+        if let Some(value) = config.get_mut(field) {
+            substitute_strings(value, &mut |s| {
+                let Some(name) = s.trim().strip_prefix("secret:") else {
+                    return Ok(s.to_owned());
+                };
+                let secrets = secrets.ok_or_else(|| anyhow!("'{s}' references secret, but secrets aren't enabled"))?;
+                let text = secrets.lookup(name).with_context(|| format!("read secret '{name}'"))?;
+                let typing = secrets::typing_macro(&text).with_context(|| format!("type secret '{name}'"))?;
+                names.insert(typing.parse()?, name);
+                Ok(typing)
+            })?;
+        }
+    }
+    Ok(names)
+}
+
+fn substitute_strings(value: &mut Value, substitute: &mut dyn FnMut(&str) -> Result<String>) -> Result<()> {
+    match value {
+        Value::String(s) => *s = substitute(s)?,
+        Value::Sequence(values) => for value in values {
+            substitute_strings(value, substitute)?;
+        },
+        Value::Mapping(mapping) => for (_, value) in mapping.iter_mut() {
+            substitute_strings(value, substitute)?;
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate(s: &str, lookup: Lookup) -> Result<String> {
+    let mut result = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let end = reference.find('}').ok_or_else(|| anyhow!("unterminated variable reference in '{s}'"))?;
+        let name = &reference[..end];
+        ensure!(!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "invalid variable name '{name}' in '{s}'");
+        let value = lookup(name).ok_or_else(|| anyhow!("environment variable {name} used in '{s}' is not set"))?;
+        result.push_str(&value);
+        rest = &reference[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Expands `scroll: <modifier>` of knobs into `ccw` and `cw` scrolling wheel
+/// with modifier held, like `ctrl` for zoom or `shift` for horizontal scroll.
+/// Known firmwares can't switch modifier on press, so knob scrolling differently
+/// on another layer is the way to toggle it.
+fn expand_scroll_knobs(config: &mut Value) -> Result<()> {
+    let Some(Value::Sequence(layers)) = config.get_mut("layers") else {
+        return Ok(());
+    };
+    for (i, layer) in layers.iter_mut().enumerate() {
+        let Some(Value::Sequence(knobs)) = layer.get_mut("knobs") else {
+            continue;
+        };
+        for (k, knob) in knobs.iter_mut().enumerate() {
+            let Some(knob) = knob.as_mapping_mut() else {
+                continue;
+            };
+            let Some(scroll) = knob.remove("scroll") else {
+                continue;
+            };
+            let context = || format!("knob {k} in layer {i}");
+            ensure!(!knob.contains_key("ccw") && !knob.contains_key("cw"),
+                    "{}: 'scroll' can't be combined with 'ccw' or 'cw'", context());
+            let prefix = match scroll.as_str() {
+                Some("none") => String::new(),
+                Some(modifier) => {
+                    let modifier = MouseModifier::from_str(modifier)
+                        .map_err(|_| anyhow!("{}: scroll modifier must be ctrl, shift, alt or none", context()))?;
+                    format!("{modifier}-")
+                }
+                None => bail!("{}: scroll modifier must be ctrl, shift, alt or none", context()),
+            };
+            knob.insert("ccw".into(), format!("{prefix}wheeldown").into());
+            knob.insert("cw".into(), format!("{prefix}wheelup").into());
+        }
+    }
+    Ok(())
+}
+
+/// Reads custom key names, they must be known before macros using them are parsed.
+fn read_keycodes(config: &Value) -> Result<HashMap<String, Code>> {
+    let Some(value) = config.get("keycodes") else {
+        return Ok(HashMap::new());
+    };
+    let keycodes: HashMap<String, Code> = serde_yaml::from_value(value.clone()).context("parse keycodes")?;
+    for name in keycodes.keys() {
+        ensure!(!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "invalid key name '{name}': only letters, digits and underscores are allowed");
+        ensure!(name.parse::<Macro>().is_err(), "key name '{name}' is already used by built-in key, modifier or action");
+    }
+    Ok(keycodes)
+}
+
+/// Resolves `extends` chain of config loaded from `origin` file (or stdin if `None`).
+/// Format of parent configs is detected by file extension.
+///
+/// Parent config path is relative to the extending file. Top-level fields of
+/// extending config override parent ones, except for `macros` and `keycodes` which
+/// are merged by name, and `layers` which are merged by index: missing or null (`~`) layers
+/// are inherited from parent, and so are `buttons` and `knobs` missing in a layer.
+pub fn resolve_extends(value: Value, origin: Option<&Path>) -> Result<Value> {
+    let mut chain = vec![];
+    if let Some(origin) = origin {
+        chain.push(ChainEntry::new(origin)?);
+    }
+    resolve_extends_impl(value, origin.and_then(Path::parent).unwrap_or(Path::new("")), &mut chain)
+}
+
+struct ChainEntry {
+    path: PathBuf,
+    canonical: PathBuf,
+}
+
+impl ChainEntry {
+    fn new(path: &Path) -> Result<Self> {
+        let canonical = path.canonicalize()
+            .with_context(|| format!("resolve config path {}", path.display()))?;
+        Ok(Self { path: path.to_owned(), canonical })
+    }
+}
+
+fn format_chain(chain: &[ChainEntry]) -> String {
+    chain.iter().map(|entry| entry.path.display()).join(" -> ")
+}
+
+fn resolve_extends_impl(mut value: Value, base_dir: &Path, chain: &mut Vec<ChainEntry>) -> Result<Value> {
+    let Some(parent) = value.as_mapping_mut().and_then(|m| m.remove("extends")) else {
+        return Ok(value);
+    };
+    let parent = parent.as_str().ok_or_else(|| anyhow!("'extends' must be a path to config file"))?;
+
+    let entry = ChainEntry::new(&base_dir.join(parent))?;
+    let is_cycle = chain.iter().any(|e| e.canonical == entry.canonical);
+    chain.push(entry);
+    if is_cycle {
+        bail!("config inheritance cycle: {}", format_chain(chain));
+    }
+
+    let path = &chain.last().unwrap().path;
+    let parent_value = std::fs::File::open(path).map_err(anyhow::Error::from)
+        .and_then(|file| ConfigFormat::from_path(path).read_value(std::io::BufReader::new(file)))
+        .with_context(|| format!("load config {}", format_chain(chain)))?;
+    let parent_dir = path.parent().unwrap_or(Path::new("")).to_owned();
+    let parent_value = resolve_extends_impl(parent_value, &parent_dir, chain)?;
+    chain.pop();
+
+    Ok(merge_configs(parent_value, value))
+}
+
+/// Files config at `path` is loaded from: the file itself or fragments of directory,
+/// and files they extend, including ones extended by device configs. Files which
+/// can't be read are listed but not followed, so that they may be watched for fixes.
+pub fn config_files(path: &Path) -> Vec<PathBuf> {
+    let mut pending = if path.is_dir() {
+        let entries = std::fs::read_dir(path).into_iter().flatten();
+        entries.filter_map(|entry| Some(entry.ok()?.path())).filter(|path| is_fragment(path)).sorted().collect()
+    } else {
+        vec![path.to_owned()]
+    };
+    pending.reverse();
+
+    let mut files = vec![];
+    let mut seen = vec![];
+    while let Some(path) = pending.pop() {
+        // Paths are compared canonical, so that cycles through `..` end.
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if seen.contains(&canonical) {
+            continue;
+        }
+        seen.push(canonical);
+        let value = std::fs::File::open(&path).map_err(anyhow::Error::from)
+            .and_then(|file| ConfigFormat::from_path(&path).read_value(std::io::BufReader::new(file)));
+        if let Ok(value) = value {
+            let dir = path.parent().unwrap_or(Path::new(""));
+            let devices = value.get("devices").and_then(Value::as_sequence).into_iter().flatten();
+            for config in [&value].into_iter().chain(devices) {
+                if let Some(parent) = config.get("extends").and_then(Value::as_str) {
+                    pending.push(dir.join(parent));
+                }
+            }
+        }
+        files.push(path);
+    }
+    files
+}
+
+/// Reads config fragments (`.yaml`, `.yml` and `.json` files) from directory and merges
+/// them in lexical order of file names, each one like if it extended all previous ones.
+/// So fragment may override fields, add layers or change some of them using `~` for layers
+/// kept as is. Fragment may extend other config itself, relative to its own path.
+pub fn read_fragments(dir: &Path) -> Result<Value> {
+    let mut paths = std::fs::read_dir(dir)
+        .with_context(|| format!("read config directory {}", dir.display()))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("read config directory {}", dir.display()))?;
+    paths.retain(|path| is_fragment(path));
+    paths.sort();
+    ensure!(!paths.is_empty(), "no config fragments (.yaml, .yml or .json files) in {}", dir.display());
+
+    paths.iter().try_fold(Value::Null, |merged, path| {
+        let value = std::fs::File::open(path).map_err(anyhow::Error::from)
+            .and_then(|file| ConfigFormat::from_path(path).read_value(std::io::BufReader::new(file)))
+            .and_then(|value| resolve_extends(value, Some(path)))
+            .with_context(|| format!("load config fragment {}", path.display()))?;
+        Ok(merge_configs(merged, value))
+    })
+}
+
+fn is_fragment(path: &Path) -> bool {
+    let hidden = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with('.'));
+    let known_extension = path.extension().and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ["yaml", "yml", "json"].iter().any(|known| ext.eq_ignore_ascii_case(known)));
+    !hidden && known_extension && path.is_file()
+}
+
+fn merge_configs(parent: Value, child: Value) -> Value {
+    let (mut parent, child) = match (parent, child) {
+        (Value::Mapping(parent), Value::Mapping(child)) => (parent, child),
+        (_, child) => return child,
+    };
+    for (key, child_value) in child {
+        let merged = match (key.as_str(), parent.remove(&key)) {
+            (Some("layers"), Some(Value::Sequence(parent_layers))) => match child_value {
+                Value::Sequence(child_layers) => Value::Sequence(merge_layers(parent_layers, child_layers)),
+                child_value => child_value,
+            },
+            (Some("macros" | "keycodes"), Some(Value::Mapping(mut parent_macros))) => match child_value {
+                Value::Mapping(child_macros) => {
+                    parent_macros.extend(child_macros);
+                    Value::Mapping(parent_macros)
+                }
+                child_value => child_value,
+            },
+            (_, _) => child_value,
+        };
+        parent.insert(key, merged);
+    }
+    Value::Mapping(parent)
+}
+
+fn merge_layers(parent: Vec<Value>, child: Vec<Value>) -> Vec<Value> {
+    let mut parent = parent.into_iter();
+    let mut child = child.into_iter();
+    let mut layers = vec![];
+    loop {
+        let layer = match (parent.next(), child.next()) {
+            (None, None) => break,
+            (Some(parent), None | Some(Value::Null)) => parent,
+            (Some(Value::Mapping(parent)), Some(Value::Mapping(child))) => {
+                let mut layer: Mapping = parent;
+                layer.extend(child);
+                Value::Mapping(layer)
+            }
+            (_, Some(child)) => child,
+        };
+        layers.push(layer);
+    }
+    layers
+}
+
 fn reorient_row<T>(orientation: Orientation, mut data: Vec<T>) -> Vec<T> {
     let reverse = match orientation {
         Orientation::Normal => false,
@@ -114,10 +995,15 @@ fn reorient_row<T>(orientation: Orientation, mut data: Vec<T>) -> Vec<T> {
 mod tests {
     use crate::config::Layer;
 
-    use super::{reorient_grid, Config, Knob, Orientation};
+    use super::{
+        config_files, reorient_grid, resolve_extends, substitute_env, substitute_secrets, Config, ConfigFormat, DeviceChoice, DeviceConfig, DeviceSelector,
+        Geometry, Hooks, Knob, LoadOptions, Orientation, TargetOs,
+    };
 
     use std::path::PathBuf;
 
+    use indoc::indoc;
+
     #[test]
     fn parse_example_config() -> anyhow::Result<()> {
         let mut path = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
@@ -130,6 +1016,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_button_index() {
+        // 3 rows and 4 columns in normal orientation.
+        assert_eq!(Orientation::Normal.button_index(3, 4, 0, 0), Some(0));
+        assert_eq!(Orientation::UpsideDown.button_index(3, 4, 0, 0), Some(11));
+        assert_eq!(Orientation::Clockwise.button_index(3, 4, 0, 0), Some(8));
+        assert_eq!(Orientation::CounterClockwise.button_index(3, 4, 0, 0), Some(3));
+        assert_eq!(Orientation::Clockwise.button_index(3, 4, 0, 2), Some(0));
+        assert_eq!(Orientation::Clockwise.button_index(3, 4, 0, 3), None);
+
+        assert_eq!(Orientation::Normal.button_position(3, 4, 5), Some((1, 1)));
+        assert_eq!(Orientation::Clockwise.button_position(3, 4, 0), Some((0, 2)));
+        assert_eq!(Orientation::UpsideDown.button_position(3, 4, 11), Some((0, 0)));
+        assert_eq!(Orientation::Normal.button_position(3, 4, 12), None);
+    }
+
+    #[test]
+    fn test_knob_labels() {
+        assert_eq!(Orientation::Normal.knob_label(0, 2), "left knob");
+        assert_eq!(Orientation::Clockwise.knob_label(2, 3), "bottom knob");
+        assert_eq!(Orientation::Normal.knob_label(0, 1), "knob");
+        assert_eq!(Orientation::CounterClockwise.knob_label(3, 4), "knob 4 from top");
+
+        assert_eq!(Orientation::Normal.knob_index(0, 3), 0);
+        assert_eq!(Orientation::UpsideDown.knob_index(0, 3), 2);
+        assert_eq!(Orientation::Clockwise.knob_index(2, 3), 0);
+    }
+
     #[test]
     fn test_reorient_grid() {
         assert_eq!(
@@ -172,8 +1086,15 @@ mod tests {
             rows: 1,
             columns: 3,
             knobs: 1,
+            variant: None,
+            keycodes: Default::default(),
+            macros: Default::default(),
+            led: None,
+            hooks: Default::default(),
+            secrets: Default::default(),
             layers: vec![
                 Layer {
+                    orientation: None,
                     buttons: vec![
                         vec![
                             Some("a,alt-b".parse().unwrap()),
@@ -187,4 +1108,493 @@ mod tests {
         };
         config.render().unwrap();
     }
+
+    fn write_temp_config(name: &str, content: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("ch57x-test-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        path.push(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_extends() -> anyhow::Result<()> {
+        write_temp_config("extends-base.yaml", indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 2
+            knobs: 1
+            layers:
+              - buttons: [[a, b]]
+                knobs: [{ccw: volumedown, press: mute, cw: volumeup}]
+              - buttons: [[c, d]]
+                knobs: [{ccw: left, press: enter, cw: right}]
+        "});
+        let child = write_temp_config("extends-child.yaml", indoc! {"
+            extends: extends-base.yaml
+            orientation: upsidedown
+            layers:
+              - ~
+              - buttons: [[e, f]]
+              - buttons: [[g, h]]
+                knobs: [{ccw: up, press: ~, cw: down}]
+        "});
+
+        let value = serde_yaml::from_reader(std::fs::File::open(&child)?)?;
+        let config: Config = serde_yaml::from_value(resolve_extends(value, Some(&child))?)?;
+        assert_eq!(config.orientation, Orientation::UpsideDown);
+        assert_eq!(config.columns, 2);
+        assert_eq!(config.layers.len(), 3);
+        assert_eq!(config.layers[0].buttons, vec![vec![Some("a".parse()?), Some("b".parse()?)]]);
+        assert_eq!(config.layers[1].buttons, vec![vec![Some("e".parse()?), Some("f".parse()?)]]);
+        assert_eq!(config.layers[1].knobs[0].press, Some("enter".parse()?));
+        assert_eq!(config.layers[2].knobs[0].press, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_files() {
+        let top = write_temp_config("files-top.yaml", "extends: files-base.yaml\ndevices:\n  - extends: files-device.yaml\n");
+        let base = write_temp_config("files-base.yaml", "extends: files-top.yaml");
+        let device = top.with_file_name("files-device.yaml");
+        std::fs::remove_file(&device).ok();
+        // Missing file is listed, so that it's noticed when created.
+        assert_eq!(config_files(&top), [top.clone(), device, base]);
+    }
+
+    #[test]
+    fn test_extends_cycle() {
+        let a = write_temp_config("cycle-a.yaml", "extends: cycle-b.yaml");
+        write_temp_config("cycle-b.yaml", "extends: cycle-a.yaml");
+
+        let value = serde_yaml::from_str("extends: cycle-b.yaml").unwrap();
+        let err = resolve_extends(value, Some(&a)).unwrap_err();
+        assert!(err.to_string().starts_with("config inheritance cycle: "), "{err}");
+        assert_eq!(err.to_string().matches("cycle-a.yaml").count(), 2, "{err}");
+        assert_eq!(err.to_string().matches("cycle-b.yaml").count(), 1, "{err}");
+    }
+
+    #[test]
+    fn test_fragments() -> anyhow::Result<()> {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("ch57x-test-{}-fragments", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let write = |name: &str, content: &str| std::fs::write(dir.join(name), content);
+        write("20-obs.yaml", indoc! {"
+            layers:
+              - ~
+              - buttons: [[f13, f14]]
+        "})?;
+        write("10-base.yaml", indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 2
+            knobs: 0
+            layers:
+              - buttons: [[a, b]]
+                knobs: []
+              - buttons: [[c, d]]
+                knobs: []
+        "})?;
+        write("30-flip.json", r#"{"orientation": "upsidedown"}"#)?;
+        write("README.md", "not a config")?;
+
+        let config = Config::load(&dir)?;
+        assert_eq!(config.orientation, Orientation::UpsideDown);
+        assert_eq!(config.layers.len(), 2);
+        assert_eq!(config.layers[0].buttons, vec![vec![Some("a".parse()?), Some("b".parse()?)]]);
+        assert_eq!(config.layers[1].buttons, vec![vec![Some("f13".parse()?), Some("f14".parse()?)]]);
+        assert_eq!(config.layers[1].knobs.len(), 0);
+
+        let empty = dir.join("empty");
+        std::fs::create_dir_all(&empty)?;
+        assert!(Config::load(&empty).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hooks() -> anyhow::Result<()> {
+        let config: Config = serde_yaml::from_str(indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 1
+            knobs: 0
+            hooks:
+              upload: notify-send uploaded
+            layers: []
+        "})?;
+        assert_eq!(config.hooks, Hooks { validate: None, upload: Some("notify-send uploaded".to_owned()) });
+        assert!(serde_yaml::from_str::<Hooks>("render: ls").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_named_macros() -> anyhow::Result<()> {
+        let config: Config = serde_yaml::from_str(indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 2
+            knobs: 1
+            macros:
+              copy: ctrl-c
+            layers:
+              - buttons: [[$copy, a]]
+                knobs: [{ccw: ~, press: $copy, cw: ~}]
+        "})?;
+        let layers = config.render()?;
+        assert_eq!(layers[0].buttons[0], Some("ctrl-c".parse()?));
+        assert_eq!(layers[0].knobs[0].press, Some("ctrl-c".parse()?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keycodes() -> anyhow::Result<()> {
+        let config = indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 2
+            knobs: 0
+            keycodes:
+              mute_mic: <239>
+            layers:
+              - buttons: [[mute_mic, ctrl-mute_mic]]
+                knobs: []
+        "};
+        let config = Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None)?;
+        let layers = config.render()?;
+        assert_eq!(layers[0].buttons[0], Some("<239>".parse()?));
+        assert_eq!(layers[0].buttons[1], Some("ctrl-<239>".parse()?));
+
+        let config = indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 1
+            knobs: 0
+            layers:
+              - buttons: [[ctrl-mute_mic]]
+                knobs: []
+        "};
+        let err = Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None).unwrap_err();
+        assert!(err.to_string().contains("unknown key name 'mute_mic'"), "{err}");
+
+        let err = Config::from_reader("keycodes: {play: <1>}".as_bytes(), ConfigFormat::Yaml, None).unwrap_err();
+        assert!(err.to_string().contains("already used"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_variant() -> anyhow::Result<()> {
+        let config = |rows, columns, knobs| format!(indoc! {"
+            orientation: normal
+            rows: {}
+            columns: {}
+            knobs: {}
+            variant: 12+4
+            layers: []
+        "}, rows, columns, knobs);
+
+        let fits: Config = serde_yaml::from_str(&config(3, 4, 2))?;
+        assert_eq!(fits.geometry(), Geometry { buttons: 12, knobs: 4 });
+        fits.render()?;
+
+        let too_many_buttons: Config = serde_yaml::from_str(&config(5, 3, 3))?;
+        let err = too_many_buttons.render().unwrap_err();
+        assert!(err.to_string().contains("variant 12+4 has fewer"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_led() -> anyhow::Result<()> {
+        let config = |led: &str| format!(indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 1
+            knobs: 0
+            {}
+            layers: []
+        "}, led);
+
+        let parse = |led: &str| serde_yaml::from_str::<Config>(&config(led)).map(|c| c.led);
+        assert_eq!(parse("")?, None);
+        assert_eq!(parse("led: 2")?, Some(2));
+        let err = parse("led: [1, ~, 0]").unwrap_err();
+        assert!(err.to_string().contains("LED modes by layer aren't supported"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_orientation() -> anyhow::Result<()> {
+        let config: Config = serde_yaml::from_str(indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 2
+            knobs: 0
+            layers:
+              - buttons: [[a, b]]
+                knobs: []
+              - orientation: counterclockwise
+                buttons: [[a], [b]]
+                knobs: []
+        "})?;
+        let layers = config.render()?;
+        assert_eq!(layers[0].buttons, vec![Some("a".parse()?), Some("b".parse()?)]);
+        assert_eq!(layers[1].buttons, vec![Some("b".parse()?), Some("a".parse()?)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_named_macro() {
+        let config: Config = serde_yaml::from_str(indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 1
+            knobs: 0
+            layers:
+              - buttons: [[$paste]]
+                knobs: []
+        "}).unwrap();
+        let err = config.render().unwrap_err();
+        assert_eq!(format!("{err:#}"), "resolve buttons in layer 0: unknown macro reference $paste");
+    }
+
+    #[test]
+    fn test_labels() -> anyhow::Result<()> {
+        let config = indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 3
+            knobs: 0
+            layers:
+              - buttons: [[{ map: ctrl-shift-t, label: Reopen tab }, { map: { macos: cmd-c, default: ctrl-c } }, { macos: cmd-v }]]
+                knobs: []
+        "};
+        let config = Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None)?;
+        let describe = |os| config.layers[0].buttons[0].iter().map(|b| b.as_ref().and_then(|b| b.describe(os))).collect::<Vec<_>>();
+        assert_eq!(describe(TargetOs::MacOs), [Some("Reopen tab".to_owned()), Some("cmd-c".to_owned()), Some("cmd-v".to_owned())]);
+        assert_eq!(describe(TargetOs::Linux), [Some("Reopen tab".to_owned()), Some("ctrl-c".to_owned()), None]);
+
+        let layers = config.render_for(TargetOs::Linux)?;
+        assert_eq!(layers[0].buttons, [Some("ctrl-shift-t".parse()?), Some("ctrl-c".parse()?), None]);
+
+        let config = "orientation: normal\nrows: 1\ncolumns: 1\nknobs: 0\nlayers:\n  - buttons: [[{ map: a, title: A }]]\n    knobs: []\n";
+        assert!(Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_devices() -> anyhow::Result<()> {
+        let config = indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 2
+            knobs: 0
+            macros:
+              copy: ctrl-c
+            devices:
+              - product_id: 0x8890
+                address: '1:5'
+                layers:
+                  - buttons: [[$copy, a]]
+                    knobs: []
+              - serial: ABC
+                columns: 1
+                layers:
+                  - buttons: [[b]]
+                    knobs: []
+        "};
+        let devices = Config::load_devices(config.as_bytes(), ConfigFormat::Yaml, None, LoadOptions::default())?;
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].selector, DeviceSelector { product_id: Some(0x8890), address: Some((1, 5)), serial: None });
+        assert_eq!(devices[1].selector, DeviceSelector { serial: Some("ABC".to_owned()), ..Default::default() });
+
+        let mut devices = devices.into_iter().map(|d| d.config.render()).collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(devices[0].remove(0).buttons, vec![Some("ctrl-c".parse()?), Some("a".parse()?)]);
+        assert_eq!(devices[1].remove(0).buttons, vec![Some("b".parse()?)]);
+
+        // Config without device list describes single device selected by command line options.
+        let single = config.split("devices:").next().unwrap().to_owned() + "layers: []\n";
+        let devices = Config::load_devices(single.as_bytes(), ConfigFormat::Yaml, None, LoadOptions::default())?;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].selector, DeviceSelector::default());
+
+        assert!(Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_choose_device() -> anyhow::Result<()> {
+        let device = |selector: DeviceSelector, columns| -> anyhow::Result<DeviceConfig> {
+            let config = format!("orientation: normal\nrows: 1\ncolumns: {columns}\nknobs: 0\nlayers: []\n");
+            Ok(DeviceConfig { selector, config: Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None)? })
+        };
+        let configs = || -> anyhow::Result<Vec<DeviceConfig>> {
+            Ok(vec![
+                device(DeviceSelector { product_id: Some(0x8890), address: Some((1, 5)), serial: None }, 1)?,
+                device(DeviceSelector { serial: Some("12AB".to_owned()), ..Default::default() }, 2)?,
+            ])
+        };
+
+        assert_eq!("1".parse::<DeviceChoice>()?, DeviceChoice::Index(1));
+        assert_eq!(DeviceConfig::choose(configs()?, Some(&"1".parse()?))?.config.columns, 2);
+        assert_eq!(DeviceConfig::choose(configs()?, Some(&"serial=12AB".parse()?))?.config.columns, 2);
+        assert_eq!(DeviceConfig::choose(configs()?, Some(&"product_id=0x8890".parse()?))?.config.columns, 1);
+        assert_eq!(DeviceConfig::choose(configs()?, Some(&"address=1:5".parse()?))?.config.columns, 1);
+
+        let err = DeviceConfig::choose(configs()?, None).unwrap_err();
+        assert_eq!(err.to_string(), "config describes 2 devices, choose one with --device");
+        assert!(DeviceConfig::choose(configs()?, Some(&DeviceChoice::Index(2))).is_err());
+        assert!(DeviceConfig::choose(configs()?, Some(&"serial=XYZ".parse()?)).is_err());
+        assert!("color=red".parse::<DeviceChoice>().is_err());
+        assert!("first".parse::<DeviceChoice>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_os_macros() -> anyhow::Result<()> {
+        let config = indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 3
+            knobs: 0
+            macros:
+              copy: { macos: cmd-c, default: ctrl-c }
+            layers:
+              - buttons: [[$copy, { linux: a }, b]]
+                knobs: []
+        "};
+        let render = |os| Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None)?.render_for(os);
+        assert_eq!(render(TargetOs::MacOs)?[0].buttons, vec![Some("cmd-c".parse()?), None, Some("b".parse()?)]);
+        assert_eq!(render(TargetOs::Linux)?[0].buttons, vec![Some("ctrl-c".parse()?), Some("a".parse()?), Some("b".parse()?)]);
+
+        let linux_only = config.replace("macos: cmd-c, default: ctrl-c", "linux: ctrl-c");
+        let err = Config::from_reader(linux_only.as_bytes(), ConfigFormat::Yaml, None)?.render_for(TargetOs::Windows).unwrap_err();
+        assert_eq!(format!("{err:#}"), "resolve buttons in layer 0: macro '$copy' has no variant for windows");
+
+        let invalid = config.replace("linux: a", "linux: nokey");
+        let err = Config::from_reader(invalid.as_bytes(), ConfigFormat::Yaml, None).unwrap_err();
+        assert!(format!("{err:#}").contains("unknown key name 'nokey'"), "{err:#}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_substitute_env() -> anyhow::Result<()> {
+        let config = indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 2
+            knobs: 0
+            macros:
+              mute: ${MOD}-shift-m
+            layers:
+              - buttons: [[$mute, '${MOD}-${KEY}']]
+                knobs: []
+        "};
+        let lookup = |name: &str| match name {
+            "MOD" => Some("ctrl".to_owned()),
+            "KEY" => Some("k".to_owned()),
+            _ => None,
+        };
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(config)?;
+        substitute_env(&mut value, Some(&lookup))?;
+        let layers = serde_yaml::from_value::<Config>(value)?.render()?;
+        assert_eq!(layers[0].buttons, vec![Some("ctrl-shift-m".parse()?), Some("ctrl-k".parse()?)]);
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&config.replace("KEY", "UNSET"))?;
+        let err = substitute_env(&mut value, Some(&lookup)).unwrap_err();
+        assert_eq!(err.to_string(), "environment variable UNSET used in '${MOD}-${UNSET}' is not set");
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(config)?;
+        assert!(substitute_env(&mut value, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scroll_knobs() -> anyhow::Result<()> {
+        let config = indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 1
+            knobs: 2
+            layers:
+              - buttons: [[a]]
+                knobs:
+                  - { scroll: ctrl, press: mute }
+                  - { scroll: none }
+              - buttons: [[a]]
+                knobs:
+                  - { scroll: Shift }
+                  - {}
+        "};
+        let layers = Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None)?.render()?;
+        let knob = |l: usize, k: usize| [&layers[l].knobs[k].ccw, &layers[l].knobs[k].press, &layers[l].knobs[k].cw]
+            .map(|m| m.as_ref().map(|m| m.to_string()));
+        assert_eq!(knob(0, 0), [Some("ctrl-wheeldown".to_owned()), Some("mute".to_owned()), Some("ctrl-wheelup".to_owned())]);
+        assert_eq!(knob(0, 1), [Some("wheeldown".to_owned()), None, Some("wheelup".to_owned())]);
+        assert_eq!(knob(1, 0), [Some("shift-wheeldown".to_owned()), None, Some("shift-wheelup".to_owned())]);
+
+        let err = Config::from_reader(config.replace("scroll: none", "scroll: win").as_bytes(), ConfigFormat::Yaml, None).unwrap_err();
+        assert_eq!(err.to_string(), "knob 1 in layer 0: scroll modifier must be ctrl, shift, alt or none");
+        let err = Config::from_reader(config.replace("scroll: none", "scroll: none, cw: a").as_bytes(), ConfigFormat::Yaml, None).unwrap_err();
+        assert_eq!(err.to_string(), "knob 1 in layer 0: 'scroll' can't be combined with 'ccw' or 'cw'");
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct TestSecrets;
+
+    impl crate::secrets::SecretProvider for TestSecrets {
+        fn lookup(&self, name: &str) -> anyhow::Result<String> {
+            match name {
+                "pin" => Ok("12Ab".to_owned()),
+                _ => anyhow::bail!("secret '{name}' not found"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_substitute_secrets() -> anyhow::Result<()> {
+        let config = indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 2
+            knobs: 0
+            macros:
+              pin: secret:pin
+            layers:
+              - buttons: [[$pin, secret:pin]]
+                knobs: []
+        "};
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(config)?;
+        let names = substitute_secrets(&mut value, Some(&TestSecrets))?;
+        let layers = serde_yaml::from_value::<Config>(value)?.render()?;
+        assert_eq!(layers[0].buttons, vec![Some("1,2,shift-a,b".parse()?); 2]);
+        assert_eq!(names.show(layers[0].buttons[0].as_ref().unwrap()), "secret:pin");
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&config.replace("secret:pin]", "secret:unknown]"))?;
+        let err = substitute_secrets(&mut value, Some(&TestSecrets)).unwrap_err();
+        assert_eq!(format!("{err:#}"), "read secret 'unknown': secret 'unknown' not found");
+
+        let mut value: serde_yaml::Value = serde_yaml::from_str(config)?;
+        let err = substitute_secrets(&mut value, None).unwrap_err();
+        assert_eq!(err.to_string(), "'secret:pin' references secret, but secrets aren't enabled");
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_config() -> anyhow::Result<()> {
+        let json = r#"{
+            "orientation": "normal", "rows": 1, "columns": 2, "knobs": 0,
+            "layers": [{"buttons": [["a", "ctrl-c"]], "knobs": []}]
+        }"#;
+        let config = Config::from_reader(json.as_bytes(), ConfigFormat::Json, None)?;
+        let layers = config.render()?;
+        assert_eq!(layers[0].buttons[1], Some("ctrl-c".parse()?));
+
+        // JSON format is strict, so YAML is rejected.
+        assert!(Config::from_reader("rows: 1".as_bytes(), ConfigFormat::Json, None).is_err());
+        Ok(())
+    }
 }
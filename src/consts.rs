@@ -1,2 +1,5 @@
 pub const VENDOR_ID: u16 = 0x1189;
 pub const PRODUCT_IDS: [u16; 3] = [0x8840, 0x8842, 0x8890];
+
+/// Number of layers on all keyboards seen so far.
+pub const LAYER_COUNT: u8 = 3;
@@ -0,0 +1,164 @@
+//! Daemon uploading configs to keyboards as they are connected.
+//!
+//! Configs are chosen by profiles in TOML file:
+//!
+//! ```toml
+//! [[device]]
+//! product_id = 0x8890
+//! serial = "A1B2"
+//! config = "work.yaml"
+//! ```
+//!
+//! First profile matching connected keyboard is used, profile without
+//! criteria matches any keyboard. Config paths are relative to profiles file.
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::{ensure, Context as _, Result};
+use rusb::{Hotplug, HotplugBuilder, UsbContext as _};
+use serde::Deserialize;
+
+use ch57x_keyboard::config::{DeviceSelector, TargetOs};
+
+use crate::options::{ConfigParams, Options, UploadCommand};
+use crate::Uploaded;
+
+/// Time given to just connected device to settle before it is opened.
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profiles {
+    #[serde(rename = "device", default)]
+    devices: Vec<Profile>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    #[serde(default)]
+    product_id: Option<u16>,
+    #[serde(default)]
+    serial: Option<String>,
+    config: PathBuf,
+}
+
+impl Profile {
+    fn matches(&self, product_id: u16, serial: Option<&str>) -> bool {
+        self.product_id.is_none_or(|id| id == product_id)
+            && self.serial.as_deref().is_none_or(|s| Some(s) == serial)
+    }
+}
+
+/// Sends bus number and address of each connected device.
+struct Arrivals(mpsc::Sender<(u8, u8)>);
+
+impl Hotplug<rusb::Context> for Arrivals {
+    fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
+        // Receiver is only dropped when daemon exits.
+        let _ = self.0.send((device.bus_number(), device.address()));
+    }
+
+    fn device_left(&mut self, _device: rusb::Device<rusb::Context>) {}
+}
+
+pub fn run(options: &Options, profiles_path: &Path) -> Result<()> {
+    ensure!(rusb::has_hotplug(), "USB hotplug isn't supported on this platform");
+    // Profiles are checked at start, so that mistakes aren't found only when device is connected.
+    load_profiles(profiles_path)?;
+    // Nobody watches upload progress.
+    crate::set_quiet(true);
+
+    let context = rusb::Context::new()?;
+    let (sender, arrivals) = mpsc::channel();
+    // Already connected devices are reported too.
+    let _registration = HotplugBuilder::new()
+        .vendor_id(options.devel_options.vendor_id)
+        .enumerate(true)
+        .register(&context, Box::new(Arrivals(sender)))
+        .context("register hotplug callback")?;
+    // Device can't be opened from callback, so events are handled on separate thread.
+    std::thread::spawn({
+        let context = context.clone();
+        move || loop {
+            if let Err(err) = context.handle_events(None) {
+                eprintln!("warning: can't handle USB events: {err}");
+            }
+        }
+    });
+
+    println!("waiting for keyboards, press Ctrl-C to stop");
+    for address in arrivals {
+        std::thread::sleep(SETTLE_DELAY);
+        if let Err(err) = program(options, profiles_path, address) {
+            eprintln!("{}:{}: upload failed: {err:#}", address.0, address.1);
+        }
+    }
+    Ok(())
+}
+
+/// Uploads config of matching profile to device, profiles are reloaded each time,
+/// so that changes are picked up without restart.
+fn program(options: &Options, profiles_path: &Path, address: (u8, u8)) -> Result<()> {
+    let profiles = load_profiles(profiles_path)?;
+    let device = crate::find_selected_device(options, &DeviceSelector { address: Some(address), ..Default::default() })?;
+    let serial = device.serial_number().ok().flatten();
+    let Some(profile) = profiles.devices.iter().find(|p| p.matches(device.product_id(), serial.as_deref())) else {
+        println!("{}: no profile matches, skipped", crate::describe_device(&device));
+        return Ok(());
+    };
+
+    let config_path = profiles_path.parent().unwrap_or(Path::new("")).join(&profile.config);
+    let config_params = ConfigParams {
+        config_path: Some(config_path.into_os_string()),
+        format: None,
+        allow_env: false,
+        allow_secrets: false,
+        sha256: None,
+        target_os: TargetOs::host(),
+        device: None,
+    };
+    let rendered = crate::render(crate::load_config(&config_params)?, config_params.target_os)?;
+    let command = UploadCommand::defaults(config_params);
+    match crate::upload(options, &device, &rendered, &command)? {
+        Uploaded::UpToDate => println!("{}: already up to date 👌", crate::describe_device(&device)),
+        Uploaded::Bindings { count, .. } => println!("{}: uploaded {count} bindings 👌", crate::describe_device(&device)),
+    }
+    Ok(())
+}
+
+fn load_profiles(path: &Path) -> Result<Profiles> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("parse {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() -> Result<()> {
+        let profiles: Profiles = toml::from_str(indoc::indoc! {r#"
+            [[device]]
+            product_id = 0x8890
+            serial = "A1"
+            config = "work.yaml"
+
+            [[device]]
+            config = "default.yaml"
+        "#})?;
+        assert_eq!(profiles.devices.len(), 2);
+
+        let matching = |product_id, serial| {
+            profiles.devices.iter().find(|p: &&Profile| p.matches(product_id, serial)).map(|p| p.config.clone())
+        };
+        assert_eq!(matching(0x8890, Some("A1")), Some(PathBuf::from("work.yaml")));
+        assert_eq!(matching(0x8890, None), Some(PathBuf::from("default.yaml")));
+        assert_eq!(matching(0x8840, Some("A1")), Some(PathBuf::from("default.yaml")));
+
+        assert!(toml::from_str::<Profiles>("[[device]]\nconfig = 'a.yaml'\nname = 'x'").is_err());
+        Ok(())
+    }
+}
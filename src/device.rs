@@ -0,0 +1,656 @@
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, ensure, Context as _, Result};
+use indoc::indoc;
+use itertools::Itertools as _;
+use log::{debug, warn};
+use rusb::{Context, DeviceDescriptor, TransferType};
+
+use rusb::UsbContext as _;
+
+use crate::config::Config;
+use crate::consts::VENDOR_ID;
+use crate::exit::ConfigError;
+use crate::hid;
+use crate::keyboard::mock::MockDevice;
+use crate::keyboard::{Geometry, Keyboard, Model, TransferOptions, Transport, DEFAULT_PACKET_SIZE};
+use crate::quirks::{Capabilities, DeviceKey, Quirks};
+use crate::traffic::{self, Direction};
+
+/// Error of choosing device, distinguished so that callers may tell why it failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FindError {
+    NotFound,
+    /// Addresses of all found devices.
+    Several(Vec<(u8, u8)>),
+}
+
+impl std::fmt::Display for FindError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // Wireless keyboards aren't seen as USB devices unless connected with cable,
+            // and bindings can't be sent over Bluetooth or 2.4G link.
+            FindError::NotFound => write!(f,
+                "CH57x keyboard device not found. Wireless keyboards must be connected with USB cable to be programmed. \
+                 Use --vendor-id and --product-id to override settings."
+            ),
+            FindError::Several(addresses) => write!(f, indoc! {"
+                Several compatible devices are found. Choose one by USB address with --address option,
+                or in config: by `address` or `serial` (if keyboards have serial numbers) in `devices`
+                list, and with --device option if config describes several devices.
+
+                Addresses:
+                {}
+            "}, addresses.iter().map(|(bus, addr)| format!("{bus}:{addr}")).join("\n")),
+        }
+    }
+}
+
+impl std::error::Error for FindError {}
+
+/// Number of times claiming busy interface is retried.
+const CLAIM_RETRIES: u32 = 4;
+
+/// Delay before first retry of claiming interface, doubled for each next one.
+const CLAIM_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Criteria used to find keyboard among connected USB devices.
+#[derive(Debug, Clone)]
+pub struct DeviceFilter {
+    pub vendor_id: u16,
+    /// Product ID, any of supported ones if not given.
+    pub product_id: Option<u16>,
+    /// USB bus number and address, used to choose one of several devices.
+    pub address: Option<(u8, u8)>,
+}
+
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        Self { vendor_id: VENDOR_ID, product_id: None, address: None }
+    }
+}
+
+/// Overrides for programming interface and endpoint, normally
+/// they are determined automatically.
+#[derive(Debug, Clone, Default)]
+pub struct EndpointOptions {
+    pub interface_number: Option<u8>,
+    pub endpoint_address: Option<u8>,
+    /// Report ID prefixed to messages: `None` to detect it from HID report
+    /// descriptor, `Some(None)` to send messages without report ID.
+    pub report_id: Option<Option<u8>>,
+    pub transfer: TransferOptions,
+    /// Program device even if its endpoints look like ones of another model.
+    pub force_protocol: bool,
+}
+
+/// Compatible keyboard found on USB bus, not opened yet.
+pub struct Device {
+    source: Source,
+    key: DeviceKey,
+    /// `None` if device is not known to quirks database.
+    capabilities: Option<Capabilities>,
+}
+
+enum Source {
+    Usb { device: rusb::Device<Context>, descriptor: DeviceDescriptor },
+    /// In-memory device decoding messages like keyboard.
+    Mock(MockDevice),
+}
+
+impl Device {
+    /// Lists connected devices with vendor and product IDs matching filter.
+    pub fn enumerate(filter: &DeviceFilter) -> Result<Vec<Device>> {
+        let options = vec![
+            #[cfg(windows)] rusb::UsbOption::use_usbdk(),
+        ];
+        let usb_context = rusb::Context::with_options(&options)?;
+        let quirks = Quirks::load().context("load device quirks")?;
+
+        let mut found = vec![];
+        for device in usb_context.devices().context("get USB device list")?.iter() {
+            let desc = device.device_descriptor().context("get USB device info")?;
+            debug!(
+                "Bus {:03} Device {:03} ID {:04x}:{:04x}",
+                device.bus_number(),
+                device.address(),
+                desc.vendor_id(),
+                desc.product_id()
+            );
+            if desc.vendor_id() != filter.vendor_id {
+                continue;
+            }
+            let key = DeviceKey {
+                vendor_id: desc.vendor_id(),
+                product_id: desc.product_id(),
+                bcd_device: version_to_bcd(desc.device_version()),
+                interfaces: device.config_descriptor(0).map_or(0, |c| c.num_interfaces()),
+            };
+            let capabilities = quirks.capabilities(&key);
+            debug!("Device {key:?} capabilities: {capabilities:?}");
+            let matches = match filter.product_id {
+                Some(product_id) => product_id == key.product_id,
+                None => capabilities.is_some(),
+            };
+            if matches {
+                found.push(Device { source: Source::Usb { device, descriptor: desc }, key, capabilities });
+            }
+        }
+        Ok(found)
+    }
+
+    /// In-memory device emulating keyboard of its model, product ID is
+    /// known one of this model unless it is given.
+    pub fn mock(mock: MockDevice, product_id: Option<u16>) -> Result<Device> {
+        let model = mock.model();
+        let quirks = Quirks::load().context("load device quirks")?;
+        let product_id = product_id.or_else(|| quirks.product_id(model))
+            .ok_or_else(|| anyhow!("no known product ID of {model} keyboard"))?;
+        let key = DeviceKey { vendor_id: VENDOR_ID, product_id, bcd_device: 0, interfaces: 0 };
+        let capabilities = quirks.capabilities(&key);
+        Ok(Device { source: Source::Mock(mock), key, capabilities })
+    }
+
+    /// Whether device is in-memory one, its state and backups aren't kept.
+    pub fn is_mock(&self) -> bool {
+        matches!(self.source, Source::Mock(_))
+    }
+
+    /// USB device with its descriptor, `None` for mock device.
+    fn usb(&self) -> Option<(&rusb::Device<Context>, &DeviceDescriptor)> {
+        match &self.source {
+            Source::Usb { device, descriptor } => Some((device, descriptor)),
+            Source::Mock(_) => None,
+        }
+    }
+
+    /// Finds single device matching filter.
+    pub fn find(filter: &DeviceFilter) -> Result<Device> {
+        let found = Self::enumerate(filter)?;
+        Self::choose(found, filter.address)
+    }
+
+    /// Chooses single device from found ones, by address if it is given.
+    pub fn choose(mut found: Vec<Device>, address: Option<(u8, u8)>) -> Result<Device> {
+        if let Some(address) = address {
+            found.retain(|device| device.address() == address);
+        }
+        match found.len() {
+            0 => Err(FindError::NotFound.into()),
+            1 => Ok(found.pop().unwrap()),
+            _ => {
+                let mut addresses = vec![];
+                for device in found {
+                    /*let handle = device.open().context("open device")?;
+                    let langs = handle.read_languages(DEFAULT_TIMEOUT).context("get langs")?;
+                    dbg!(&langs);
+                    let lang =
+                        // First try to find US English language
+                        langs.iter().find(|l| {
+                            l.primary_language() == PrimaryLanguage::English &&
+                            l.sub_language() == SubLanguage::UnitedStates
+                        })
+                        // Then any English sublanguage
+                        .or_else(|| langs.iter().find(|l| l.primary_language() == PrimaryLanguage::English))
+                        // Then just first available language
+                        .or_else(|| langs.first())
+                        // Ok, give up
+                        .ok_or_else(|| anyhow!("No languages found"))?;
+                    dbg!(lang);
+                    let serial = handle.read_serial_number_string(*lang, &desc, DEFAULT_TIMEOUT)
+                        .context("read serial")?;*/
+                    addresses.push(device.address());
+                }
+
+                Err(FindError::Several(addresses).into())
+            }
+        }
+    }
+
+    /// USB bus number and device address, zeros for mock device.
+    pub fn address(&self) -> (u8, u8) {
+        match self.usb() {
+            Some((device, _)) => (device.bus_number(), device.address()),
+            None => (0, 0),
+        }
+    }
+
+    /// Bus number and port path, like `1-2.3`. Unlike address,
+    /// it doesn't change when device is plugged into the same port again.
+    /// Mock device is located at `mock`.
+    pub fn location(&self) -> String {
+        let Some((device, _)) = self.usb() else {
+            return "mock".to_owned();
+        };
+        let ports = device.port_numbers().unwrap_or_default();
+        format!("{}-{}", device.bus_number(), ports.iter().join("."))
+    }
+
+    /// Serial number, `None` if device doesn't have it, like most keyboards.
+    pub fn serial_number(&self) -> Result<Option<String>> {
+        let Some((device, descriptor)) = self.usb() else {
+            return Ok(None);
+        };
+        if descriptor.serial_number_string_index().is_none() {
+            return Ok(None);
+        }
+        let handle = device.open().context("open USB device")?;
+        let serial = handle.read_serial_number_string_ascii(descriptor).context("read serial number")?;
+        Ok(Some(serial))
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.key.vendor_id
+    }
+
+    pub fn product_id(&self) -> u16 {
+        self.key.product_id
+    }
+
+    /// Properties device is looked up in quirks database by.
+    pub fn key(&self) -> &DeviceKey {
+        &self.key
+    }
+
+    /// Keyboard model, `None` if device is not supported.
+    pub fn model(&self) -> Option<Model> {
+        self.capabilities.as_ref().map(|c| c.model)
+    }
+
+    /// Device properties from quirks database, `None` if device is not supported.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// Opens device and claims all HID interfaces to read input reports.
+    /// Interfaces are detached from system driver, so key presses don't reach
+    /// applications until device is closed. Mock device never sends reports.
+    pub fn open_input(&self) -> Result<InputReports> {
+        let Some((device, _)) = self.usb() else {
+            return Ok(InputReports { handle: None, endpoints: vec![] });
+        };
+        let conf_desc = device.config_descriptor(0).context("get config #0 descriptor")?;
+        let handle = device.open().context("open USB device")?;
+        let _ = handle.set_auto_detach_kernel_driver(true);
+
+        let mut endpoints = vec![];
+        for intf in conf_desc.interfaces() {
+            for intf_desc in intf.descriptors().filter(|d| d.class_code() == 0x03) {
+                let inputs = intf_desc.endpoint_descriptors()
+                    .filter(|ep| ep.transfer_type() == TransferType::Interrupt && ep.direction() == rusb::Direction::In)
+                    .map(|ep| (ep.address(), intf_desc.protocol_code()))
+                    .collect_vec();
+                if inputs.is_empty() {
+                    continue;
+                }
+                claim_interface(device, &handle, intf.number())?;
+                endpoints.extend(inputs);
+            }
+        }
+        ensure!(!endpoints.is_empty(), "no HID input endpoints found");
+
+        Ok(InputReports { handle: Some(handle), endpoints })
+    }
+
+    /// Opens keyboard for programming. Geometry given in config, if known,
+    /// is used to choose key ID layout for keyboard variant.
+    pub fn open(&self, endpoint_options: &EndpointOptions, geometry: Option<Geometry>) -> Result<Box<dyn Keyboard>> {
+        self.open_session(endpoint_options)?.keyboard(geometry)
+    }
+
+    /// Uploads config with its LED modes, returns number of bound keys. Unlike `upload`
+    /// command, it doesn't keep backups and state of device.
+    pub fn upload_config(&self, endpoint_options: &EndpointOptions, config: Config) -> Result<usize> {
+        let geometry = config.geometry();
+        let led = config.led;
+        let layers = config.render().context(ConfigError("render mapping config"))?;
+        if let Some(caps) = self.capabilities() {
+            ensure!(caps.led || led.is_none(), "this keyboard doesn't support setting LED mode");
+        }
+
+        let count = self.open(endpoint_options, Some(geometry))?.upload(&layers)?;
+        // LED modes are set in separate session, like command-line tool does.
+        if let Some(mode) = led {
+            self.open(endpoint_options, Some(geometry))?.set_led(0, mode).context("set LED mode")?;
+        }
+        Ok(count)
+    }
+
+    /// Opens device and claims programming interface, so that several
+    /// keyboards may be opened on it one after another.
+    pub fn open_session(&self, endpoint_options: &EndpointOptions) -> Result<Session> {
+        let model = self.model()
+            .ok_or_else(|| anyhow!("unsupported product ID {:04x}", self.product_id()))?;
+
+        let (device, descriptor) = match &self.source {
+            Source::Usb { device, descriptor } => (device, descriptor),
+            Source::Mock(mock) => {
+                let (_, endpoint, _) = self.programming_endpoint(endpoint_options)?;
+                return Ok(Session {
+                    model,
+                    transport: Rc::new(mock.clone()),
+                    endpoint,
+                    report_id: endpoint_options.report_id.unwrap_or(Some(hid::DEFAULT_REPORT_ID)),
+                    transfer: endpoint_options.transfer.clone(),
+                });
+            }
+        };
+        ensure!(
+            descriptor.num_configurations() == 1,
+            "only one device configuration is expected"
+        );
+
+        // Messages of wrong model would bind garbage, so check model from quirks against
+        // endpoints, unless endpoint is given explicitly for clone with unusual layout.
+        let endpoint_overridden = endpoint_options.endpoint_address.is_some()
+            || self.capabilities.as_ref().is_some_and(|c| c.endpoint.is_some());
+        if !endpoint_options.force_protocol && !endpoint_overridden {
+            let conf_desc = device.config_descriptor(0).context("get config #0 descriptor")?;
+            let out_endpoints = conf_desc.interfaces()
+                .flat_map(|intf| intf.descriptors().collect_vec())
+                .flat_map(|desc| desc.endpoint_descriptors().collect_vec())
+                .filter(|ep| ep.transfer_type() == TransferType::Interrupt && ep.direction() == rusb::Direction::Out)
+                .map(|ep| ep.address())
+                .collect_vec();
+            if let Some(detected) = model_by_endpoints(&out_endpoints) {
+                ensure!(
+                    detected == model,
+                    "device endpoints look like {detected} keyboard, but it is configured as {model} in quirks, \
+                     use --force-protocol to program it anyway"
+                );
+            }
+        }
+
+        let (intf_num, endpt_addr, packet_size) = self.programming_endpoint(endpoint_options)?;
+
+        // Open device.
+        let handle = device.open().context("open USB device")?;
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        claim_interface(device, &handle, intf_num)?;
+
+        let report_id = match endpoint_options.report_id {
+            Some(report_id) => report_id,
+            None => detect_report_id(&handle, intf_num),
+        };
+        debug!("Using report ID {report_id:?}");
+
+        debug!("Using packet size {packet_size}");
+        let transfer = TransferOptions { packet_size, ..endpoint_options.transfer.clone() };
+
+        Ok(Session { model, transport: Rc::new(handle), endpoint: endpt_addr, report_id, transfer })
+    }
+
+    /// Interface number, endpoint address and packet size used to program device.
+    pub fn programming_endpoint(&self, endpoint_options: &EndpointOptions) -> Result<(u8, u8, usize)> {
+        let model = self.model()
+            .ok_or_else(|| anyhow!("unsupported product ID {:04x}", self.product_id()))?;
+        let preferred_endpint = self.capabilities.as_ref().and_then(|c| c.endpoint).unwrap_or(model.preferred_endpoint());
+        let endpoint = endpoint_options.endpoint_address.unwrap_or(preferred_endpint);
+
+        let Some((device, _)) = self.usb() else {
+            return Ok((endpoint_options.interface_number.unwrap_or(0), endpoint, DEFAULT_PACKET_SIZE));
+        };
+
+        // Find correct endpoint
+        find_interface_and_endpoint(
+            device,
+            endpoint_options.interface_number,
+            endpoint,
+        )
+    }
+
+    /// Device descriptors in form similar to `lsusb -v` output, for bug reports.
+    /// String descriptors aren't read, so that serial number isn't disclosed.
+    pub fn describe_descriptors(&self) -> Result<String> {
+        use std::fmt::Write as _;
+
+        let Some((device, desc)) = self.usb() else {
+            return Ok("Device: in-memory mock\n".to_owned());
+        };
+        let mut text = String::new();
+        writeln!(text, "Device: USB {}, class {:#04x}, vendor {:04x}, product {:04x}, release {:#06x}, {} configuration(s)",
+                 desc.usb_version(), desc.class_code(), desc.vendor_id(), desc.product_id(),
+                 version_to_bcd(desc.device_version()), desc.num_configurations())?;
+        for index in 0..desc.num_configurations() {
+            let config = device.config_descriptor(index)
+                .with_context(|| format!("get config #{index} descriptor"))?;
+            writeln!(text, "  Configuration {}: {} interface(s), max power {}mA",
+                     config.number(), config.num_interfaces(), config.max_power())?;
+            for intf in config.interfaces() {
+                let driver = interface_driver(device, intf.number())
+                    .map_or(String::new(), |driver| format!(", driver {driver}"));
+                for intf_desc in intf.descriptors() {
+                    writeln!(text, "    Interface {} alt {}: class {:#04x}, subclass {:#04x}, protocol {:#04x}{driver}",
+                             intf_desc.interface_number(), intf_desc.setting_number(), intf_desc.class_code(),
+                             intf_desc.sub_class_code(), intf_desc.protocol_code())?;
+                    for ep in intf_desc.endpoint_descriptors() {
+                        writeln!(text, "      Endpoint {:#04x}: {:?} {:?}, max packet {}, interval {}",
+                                 ep.address(), ep.direction(), ep.transfer_type(), ep.max_packet_size(), ep.interval())?;
+                    }
+                }
+            }
+        }
+        Ok(text)
+    }
+}
+
+/// Device opened for programming, interface is released when session
+/// and all keyboards opened on it are dropped.
+pub struct Session {
+    model: Model,
+    transport: Rc<dyn Transport>,
+    endpoint: u8,
+    report_id: Option<u8>,
+    transfer: TransferOptions,
+}
+
+impl Session {
+    /// Opens keyboard on session. Geometry given in config, if known,
+    /// is used to choose key ID layout for keyboard variant.
+    pub fn keyboard(&self, geometry: Option<Geometry>) -> Result<Box<dyn Keyboard>> {
+        let key_ids = self.model.key_id_layout(geometry);
+        debug!("Using key ID layout {key_ids:?}");
+        self.model.open(Box::new(self.transport.clone()), self.endpoint, self.report_id, self.transfer.clone(), key_ids)
+    }
+}
+
+/// Guesses model by interrupt OUT endpoints: each model is programmed through its own
+/// endpoint, `None` if both or neither of them are present.
+fn model_by_endpoints(out_endpoints: &[u8]) -> Option<Model> {
+    let k884x = out_endpoints.contains(&Model::K884x.preferred_endpoint());
+    let k8890 = out_endpoints.contains(&Model::K8890.preferred_endpoint());
+    match (k884x, k8890) {
+        (true, false) => Some(Model::K884x),
+        (false, true) => Some(Model::K8890),
+        _ => None,
+    }
+}
+
+/// Claims interface, retrying for a while if it is held by other driver or process.
+fn claim_interface(device: &rusb::Device<Context>, handle: &rusb::DeviceHandle<Context>, interface_num: u8) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        let err = match handle.claim_interface(interface_num) {
+            Ok(()) => return Ok(()),
+            Err(err @ (rusb::Error::Busy | rusb::Error::Pipe)) if attempt < CLAIM_RETRIES => err,
+            Err(err) => {
+                let holder = match interface_driver(device, interface_num) {
+                    Some(driver) => format!(", it is held by '{driver}' driver"),
+                    None => String::new(),
+                };
+                return Err(err).with_context(|| format!("claim interface {interface_num}{holder}"));
+            }
+        };
+
+        // Automatic detach may fail or not be supported, try it explicitly.
+        if handle.kernel_driver_active(interface_num).unwrap_or(false) {
+            if let Err(err) = handle.detach_kernel_driver(interface_num) {
+                debug!("Can't detach kernel driver from interface {interface_num}: {err}");
+            }
+        }
+        let delay = CLAIM_RETRY_DELAY * 2u32.pow(attempt);
+        attempt += 1;
+        warn!("claim interface {interface_num} failed: {err}, retrying in {delay:?} (attempt {attempt} of {CLAIM_RETRIES})");
+        std::thread::sleep(delay);
+    }
+}
+
+/// Name of driver bound to interface, if it may be found out.
+#[cfg(target_os = "linux")]
+fn interface_driver(device: &rusb::Device<Context>, interface_num: u8) -> Option<String> {
+    let ports = device.port_numbers().ok()?;
+    let config = device.active_config_descriptor().ok()?.number();
+    let path = format!("/sys/bus/usb/devices/{}-{}:{config}.{interface_num}/driver", device.bus_number(), ports.iter().join("."));
+    let driver = std::fs::read_link(path).ok()?;
+    Some(driver.file_name()?.to_string_lossy().into_owned())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_driver(_device: &rusb::Device<Context>, _interface_num: u8) -> Option<String> {
+    None
+}
+
+/// Converts device release back to BCD form it is given in descriptor,
+/// rusb decodes major version as decimal number.
+fn version_to_bcd(version: rusb::Version) -> u16 {
+    let major = version.major() as u16;
+    (major / 10) << 12 | (major % 10) << 8 | (version.minor() as u16) << 4 | version.sub_minor() as u16
+}
+
+/// Chooses report ID using interface's HID report descriptor.
+/// Some firmware revisions declare several output reports, so ID
+/// can't be hardcoded. Falls back to default one if descriptor can't be read.
+fn detect_report_id(handle: &rusb::DeviceHandle<Context>, interface_num: u8) -> Option<u8> {
+    let mut buf = [0; 1024];
+    let request_type = rusb::request_type(
+        rusb::Direction::In, rusb::RequestType::Standard, rusb::Recipient::Interface);
+    // GET_DESCRIPTOR for HID report descriptor (type 0x22).
+    match handle.read_control(request_type, 0x06, 0x2200, interface_num as u16, &mut buf, Duration::from_millis(100)) {
+        Ok(len) => {
+            debug!("Report descriptor: {:02x?}", &buf[..len]);
+            hid::choose_report_id(&buf[..len])
+        }
+        Err(err) => {
+            debug!("Can't read report descriptor, using default report ID: {err}");
+            Some(hid::DEFAULT_REPORT_ID)
+        }
+    }
+}
+
+/// Input endpoints of opened device, used to watch reports it sends to host.
+pub struct InputReports {
+    /// `None` for mock device.
+    handle: Option<rusb::DeviceHandle<Context>>,
+    /// Endpoint addresses with HID protocol codes of their interfaces.
+    endpoints: Vec<(u8, u8)>,
+}
+
+impl InputReports {
+    /// Waits for report on any of endpoints, returns it with interface protocol code.
+    pub fn read(&mut self) -> Result<(u8, Vec<u8>)> {
+        loop {
+            if let Some(report) = self.read_timeout(Duration::from_secs(1))? {
+                return Ok(report);
+            }
+        }
+    }
+
+    /// Same as `read`, but returns `None` if there is no report during `timeout`.
+    pub fn read_timeout(&mut self, timeout: Duration) -> Result<Option<(u8, Vec<u8>)>> {
+        let Some(handle) = &self.handle else {
+            std::thread::sleep(timeout);
+            return Ok(None);
+        };
+        let start = Instant::now();
+        let mut buf = [0; 64];
+        while start.elapsed() < timeout {
+            for &(endpoint, protocol) in &self.endpoints {
+                match handle.read_interrupt(endpoint, &mut buf, Duration::from_millis(20)) {
+                    Ok(len) => {
+                        traffic::record(Direction::In, endpoint, &buf[..len]);
+                        return Ok(Some((protocol, buf[..len].to_vec())));
+                    }
+                    Err(rusb::Error::Timeout) => {}
+                    Err(err) => return Err(err).context("read input report"),
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn find_interface_and_endpoint(
+    device: &rusb::Device<Context>,
+    interface_num: Option<u8>,
+    endpoint_addr: u8,
+) -> Result<(u8, u8, usize)> {
+    let conf_desc = device
+        .config_descriptor(0)
+        .context("get config #0 descriptor")?;
+
+    // Get the numbers of interfaces to explore
+    let interface_nums = match interface_num {
+        Some(iface_num) => vec![iface_num],
+        None => conf_desc.interfaces().map(|iface| iface.number()).collect(),
+    };
+
+    for iface_num in interface_nums {
+        debug!("Probing interface {iface_num}");
+
+        // Look for an interface with the given number
+        let intf = conf_desc
+            .interfaces()
+            .find(|iface| iface_num == iface.number())
+            .ok_or_else(|| {
+                anyhow!(
+                    "interface #{} not found, interface numbers:\n{:#?}",
+                    iface_num,
+                    conf_desc.interfaces().map(|i| i.number()).format(", ")
+                )
+            })?;
+
+        // Check that it's a HID device
+        let intf_desc = intf.descriptors().exactly_one().map_err(|_| {
+            anyhow!(
+                "only one interface descriptor is expected, got:\n{:#?}",
+                intf.descriptors().format("\n")
+            )
+        })?;
+
+        // Look for suitable endpoints
+        if let Some(endpt_desc) = intf_desc.endpoint_descriptors().find(|ep| {
+            ep.transfer_type() == TransferType::Interrupt && ep.address() == endpoint_addr
+        }) {
+            debug!("Found endpoint {endpt_desc:?}");
+            if intf_desc.class_code() == 0x03
+                && intf_desc.sub_class_code() == 0x00
+                && intf_desc.protocol_code() == 0x00
+            {
+                // Zero size would mean broken descriptor, don't trust it.
+                let packet_size = match endpt_desc.max_packet_size() as usize & 0x7ff {
+                    0 => DEFAULT_PACKET_SIZE,
+                    size => size,
+                };
+                return Ok((iface_num, endpt_desc.address(), packet_size));
+            } else {
+                debug!("unexpected interface parameters: {:#?}", intf_desc);
+            }
+        }
+    }
+
+    Err(anyhow!("No valid interface/endpoint combination found!"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_by_endpoints() {
+        assert_eq!(model_by_endpoints(&[0x04]), Some(Model::K884x));
+        assert_eq!(model_by_endpoints(&[0x01, 0x02]), Some(Model::K8890));
+        assert_eq!(model_by_endpoints(&[0x02, 0x04]), None);
+        assert_eq!(model_by_endpoints(&[]), None);
+    }
+}
@@ -0,0 +1,230 @@
+//! Editing of layers in config file text, keeping comments and formatting.
+//!
+//! Layers are found as items of block sequence under top-level `layers` key.
+//! Comments and blank lines before item stay in place, since they usually
+//! describe position (like `# Layer 2`), everything else is moved with item.
+//! Result is parsed and compared with expected layers, so that unusual
+//! formatting is reported instead of corrupting config.
+
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+use serde_yaml::Value;
+
+/// Config text split around layer items.
+struct Layers {
+    /// Text up to the first layer item.
+    head: String,
+    /// Comments and blank lines before each item, item itself.
+    items: Vec<(String, String)>,
+    /// Text after the last item.
+    tail: String,
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+fn is_blank_or_comment(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with('#')
+}
+
+impl Layers {
+    fn split(text: &str) -> Result<Self> {
+        let lines: Vec<&str> = text.split_inclusive('\n').collect();
+        let start = lines.iter()
+            .position(|line| line.starts_with("layers:"))
+            .ok_or_else(|| anyhow!("config has no layers"))?;
+        let rest = lines[start]["layers:".len()..].trim();
+        ensure!(rest.is_empty() || rest.starts_with('#'), "layers must be written as list of items starting with '-' to be edited");
+
+        let first_item = (start + 1..lines.len())
+            .find(|&i| !is_blank_or_comment(lines[i]))
+            .filter(|&i| lines[i].trim_start().starts_with('-'))
+            .ok_or_else(|| anyhow!("layers must be written as list of items starting with '-' to be edited"))?;
+        let item_indent = indent(lines[first_item]);
+        let is_item_start = |line: &str| indent(line) == item_indent && line.trim_start().starts_with('-');
+
+        let end = (first_item..lines.len())
+            .find(|&i| {
+                let line = lines[i];
+                !is_blank_or_comment(line) && (indent(line) < item_indent || (indent(line) == item_indent && !is_item_start(line)))
+            })
+            .unwrap_or(lines.len());
+
+        // Comments and blank lines at the end of item are moved to prefix of the next one.
+        let mut items = vec![];
+        let mut prefix_start = start + 1;
+        let mut i = first_item;
+        while i < end {
+            let mut next = i + 1;
+            while next < end && !is_item_start(lines[next]) {
+                next += 1;
+            }
+            let mut body_end = next;
+            while body_end > i + 1 && is_blank_or_comment(lines[body_end - 1]) {
+                body_end -= 1;
+            }
+            items.push((lines[prefix_start..i].concat(), lines[i..body_end].concat()));
+            prefix_start = body_end;
+            i = next;
+        }
+
+        Ok(Layers {
+            head: lines[..start + 1].concat(),
+            items,
+            tail: lines[prefix_start..].concat(),
+        })
+    }
+
+    fn join(&self) -> String {
+        let mut text = self.head.clone();
+        for (prefix, item) in &self.items {
+            text += prefix;
+            text += item;
+        }
+        text += &self.tail;
+        text
+    }
+
+    fn check_index(&self, layer: usize) -> Result<()> {
+        ensure!(layer < self.items.len(), "config has {} layers, there is no layer {}", self.items.len(), layer + 1);
+        Ok(())
+    }
+}
+
+fn layers_value(text: &str) -> Result<Vec<Value>> {
+    let value: Value = serde_yaml::from_str(text).context("parse config")?;
+    match value.get("layers") {
+        Some(Value::Sequence(layers)) => Ok(layers.clone()),
+        _ => bail!("config has no layers"),
+    }
+}
+
+/// Checks that edited text has expected layers.
+fn verify(text: String, expected: Vec<Value>) -> Result<String> {
+    ensure!(layers_value(&text).ok() == Some(expected),
+            "can't edit layers of this config keeping its formatting, edit it by hand");
+    Ok(text)
+}
+
+/// Copies zero-based layer `from` over layer `to`, layer right after the last one may be added this way.
+pub fn copy_layer(text: &str, from: usize, to: usize) -> Result<String> {
+    let mut layers = Layers::split(text)?;
+    let mut expected = layers_value(text)?;
+    ensure!(expected.len() == layers.items.len(), "can't find layers in config text, edit it by hand");
+    layers.check_index(from)?;
+    ensure!(to <= layers.items.len(), "config has {} layers, layer {} can't be added", layers.items.len(), to + 1);
+
+    let item = layers.items[from].1.clone();
+    if to == layers.items.len() {
+        // Separate new layer with blank line if other layers are separated so.
+        let separated = layers.items.iter().skip(1).any(|(prefix, _)| prefix.starts_with('\n'));
+        let prefix = if separated { "\n".to_owned() } else { String::new() };
+        let item = if item.ends_with('\n') { item } else { item + "\n" };
+        if let Some((_, last)) = layers.items.last_mut() {
+            if !last.ends_with('\n') {
+                last.push('\n');
+            }
+        }
+        layers.items.push((prefix, item));
+        expected.push(expected[from].clone());
+    } else {
+        layers.items[to].1 = item;
+        expected[to] = expected[from].clone();
+    }
+    verify(layers.join(), expected)
+}
+
+/// Swaps two zero-based layers.
+pub fn swap_layers(text: &str, a: usize, b: usize) -> Result<String> {
+    let mut layers = Layers::split(text)?;
+    let mut expected = layers_value(text)?;
+    ensure!(expected.len() == layers.items.len(), "can't find layers in config text, edit it by hand");
+    layers.check_index(a)?;
+    layers.check_index(b)?;
+
+    // The last item may lack trailing newline.
+    let (item_a, item_b) = (layers.items[a].1.clone(), layers.items[b].1.clone());
+    let newline = |item: &String, like: &String| if like.ends_with('\n') && !item.ends_with('\n') { item.clone() + "\n" } else { item.clone() };
+    layers.items[a].1 = newline(&item_b, &item_a);
+    layers.items[b].1 = newline(&item_a, &item_b);
+    expected.swap(a, b);
+    verify(layers.join(), expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+
+    const CONFIG: &str = indoc! {"
+        orientation: normal
+        rows: 1
+        columns: 2
+        knobs: 0
+        layers:
+          # Layer 1
+          - buttons:
+              # Copy and paste.
+              - [ctrl-c, ctrl-v]
+            knobs: []
+
+          # Layer 2
+          - buttons: [[a, b]]
+            knobs: []
+        led: 1
+    "};
+
+    #[test]
+    fn test_swap() -> Result<()> {
+        assert_eq!(swap_layers(CONFIG, 0, 1)?, indoc! {"
+            orientation: normal
+            rows: 1
+            columns: 2
+            knobs: 0
+            layers:
+              # Layer 1
+              - buttons: [[a, b]]
+                knobs: []
+
+              # Layer 2
+              - buttons:
+                  # Copy and paste.
+                  - [ctrl-c, ctrl-v]
+                knobs: []
+            led: 1
+        "});
+        assert!(swap_layers(CONFIG, 0, 2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy() -> Result<()> {
+        let copied = copy_layer(CONFIG, 1, 0)?;
+        assert_eq!(layers_value(&copied)?, [layers_value(CONFIG)?[1].clone(), layers_value(CONFIG)?[1].clone()]);
+        assert!(copied.contains("# Layer 1\n  - buttons: [[a, b]]\n"));
+
+        let added = copy_layer(CONFIG, 0, 2)?;
+        let layers = layers_value(&added)?;
+        assert_eq!(layers.len(), 3);
+        assert_eq!(layers[2], layers[0]);
+        assert!(added.ends_with("    knobs: []\nled: 1\n"));
+
+        assert!(copy_layer(CONFIG, 0, 3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_formatting() {
+        assert!(swap_layers("layers: [{buttons: [[a]], knobs: []}, {buttons: [[b]], knobs: []}]\n", 0, 1).is_err());
+        assert!(swap_layers("rows: 1\n", 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_zero_indent() -> Result<()> {
+        let config = "layers:\n- buttons: [[a]]\n  knobs: []\n- buttons: [[b]]\n  knobs: []\nrows: 1\n";
+        assert_eq!(swap_layers(config, 0, 1)?, "layers:\n- buttons: [[b]]\n  knobs: []\n- buttons: [[a]]\n  knobs: []\nrows: 1\n");
+        Ok(())
+    }
+}
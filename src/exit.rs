@@ -0,0 +1,80 @@
+//! Exit codes, they are stable so that scripts may tell what failed.
+
+use std::fmt::Display;
+
+use crate::device::FindError;
+use crate::keyboard::WriteFailed;
+
+/// Context of errors caused by invalid config, like `ConfigError("load mapping config")`.
+#[derive(Debug)]
+pub struct ConfigError(pub &'static str);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Any other error.
+    Failure = 1,
+    /// Config can't be loaded or is invalid.
+    Config = 2,
+    DeviceNotFound = 3,
+    /// No permission to access device.
+    Permission = 4,
+    /// Communication with device failed.
+    Transfer = 5,
+    /// Several devices match, and it isn't known which one to use.
+    SeveralDevices = 6,
+    /// Invalid command line, like `EX_USAGE` of sysexits.
+    Usage = 64,
+}
+
+impl ExitCode {
+    pub fn of(err: &anyhow::Error) -> Self {
+        let usb_error = err.chain().find_map(|e| e.downcast_ref::<rusb::Error>());
+        if usb_error == Some(&rusb::Error::Access) {
+            return ExitCode::Permission;
+        }
+        match err.chain().find_map(|e| e.downcast_ref::<FindError>()) {
+            Some(FindError::NotFound) => return ExitCode::DeviceNotFound,
+            Some(FindError::Several(_)) => return ExitCode::SeveralDevices,
+            None => {}
+        }
+        if err.downcast_ref::<ConfigError>().is_some() {
+            return ExitCode::Config;
+        }
+        let transfer_error = matches!(usb_error, Some(rusb::Error::Timeout | rusb::Error::Io | rusb::Error::Pipe | rusb::Error::NoDevice
+                                                      | rusb::Error::Busy | rusb::Error::Interrupted | rusb::Error::Overflow));
+        if transfer_error || err.downcast_ref::<WriteFailed>().is_some() {
+            return ExitCode::Transfer;
+        }
+        ExitCode::Failure
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::{anyhow, Context as _};
+
+    #[test]
+    fn test_exit_code() {
+        let err = Err::<(), _>(rusb::Error::Access).context("open USB device").context("upload").unwrap_err();
+        assert_eq!(ExitCode::of(&err), ExitCode::Permission);
+        let err = Err::<(), _>(rusb::Error::Timeout).context(WriteFailed { attempts: 4 }).context("bind key").unwrap_err();
+        assert_eq!(ExitCode::of(&err), ExitCode::Transfer);
+        let err = anyhow!("only 10 of 65 bytes written").context(WriteFailed { attempts: 4 });
+        assert_eq!(ExitCode::of(&err), ExitCode::Transfer);
+        let err = anyhow::Error::from(FindError::Several(vec![(1, 2), (1, 3)])).context("find USB device");
+        assert_eq!(ExitCode::of(&err), ExitCode::SeveralDevices);
+        let err = anyhow!("unknown key name 'nokey'").context(ConfigError("load mapping config")).context("device 1");
+        assert_eq!(ExitCode::of(&err), ExitCode::Config);
+        assert_eq!(ExitCode::of(&anyhow!("can't back up config")), ExitCode::Failure);
+        let err = Err::<(), _>(rusb::Error::Other).context("find USB device").unwrap_err();
+        assert_eq!(ExitCode::of(&err), ExitCode::Failure);
+    }
+}
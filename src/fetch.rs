@@ -0,0 +1,136 @@
+//! Download of configs hosted on web server, so that team may share
+//! the same mapping. Content may be pinned with SHA-256 hash.
+
+use std::io::Read as _;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+use sha2::{Digest as _, Sha256};
+
+use ch57x_keyboard::config::ConfigFormat;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Configs are small, larger response is most probably wrong URL.
+const MAX_SIZE: u64 = 1024 * 1024;
+
+/// Whether config path is actually URL.
+pub fn is_url(path: &str) -> bool {
+    ["http://", "https://"].iter().any(|scheme| path.get(..scheme.len()).is_some_and(|s| s.eq_ignore_ascii_case(scheme)))
+}
+
+/// Config format detected by extension of URL path.
+pub fn format_of(url: &str) -> ConfigFormat {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    ConfigFormat::from_path(Path::new(path))
+}
+
+/// Downloads config, checking its hash if given.
+pub fn fetch(url: &str, sha256: Option<&str>) -> Result<Vec<u8>> {
+    if sha256.is_none() && !url[..5].eq_ignore_ascii_case("https") {
+        eprintln!("warning: config is downloaded over plain HTTP, pin its content with --sha256");
+    }
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(TIMEOUT)
+        .timeout(TIMEOUT)
+        .build();
+    let response = agent.get(url)
+        .set("User-Agent", concat!("ch57x-keyboard-tool/", env!("CARGO_PKG_VERSION")))
+        .call()
+        .map_err(describe_error)
+        .with_context(|| format!("download config from {url}"))?;
+
+    let mut data = vec![];
+    response.into_reader().take(MAX_SIZE + 1).read_to_end(&mut data)
+        .map_err(|err| anyhow!("{}", describe_io_error(&err)))
+        .with_context(|| format!("download config from {url}"))?;
+    ensure!(data.len() as u64 <= MAX_SIZE, "config at {url} is larger than {} KiB", MAX_SIZE / 1024);
+
+    if let Some(expected) = sha256 {
+        check_sha256(&data, expected)?;
+    }
+    Ok(data)
+}
+
+fn check_sha256(data: &[u8], expected: &str) -> Result<()> {
+    let expected = expected.trim();
+    ensure!(expected.len() == 64 && expected.chars().all(|c| c.is_ascii_hexdigit()),
+            "SHA-256 must be 64 hex digits");
+    let actual = format!("{:x}", Sha256::digest(data));
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!("downloaded config has SHA-256 {actual}, but {} is expected; config was changed, check it and update hash",
+              expected.to_lowercase());
+    }
+    Ok(())
+}
+
+fn describe_io_error(err: &std::io::Error) -> String {
+    match err.kind() {
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock =>
+            format!("no response in {} seconds", TIMEOUT.as_secs()),
+        _ => err.to_string(),
+    }
+}
+
+/// Converts ureq error into message telling what went wrong in plain words.
+fn describe_error(err: ureq::Error) -> anyhow::Error {
+    use ureq::ErrorKind;
+
+    let transport = match err {
+        ureq::Error::Status(code, response) => {
+            return anyhow!("server responded with {code} {}", response.status_text());
+        }
+        ureq::Error::Transport(transport) => transport,
+    };
+
+    let io_error = std::error::Error::source(&transport)
+        .and_then(|source| source.downcast_ref::<std::io::Error>());
+    // TLS errors are reported as IO errors wrapping TLS library error.
+    let tls_error = io_error
+        .and_then(|err| err.get_ref())
+        .map(|inner| inner.to_string())
+        .filter(|message| ["certificate", "handshake", "tls", "alert"].iter().any(|word| message.to_lowercase().contains(word)));
+
+    match (transport.kind(), io_error, tls_error) {
+        (_, _, Some(message)) => anyhow!("TLS connection failed: {message}"),
+        (ErrorKind::Dns, _, _) => anyhow!("host not found: {transport}"),
+        (ErrorKind::InvalidUrl | ErrorKind::UnknownScheme, _, _) => anyhow!("invalid URL: {transport}"),
+        (_, Some(io_error), _) if matches!(io_error.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock) => {
+            anyhow!("{}", describe_io_error(io_error))
+        }
+        _ => anyhow::Error::new(transport),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_url() {
+        assert!(is_url("https://example.com/team.yaml"));
+        assert!(is_url("HTTP://example.com/team.yaml"));
+        assert!(!is_url("team.yaml"));
+        assert!(!is_url("http"));
+        assert!(!is_url("./https://team.yaml"));
+    }
+
+    #[test]
+    fn test_format_of() {
+        assert_eq!(format_of("https://example.com/team.json"), ConfigFormat::Json);
+        assert_eq!(format_of("https://example.com/team.json?raw=1"), ConfigFormat::Json);
+        assert_eq!(format_of("https://example.com/team.yaml#top"), ConfigFormat::Yaml);
+        assert_eq!(format_of("https://example.com/config"), ConfigFormat::Yaml);
+    }
+
+    #[test]
+    fn test_check_sha256() {
+        const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert!(check_sha256(b"abc", ABC).is_ok());
+        assert!(check_sha256(b"abc", &ABC.to_uppercase()).is_ok());
+        assert!(check_sha256(b"abd", ABC).is_err());
+        assert!(check_sha256(b"abc", "ba7816bf").is_err());
+    }
+}
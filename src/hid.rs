@@ -0,0 +1,100 @@
+//! Minimal HID report descriptor parsing.
+
+use log::debug;
+
+/// Report ID used by all known keyboards.
+pub const DEFAULT_REPORT_ID: u8 = 0x03;
+
+/// Collects report IDs of output reports declared in report descriptor,
+/// `None` stands for output report without ID.
+pub fn output_report_ids(descriptor: &[u8]) -> Vec<Option<u8>> {
+    let mut report_id = None;
+    let mut stack = vec![];
+    let mut ids = vec![];
+
+    let mut rest = descriptor;
+    while let Some((&prefix, data)) = rest.split_first() {
+        if prefix == 0xfe {
+            // Long item: data size is in the next byte, it is followed by long item tag.
+            let size = data.first().map_or(0, |&size| size as usize);
+            rest = data.get(2 + size..).unwrap_or(&[]);
+            continue;
+        }
+
+        let size = match prefix & 0x03 { 3 => 4, n => n as usize };
+        let Some(value) = data.get(..size) else {
+            debug!("truncated report descriptor item {prefix:02x}");
+            break;
+        };
+        rest = &data[size..];
+
+        match prefix & 0xfc {
+            // Output (main item)
+            0x90 if !ids.contains(&report_id) => ids.push(report_id),
+            // Report ID (global item)
+            0x84 => report_id = value.first().copied(),
+            // Push (global item)
+            0xa4 => stack.push(report_id),
+            // Pop (global item)
+            0xb4 => report_id = stack.pop().unwrap_or(report_id),
+            _ => {}
+        }
+    }
+
+    ids
+}
+
+/// Chooses report ID to send messages with based on report descriptor.
+///
+/// Default report ID is kept unless descriptor declares output reports
+/// and none of them uses it.
+pub fn choose_report_id(descriptor: &[u8]) -> Option<u8> {
+    let ids = output_report_ids(descriptor);
+    if ids.is_empty() || ids.contains(&Some(DEFAULT_REPORT_ID)) {
+        Some(DEFAULT_REPORT_ID)
+    } else {
+        ids[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose_report_id, output_report_ids};
+
+    // Vendor collection with input and output reports, both 64 bytes.
+    const NUMBERED: &[u8] = &[
+        0x06, 0x00, 0xff,   // Usage Page (Vendor Defined 0xFF00)
+        0x09, 0x01,         // Usage (0x01)
+        0xa1, 0x01,         // Collection (Application)
+        0x85, 0x02,         //   Report ID (2)
+        0x95, 0x3f,         //   Report Count (63)
+        0x75, 0x08,         //   Report Size (8)
+        0x09, 0x01,         //   Usage (0x01)
+        0x81, 0x02,         //   Input (Data,Var,Abs)
+        0x09, 0x01,         //   Usage (0x01)
+        0x91, 0x02,         //   Output (Data,Var,Abs)
+        0xc0,               // End Collection
+    ];
+
+    #[test]
+    fn test_output_report_ids() {
+        assert_eq!(output_report_ids(NUMBERED), vec![Some(2)]);
+        assert_eq!(output_report_ids(&NUMBERED[..7]), vec![]);
+
+        // Without Report ID item.
+        let unnumbered = [&NUMBERED[..7], &NUMBERED[9..]].concat();
+        assert_eq!(output_report_ids(&unnumbered), vec![None]);
+    }
+
+    #[test]
+    fn test_choose_report_id() {
+        assert_eq!(choose_report_id(NUMBERED), Some(2));
+        assert_eq!(choose_report_id(&[]), Some(3));
+
+        let unnumbered = [&NUMBERED[..7], &NUMBERED[9..]].concat();
+        assert_eq!(choose_report_id(&unnumbered), None);
+
+        let default = [&NUMBERED[..8], &[0x03], &NUMBERED[9..]].concat();
+        assert_eq!(choose_report_id(&default), Some(3));
+    }
+}
@@ -0,0 +1,144 @@
+//! Translations of `show-keys` headings and hints shown for common errors.
+//! Key names aren't translated, since they are written in configs as is.
+
+use strum_macros::{Display, EnumString};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all="lowercase")]
+#[strum(ascii_case_insensitive)]
+pub enum Lang {
+    En,
+    De,
+    Fr,
+    Ru,
+    Zh,
+}
+
+impl Lang {
+    /// Language of user's locale from environment, English if it isn't supported.
+    pub fn from_env() -> Self {
+        ["LC_ALL", "LC_MESSAGES", "LANG"].into_iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .find(|value| !value.is_empty())
+            .and_then(|locale| Self::from_locale(&locale))
+            .unwrap_or(Lang::En)
+    }
+
+    /// Language of locale like `de_DE.UTF-8`.
+    fn from_locale(locale: &str) -> Option<Self> {
+        locale.split(['_', '.', '@']).next()?.parse().ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    Modifiers,
+    Keys,
+    CustomKeySyntax,
+    MediaKeys,
+    CustomMediaSyntax,
+    MouseActions,
+    DeviceNotFoundHint,
+    AccessDeniedHint,
+    UnknownKeyHint,
+}
+
+pub fn tr(lang: Lang, message: Message) -> &'static str {
+    use Lang::*;
+    use Message::*;
+
+    match (message, lang) {
+        (Modifiers, En) => "Modifiers",
+        (Modifiers, De) => "Modifikatortasten",
+        (Modifiers, Fr) => "Modificateurs",
+        (Modifiers, Ru) => "Модификаторы",
+        (Modifiers, Zh) => "修饰键",
+
+        (Keys, En) => "Keys",
+        (Keys, De) => "Tasten",
+        (Keys, Fr) => "Touches",
+        (Keys, Ru) => "Клавиши",
+        (Keys, Zh) => "按键",
+
+        (CustomKeySyntax, En) => "Custom key syntax (use decimal code)",
+        (CustomKeySyntax, De) => "Syntax für eigene Tastencodes (dezimal)",
+        (CustomKeySyntax, Fr) => "Syntaxe des codes de touche personnalisés (décimal)",
+        (CustomKeySyntax, Ru) => "Синтаксис произвольного кода клавиши (десятичный)",
+        (CustomKeySyntax, Zh) => "自定义键码语法（十进制）",
+
+        (MediaKeys, En) => "Media keys",
+        (MediaKeys, De) => "Medientasten",
+        (MediaKeys, Fr) => "Touches multimédia",
+        (MediaKeys, Ru) => "Мультимедийные клавиши",
+        (MediaKeys, Zh) => "多媒体键",
+
+        (CustomMediaSyntax, En) => "Custom media key syntax (use decimal or hex code)",
+        (CustomMediaSyntax, De) => "Syntax für eigene Medientasten (dezimal oder hexadezimal)",
+        (CustomMediaSyntax, Fr) => "Syntaxe des touches multimédia personnalisées (décimal ou hexadécimal)",
+        (CustomMediaSyntax, Ru) => "Синтаксис произвольной мультимедийной клавиши (десятичный или шестнадцатеричный код)",
+        (CustomMediaSyntax, Zh) => "自定义多媒体键语法（十进制或十六进制）",
+
+        (MouseActions, En) => "Mouse actions",
+        (MouseActions, De) => "Mausaktionen",
+        (MouseActions, Fr) => "Actions de la souris",
+        (MouseActions, Ru) => "Действия мыши",
+        (MouseActions, Zh) => "鼠标操作",
+
+        (DeviceNotFoundHint, En) => "hint: check that keyboard is connected with USB cable, it can't be programmed wirelessly",
+        (DeviceNotFoundHint, De) => "Hinweis: Prüfen Sie, ob die Tastatur per USB-Kabel angeschlossen ist, über Funk kann sie nicht programmiert werden",
+        (DeviceNotFoundHint, Fr) => "astuce : vérifiez que le clavier est branché avec un câble USB, il ne peut pas être programmé sans fil",
+        (DeviceNotFoundHint, Ru) => "подсказка: проверьте, что клавиатура подключена USB-кабелем, по беспроводной связи её запрограммировать нельзя",
+        (DeviceNotFoundHint, Zh) => "提示：请确认键盘已通过 USB 线连接，无线连接无法编程",
+
+        (AccessDeniedHint, En) => "hint: no permission to access device, run `setup-permissions` on Linux or use sudo",
+        (AccessDeniedHint, De) => "Hinweis: keine Berechtigung für das Gerät, führen Sie unter Linux `setup-permissions` aus oder verwenden Sie sudo",
+        (AccessDeniedHint, Fr) => "astuce : pas d'autorisation d'accès au périphérique, lancez `setup-permissions` sous Linux ou utilisez sudo",
+        (AccessDeniedHint, Ru) => "подсказка: нет доступа к устройству, выполните `setup-permissions` в Linux или используйте sudo",
+        (AccessDeniedHint, Zh) => "提示：没有访问设备的权限，请在 Linux 上运行 `setup-permissions` 或使用 sudo",
+
+        (UnknownKeyHint, En) => "hint: run `show-keys` to see supported key names",
+        (UnknownKeyHint, De) => "Hinweis: `show-keys` zeigt die unterstützten Tastennamen",
+        (UnknownKeyHint, Fr) => "astuce : lancez `show-keys` pour voir les noms de touches pris en charge",
+        (UnknownKeyHint, Ru) => "подсказка: выполните `show-keys`, чтобы увидеть поддерживаемые названия клавиш",
+        (UnknownKeyHint, Zh) => "提示：运行 `show-keys` 查看支持的按键名称",
+    }
+}
+
+/// Hint for common error, if it is one.
+pub fn hint(lang: Lang, err: &anyhow::Error) -> Option<&'static str> {
+    let message = if err.chain().any(|e| matches!(e.downcast_ref::<rusb::Error>(), Some(rusb::Error::Access))) {
+        Message::AccessDeniedHint
+    } else if err.chain().any(|e| e.to_string().contains("device not found")) {
+        Message::DeviceNotFoundHint
+    } else if err.chain().any(|e| e.to_string().contains("unknown key name")) {
+        Message::UnknownKeyHint
+    } else {
+        return None;
+    };
+    Some(tr(lang, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::{anyhow, Context as _};
+
+    #[test]
+    fn test_from_locale() {
+        assert_eq!(Lang::from_locale("de_DE.UTF-8"), Some(Lang::De));
+        assert_eq!(Lang::from_locale("ru"), Some(Lang::Ru));
+        assert_eq!(Lang::from_locale("zh_CN"), Some(Lang::Zh));
+        assert_eq!(Lang::from_locale("C.UTF-8"), None);
+        assert_eq!(Lang::from_locale("ja_JP"), None);
+    }
+
+    #[test]
+    fn test_hint() {
+        let err = Err::<(), _>(rusb::Error::Access).context("open device").unwrap_err();
+        assert_eq!(hint(Lang::En, &err), Some(tr(Lang::En, Message::AccessDeniedHint)));
+        let err = anyhow!("unknown key name 'nokey' in macro 'nokey'").context("load mapping config");
+        assert_eq!(hint(Lang::Fr, &err), Some(tr(Lang::Fr, Message::UnknownKeyHint)));
+        assert_eq!(hint(Lang::En, &anyhow!("config has 2 warnings")), None);
+    }
+}
@@ -0,0 +1,86 @@
+//! Starter config for given keyboard geometry.
+
+use itertools::Itertools as _;
+
+use ch57x_keyboard::consts::LAYER_COUNT;
+
+/// Placeholder macros for buttons, in keyboard order.
+const PLACEHOLDERS: &str = "abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Generates commented config with placeholder macros, buttons beyond
+/// available placeholders are left unbound.
+pub fn starter_config(rows: u8, columns: u8, knobs: u8) -> String {
+    let mut config = String::new();
+    let mut line = |s: &str| {
+        config.push_str(s);
+        config.push('\n');
+    };
+
+    line("# Generated by `ch57x-keyboard-tool init`, see example-mapping.yaml");
+    line("# for complete description of config format.");
+    line("");
+    line("# Position of knobs: 'normal' (on the right), 'upsidedown' (on the left),");
+    line("# 'clockwise' (at the bottom) or 'counterclockwise' (at the top).");
+    line("# Buttons below are listed for normal orientation, transpose them if you change it.");
+    line("orientation: normal");
+    line("");
+    line("# Count rows and columns with keyboard in normal orientation (knobs on the right).");
+    line(&format!("rows: {rows}"));
+    line(&format!("columns: {columns}"));
+    line(&format!("knobs: {knobs}"));
+    line("");
+    line("# Named macros, referenced as '$name' from layers.");
+    line("macros: {}");
+    line("");
+    line("# Layers are switched with a button on the side of keyboard.");
+    line("# Use '~' to leave key unbound.");
+    line("layers:");
+
+    let mut placeholders = PLACEHOLDERS.chars();
+    let buttons = (0..rows).map(|_| {
+        (0..columns).map(|_| placeholders.next().map_or("~".to_owned(), |c| format!("\"{c}\""))).join(", ")
+    }).collect_vec();
+
+    for layer in 1..=LAYER_COUNT {
+        line(&format!("  # Layer {layer}"));
+        line("  - buttons:");
+        for row in &buttons {
+            line(&format!("      - [{row}]"));
+        }
+        if knobs == 0 {
+            line("    knobs: []");
+            continue;
+        }
+        line("    knobs:");
+        for _ in 0..knobs {
+            line("      - ccw: \"volumedown\"");
+            line("        press: \"mute\"");
+            line("        cw: \"volumeup\"");
+        }
+    }
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use ch57x_keyboard::config::{Config, ConfigFormat};
+    use ch57x_keyboard::lint;
+
+    use super::*;
+
+    #[test]
+    fn test_starter_config_is_valid() -> anyhow::Result<()> {
+        for (rows, columns, knobs) in [(3, 4, 2), (1, 3, 1), (4, 1, 0), (7, 7, 3)] {
+            let text = starter_config(rows, columns, knobs);
+            let config = Config::from_reader(text.as_bytes(), ConfigFormat::Yaml, None)?;
+            let config_secrets = config.secrets.clone();
+            let layers = config.render()?;
+            assert_eq!(layers.len(), LAYER_COUNT as usize);
+            assert_eq!(layers[0].buttons.len(), rows as usize * columns as usize);
+            assert_eq!(layers[0].knobs.len(), knobs as usize);
+            assert!(lint::lint(&layers, &config_secrets).is_empty(), "{text}");
+        }
+        Ok(())
+    }
+}
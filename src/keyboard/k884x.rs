@@ -1,72 +1,32 @@
 use anyhow::{bail, ensure, Result};
 use log::debug;
-use rusb::{Context, DeviceHandle};
 
 use crate::keyboard::Accord;
+use crate::traffic;
 
-use super::{Key, Keyboard, Macro, MouseAction, MouseEvent};
+use super::{Key, KeyIdLayout, Keyboard, Macro, MouseAction, MouseEvent, TransferOptions, Transport};
 
 pub struct Keyboard884x {
-    handle: DeviceHandle<Context>,
+    transport: Box<dyn Transport>,
     endpoint: u8,
+    report_id: Option<u8>,
+    transfer: TransferOptions,
+    key_ids: KeyIdLayout,
 }
 
 impl Keyboard for Keyboard884x {
     fn bind_key(&mut self, layer: u8, key: Key, expansion: &Macro) -> Result<()> {
-        ensure!(layer <= 15, "invalid layer index");
-
         debug!("bind {} on layer {} to {}", key, layer, expansion);
+        let _context = traffic::context(layer, Some(key));
 
-        let mut msg = vec![
-            0x03,
-            0xfe,
-            key.to_key_id(15)?,
-            layer + 1,
-            expansion.kind(),
-            0,
-            0,
-            0,
-            0,
-            0,
-        ];
-
-        match expansion {
-            Macro::Keyboard(presses) => {
-                ensure!(presses.len() <= 18, "macro sequence is too long");
-
-                // Allow single key modifier to be used in combo with other key(s)
-                if presses.len() == 1 && presses[0].code.is_none(){
-                    msg.push(0);
-                } else {
-                    msg.push(presses.len() as u8);
-                }
-
-                for Accord { modifiers, code } in presses.iter() {
-                    msg.extend_from_slice(&[modifiers.as_u8(), code.map_or(0, |c| c.value())]);
-                }
-            }
-            Macro::Media(code) => {
-                let [low, high] = (*code as u16).to_le_bytes();
-                msg.extend_from_slice(&[0, low, high, 0, 0, 0, 0]);
-            }
-            Macro::Mouse(MouseEvent(MouseAction::Click(buttons), _)) => {
-                ensure!(!buttons.is_empty(), "buttons must be given for click macro");
-                msg.extend_from_slice(&[0x01, 0, buttons.as_u8()]);
-            }
-            Macro::Mouse(MouseEvent(MouseAction::WheelUp, modifier)) => {
-                msg.extend_from_slice(&[0x03, modifier.map_or(0, |m| m as u8), 0, 0, 0, 0x1]);
-            }
-            Macro::Mouse(MouseEvent(MouseAction::WheelDown, modifier)) => {
-                msg.extend_from_slice(&[0x03, modifier.map_or(0, |m| m as u8), 0, 0, 0, 0xff]);
-            }
-        };
-
-        self.send(&msg)?;
+        for msg in bind_key_messages(self.key_ids, layer, key, expansion)? {
+            self.send(&msg)?;
+        }
 
         Ok(())
     }
 
-    fn set_led(&mut self, _n: u8) -> Result<()> {
+    fn set_led(&mut self, _layer: u8, _n: u8) -> Result<()> {
         bail!(
             "If you have a device which supports backlight LEDs, please let us know at \
                https://github.com/kriomant/ch57x-keyboard-tool/issues/60. We'll be glad to \
@@ -74,25 +34,91 @@ impl Keyboard for Keyboard884x {
         )
     }
 
-    fn get_handle(&self) -> &DeviceHandle<Context> {
-        &self.handle
+    fn get_transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
     }
 
     fn get_endpoint(&self) -> u8 {
         self.endpoint
     }
 
+    fn get_report_id(&self) -> Option<u8> {
+        self.report_id
+    }
+
+    fn get_transfer_options(&self) -> &TransferOptions {
+        &self.transfer
+    }
+
     fn preferred_endpoint() -> u8 {
         0x04
     }
 }
 
 impl Keyboard884x {
-    pub fn new(handle: DeviceHandle<Context>, endpoint: u8) -> Result<Self> {
-        let mut keyboard = Self { handle, endpoint };
+    pub fn new(
+        transport: Box<dyn Transport>,
+        endpoint: u8,
+        report_id: Option<u8>,
+        transfer: TransferOptions,
+        key_ids: KeyIdLayout,
+    ) -> Result<Self> {
+        let mut keyboard = Self { transport, endpoint, report_id, transfer, key_ids };
 
-        keyboard.send(&[])?;
+        keyboard.send_report(&[])?;
 
         Ok(keyboard)
     }
 }
+
+/// Builds messages binding macro to key, without report ID and padding.
+pub fn bind_key_messages(key_ids: KeyIdLayout, layer: u8, key: Key, expansion: &Macro) -> Result<Vec<Vec<u8>>> {
+    ensure!(layer <= 15, "invalid layer index");
+    let key_id = key.to_key_id(key_ids)?;
+
+    let mut msg = vec![
+        0xfe,
+        key_id,
+        layer + 1,
+        expansion.kind(),
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+
+    match expansion {
+        Macro::Keyboard(presses) => {
+            ensure!(presses.len() <= 18, "macro sequence of {} chords is too long, keyboard accepts 18 at most", presses.len());
+
+            // Zero length makes modifiers held while key is pressed,
+            // so they may be used in combo with other key(s).
+            if expansion.held_modifiers().is_some() {
+                msg.push(0);
+            } else {
+                msg.push(presses.len() as u8);
+            }
+
+            for Accord { modifiers, code } in presses.iter() {
+                msg.extend_from_slice(&[modifiers.as_u8(), code.map_or(0, |c| c.value())]);
+            }
+        }
+        Macro::Media(code) => {
+            let [low, high] = code.value().to_le_bytes();
+            msg.extend_from_slice(&[0, low, high, 0, 0, 0, 0]);
+        }
+        Macro::Mouse(MouseEvent(MouseAction::Click(buttons), _)) => {
+            ensure!(!buttons.is_empty(), "buttons must be given for click macro");
+            msg.extend_from_slice(&[0x01, 0, buttons.as_u8()]);
+        }
+        Macro::Mouse(MouseEvent(MouseAction::WheelUp, modifier)) => {
+            msg.extend_from_slice(&[0x03, modifier.map_or(0, |m| m as u8), 0, 0, 0, 0x1]);
+        }
+        Macro::Mouse(MouseEvent(MouseAction::WheelDown, modifier)) => {
+            msg.extend_from_slice(&[0x03, modifier.map_or(0, |m| m as u8), 0, 0, 0, 0xff]);
+        }
+    };
+
+    Ok(vec![msg])
+}
@@ -1,91 +1,131 @@
 use anyhow::{ensure, Result};
 use log::debug;
-use rusb::{Context, DeviceHandle};
 
-use super::{Key, Keyboard, Macro, MouseAction, MouseEvent};
+use crate::traffic;
+
+use super::{Key, KeyIdLayout, Keyboard, Macro, MouseAction, MouseEvent, TransferOptions, Transport};
 
 pub struct Keyboard8890 {
-    handle: DeviceHandle<Context>,
+    transport: Box<dyn Transport>,
     endpoint: u8,
+    report_id: Option<u8>,
+    transfer: TransferOptions,
+    key_ids: KeyIdLayout,
 }
 
 impl Keyboard for Keyboard8890 {
     fn bind_key(&mut self, layer: u8, key: Key, expansion: &Macro) -> Result<()> {
-        ensure!(layer <= 15, "invalid layer index");
-
         debug!("bind {} on layer {} to {}", key, layer, expansion);
+        let _context = traffic::context(layer, Some(key));
 
-        // Start key binding
-        self.send(&[0x03, 0xfe, layer+1, 0x1, 0x1, 0, 0, 0, 0])?;
-
-        match expansion {
-            Macro::Keyboard(presses) => {
-                ensure!(presses.len() <= 5, "macro sequence is too long");
-                // For whatever reason empty key is added before others.
-                let iter = presses.iter().map(|accord| (accord.modifiers.as_u8(), accord.code.map_or(0, |c| c.value())));
-                let (len, items) = (presses.len() as u8, Box::new(std::iter::once((0, 0)).chain(iter)));
-                for (i, (modifiers, code)) in items.enumerate() {
-                    self.send(&[
-                        0x03,
-                        key.to_key_id(12)?,
-                        ((layer+1) << 4) | expansion.kind(),
-                        len,
-                        i as u8,
-                        modifiers,
-                        code,
-                        0,
-                        0,
-                    ])?;
-                }
-            }
-            Macro::Media(code) => {
-                let [low, high] = (*code as u16).to_le_bytes();
-                self.send(&[0x03, key.to_key_id(12)?, ((layer+1) << 4) | 0x02, low, high, 0, 0, 0, 0])?;
-            }
-            Macro::Mouse(MouseEvent(MouseAction::Click(buttons), modifier)) => {
-                ensure!(!buttons.is_empty(), "buttons must be given for click macro");
-                self.send(&[0x03, key.to_key_id(12)?, ((layer+1) << 4) | 0x03, buttons.as_u8(), 0, 0, 0, modifier.map_or(0, |m| m as u8), 0])?;
-            }
-            Macro::Mouse(MouseEvent(MouseAction::WheelUp, modifier)) => {
-                self.send(&[0x03, key.to_key_id(12)?, ((layer+1) << 4) | 0x03, 0, 0, 0, 0x01, modifier.map_or(0, |m| m as u8), 0])?;
-            }
-            Macro::Mouse(MouseEvent(MouseAction::WheelDown, modifier)) => {
-                self.send(&[0x03, key.to_key_id(12)?, ((layer+1) << 4) | 0x03, 0, 0, 0, 0xff, modifier.map_or(0, |m| m as u8), 0])?;
-            }
-        };
-
-        // Finish key binding
-        self.send(&[0x03, 0xaa, 0xaa, 0, 0, 0, 0, 0, 0])?;
+        for msg in bind_key_messages(self.key_ids, layer, key, expansion)? {
+            self.send(&msg)?;
+        }
 
         Ok(())
     }
 
-    fn set_led(&mut self, n: u8) -> Result<()> {
-        self.send(&[0x03, 0xa1, 0x01, 0, 0, 0, 0, 0, 0])?;
-        self.send(&[0x03, 0xb0, 0x18, n, 0, 0, 0, 0, 0])?;
-        self.send(&[0x03, 0xaa, 0xa1, 0, 0, 0, 0, 0, 0])?;
+    fn set_led(&mut self, layer: u8, n: u8) -> Result<()> {
+        // Start message is sent as captured from vendor software. Captures only show
+        // `0x01` here, it isn't known whether it is layer like in key binding messages,
+        // so other layers are rejected until setting LED mode for them is captured.
+        ensure!(layer == 0, "LED mode can be set for the first layer only, messages for other layers aren't known");
+        let _context = traffic::context(layer, None);
+
+        self.send(&[0xa1, 0x01, 0, 0, 0, 0, 0, 0])?;
+        self.send(&[0xb0, 0x18, n, 0, 0, 0, 0, 0])?;
+        self.send(&[0xaa, 0xa1, 0, 0, 0, 0, 0, 0])?;
         Ok(())
     }
 
-    fn get_handle(&self) -> &DeviceHandle<Context> {
-        &self.handle
+    fn get_transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
     }
 
     fn get_endpoint(&self) -> u8 {
         self.endpoint
     }
 
+    fn get_report_id(&self) -> Option<u8> {
+        self.report_id
+    }
+
+    fn get_transfer_options(&self) -> &TransferOptions {
+        &self.transfer
+    }
+
     fn preferred_endpoint() -> u8 {
         0x02
     }
 }
 
 impl Keyboard8890 {
-    pub fn new(handle: DeviceHandle<Context>, endpoint: u8) -> Result<Self> {
-        let mut keyboard = Self { handle, endpoint };
+    pub fn new(
+        transport: Box<dyn Transport>,
+        endpoint: u8,
+        report_id: Option<u8>,
+        transfer: TransferOptions,
+        key_ids: KeyIdLayout,
+    ) -> Result<Self> {
+        let mut keyboard = Self { transport, endpoint, report_id, transfer, key_ids };
 
-        keyboard.send(&[])?;
+        keyboard.send_report(&[])?;
 
         Ok(keyboard)
     }
 }
+
+/// Builds messages binding macro to key, without report ID and padding.
+pub fn bind_key_messages(key_ids: KeyIdLayout, layer: u8, key: Key, expansion: &Macro) -> Result<Vec<Vec<u8>>> {
+    ensure!(layer <= 15, "invalid layer index");
+    let key_id = key.to_key_id(key_ids)?;
+
+    let mut msgs = vec![];
+
+    // Start key binding
+    msgs.push(vec![0xfe, layer+1, 0x1, 0x1, 0, 0, 0, 0]);
+
+    match expansion {
+        Macro::Keyboard(presses) => {
+            ensure!(presses.len() <= 5, "macro sequence of {} chords is too long, keyboard accepts 5 at most", presses.len());
+            // There is no known way to make firmware hold modifiers, they would just be tapped.
+            ensure!(expansion.held_modifiers().is_none(),
+                    "modifier-only macro '{expansion}' isn't supported by this keyboard, \
+                     modifiers can only be combined with key, like 'ctrl-c'");
+            // For whatever reason empty key is added before others.
+            let iter = presses.iter().map(|accord| (accord.modifiers.as_u8(), accord.code.map_or(0, |c| c.value())));
+            let (len, items) = (presses.len() as u8, Box::new(std::iter::once((0, 0)).chain(iter)));
+            for (i, (modifiers, code)) in items.enumerate() {
+                msgs.push(vec![
+                    key_id,
+                    ((layer+1) << 4) | expansion.kind(),
+                    len,
+                    i as u8,
+                    modifiers,
+                    code,
+                    0,
+                    0,
+                ]);
+            }
+        }
+        Macro::Media(code) => {
+            let [low, high] = code.value().to_le_bytes();
+            msgs.push(vec![key_id, ((layer+1) << 4) | 0x02, low, high, 0, 0, 0, 0]);
+        }
+        Macro::Mouse(MouseEvent(MouseAction::Click(buttons), modifier)) => {
+            ensure!(!buttons.is_empty(), "buttons must be given for click macro");
+            msgs.push(vec![key_id, ((layer+1) << 4) | 0x03, buttons.as_u8(), 0, 0, 0, modifier.map_or(0, |m| m as u8), 0]);
+        }
+        Macro::Mouse(MouseEvent(MouseAction::WheelUp, modifier)) => {
+            msgs.push(vec![key_id, ((layer+1) << 4) | 0x03, 0, 0, 0, 0x01, modifier.map_or(0, |m| m as u8), 0]);
+        }
+        Macro::Mouse(MouseEvent(MouseAction::WheelDown, modifier)) => {
+            msgs.push(vec![key_id, ((layer+1) << 4) | 0x03, 0, 0, 0, 0xff, modifier.map_or(0, |m| m as u8), 0]);
+        }
+    };
+
+    // Finish key binding
+    msgs.push(vec![0xaa, 0xaa, 0, 0, 0, 0, 0, 0]);
+
+    Ok(msgs)
+}
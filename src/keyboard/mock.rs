@@ -0,0 +1,230 @@
+//! In-memory keyboard for trying commands and testing flows without hardware.
+//!
+//! Messages are encoded by real model implementation and written to `MockDevice`,
+//! which records packets and decodes them into bindings and LED mode as keyboard
+//! firmware would store them.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::{Model, Transport, REPORT_SIZE};
+use crate::hid::DEFAULT_REPORT_ID;
+
+/// Messages binding keys by layer and key ID.
+pub type DecodedBindings = BTreeMap<(u8, u8), Vec<Vec<u8>>>;
+
+/// Device accepting any packets, shared between clones so that its state
+/// may be inspected after keyboard took ownership of transport.
+#[derive(Debug, Clone)]
+pub struct MockDevice {
+    state: Rc<RefCell<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    model: Model,
+    packets: Vec<Vec<u8>>,
+    /// Packets after which device stops accepting writes.
+    fail_after: Option<usize>,
+    /// Index of single packet which is rejected.
+    fail_at: Option<usize>,
+    /// Received part of report split into several packets.
+    partial: Vec<u8>,
+    /// Messages of 8890 binding which isn't finished yet.
+    pending: Option<Vec<Vec<u8>>>,
+    bindings: DecodedBindings,
+    led: Option<u8>,
+}
+
+impl MockDevice {
+    /// Device decoding messages of given model.
+    pub fn new(model: Model) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                model,
+                packets: vec![],
+                fail_after: None,
+                fail_at: None,
+                partial: vec![],
+                pending: None,
+                bindings: BTreeMap::new(),
+                led: None,
+            })),
+        }
+    }
+
+    /// Device which disappears after given number of packets, to test failure handling.
+    pub fn failing_after(model: Model, count: usize) -> Self {
+        let device = Self::new(model);
+        device.state.borrow_mut().fail_after = Some(count);
+        device
+    }
+
+    /// Device which rejects packet with given index only, like on transient failure.
+    pub fn failing_at(model: Model, index: usize) -> Self {
+        let device = Self::new(model);
+        device.state.borrow_mut().fail_at = Some(index);
+        device
+    }
+
+    pub fn model(&self) -> Model {
+        self.state.borrow().model
+    }
+
+    /// Packets written so far, padded as they were sent.
+    pub fn packets(&self) -> Vec<Vec<u8>> {
+        self.state.borrow().packets.clone()
+    }
+
+    /// Bindings by layer and key ID, each is messages binding key as they were
+    /// received, without report ID. Only item messages are kept for 8890 keyboards.
+    pub fn bindings(&self) -> DecodedBindings {
+        self.state.borrow().bindings.clone()
+    }
+
+    /// LED mode set last.
+    pub fn led(&self) -> Option<u8> {
+        self.state.borrow().led
+    }
+}
+
+impl State {
+    /// Decodes complete report like keyboard would, unknown ones are ignored.
+    fn receive(&mut self, report: &[u8]) {
+        let Some((&DEFAULT_REPORT_ID, msg)) = report.split_first() else {
+            return;
+        };
+        match self.model {
+            Model::K884x => {
+                if let [0xfe, key_id, layer, ..] = *msg {
+                    self.bindings.insert((layer.wrapping_sub(1), key_id), vec![msg.to_vec()]);
+                }
+            }
+            Model::K8890 => match (msg, &mut self.pending) {
+                ([0xfe, _, 0x01, 0x01, ..], _) => self.pending = Some(vec![]),
+                ([0xaa, 0xaa, ..], _) => {
+                    if let Some(items) = self.pending.take().filter(|items| !items.is_empty()) {
+                        let (key_id, layer) = (items[0][0], (items[0][1] >> 4).wrapping_sub(1));
+                        self.bindings.insert((layer, key_id), items);
+                    }
+                }
+                ([0xb0, 0x18, mode, ..], None) => self.led = Some(*mode),
+                (_, Some(items)) => items.push(msg.to_vec()),
+                (_, None) => {}
+            },
+        }
+    }
+}
+
+impl Transport for MockDevice {
+    fn write_interrupt(&self, _endpoint: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        let mut state = self.state.borrow_mut();
+        let index = state.packets.len();
+        if state.fail_after.is_some_and(|count| index >= count) {
+            return Err(rusb::Error::NoDevice);
+        }
+        if state.fail_at.take_if(|at| *at == index).is_some() {
+            return Err(rusb::Error::NoDevice);
+        }
+        state.packets.push(buf.to_vec());
+
+        state.partial.extend_from_slice(buf);
+        while state.partial.len() >= REPORT_SIZE {
+            let report = state.partial.drain(..REPORT_SIZE).collect::<Vec<_>>();
+            state.receive(&report);
+        }
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    use crate::config::{Config, ConfigFormat};
+    use crate::keyboard::{Key, Keyboard, Macro, TransferOptions};
+
+    fn open(device: &MockDevice) -> Result<Box<dyn Keyboard>> {
+        let model = device.model();
+        let transfer = TransferOptions { retries: 0, ..Default::default() };
+        model.open(Box::new(device.clone()), model.preferred_endpoint(), Some(DEFAULT_REPORT_ID), transfer, model.key_id_layout(None))
+    }
+
+    /// Messages device keeps for binding, padded as they are sent.
+    fn expected_binding(model: Model, layer: u8, key: Key, macro_: &Macro) -> Result<Vec<Vec<u8>>> {
+        let mut messages = model.bind_key_messages(model.key_id_layout(None), layer, key, macro_)?;
+        if model == Model::K8890 {
+            // Start and finish messages aren't kept.
+            messages = messages[1..messages.len() - 1].to_vec();
+        }
+        Ok(messages.into_iter().map(|mut msg| {
+            msg.resize(REPORT_SIZE - 1, 0);
+            msg
+        }).collect())
+    }
+
+    #[test]
+    fn test_upload() -> Result<()> {
+        let config = "orientation: normal\nrows: 1\ncolumns: 2\nknobs: 0\nlayers:\n  - buttons: [[a, ctrl-c]]\n    knobs: []\n";
+        let layers = Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None)?.render()?;
+
+        for model in [Model::K884x, Model::K8890] {
+            let device = MockDevice::new(model);
+            assert_eq!(open(&device)?.upload(&layers)?, 2);
+            let mut expected = BTreeMap::new();
+            for (key, macro_) in layers[0].bindings() {
+                expected.insert((0, key.to_key_id(model.key_id_layout(None))?), expected_binding(model, 0, key, macro_)?);
+            }
+            assert_eq!(device.bindings(), expected);
+
+            // Initial report of zeros is followed by messages of each binding.
+            let packets = device.packets();
+            assert!(packets.iter().all(|p| p.len() == 64));
+            assert_eq!(packets[0], vec![0; 64]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebind() -> Result<()> {
+        let device = MockDevice::new(Model::K8890);
+        let mut keyboard = open(&device)?;
+        keyboard.bind_key(1, Key::Button(0), &"a".parse()?)?;
+        keyboard.bind_key(1, Key::Button(0), &"b".parse()?)?;
+        assert_eq!(device.bindings().into_iter().collect::<Vec<_>>(),
+                   [((1, 1), expected_binding(Model::K8890, 1, Key::Button(0), &"b".parse()?)?)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failure() -> Result<()> {
+        let device = MockDevice::failing_after(Model::K884x, 2);
+        let mut keyboard = open(&device)?;
+        keyboard.bind_key(0, Key::Button(0), &"a".parse()?)?;
+        assert!(keyboard.bind_key(0, Key::Button(1), &"b".parse()?).is_err());
+        assert_eq!(device.bindings().len(), 1);
+
+        let device = MockDevice::failing_at(Model::K884x, 1);
+        let mut keyboard = open(&device)?;
+        assert!(keyboard.bind_key(0, Key::Button(0), &"a".parse()?).is_err());
+        keyboard.bind_key(0, Key::Button(1), &"b".parse()?)?;
+        assert_eq!(device.bindings().keys().collect::<Vec<_>>(), [&(0, 2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_led() -> Result<()> {
+        let device = MockDevice::new(Model::K8890);
+        let mut keyboard = open(&device)?;
+        keyboard.set_led(0, 1)?;
+        keyboard.set_led(0, 2)?;
+        assert_eq!(device.led(), Some(2));
+        // Messages setting LED mode for other layers aren't known.
+        assert!(keyboard.set_led(1, 1).is_err());
+        assert!(open(&MockDevice::new(Model::K884x))?.set_led(0, 1).is_err());
+        Ok(())
+    }
+}
@@ -1,44 +1,285 @@
 pub(crate) mod k884x;
 pub(crate) mod k8890;
+pub mod mock;
 
+use crate::config::FlatLayer;
 use crate::parse;
+use crate::traffic::{self, Direction};
 
 use std::{time::Duration, str::FromStr, fmt::Display};
 
-use anyhow::{anyhow, ensure, Result};
+use anyhow::{anyhow, Context as _, Result};
 use enumset::{EnumSetType, EnumSet};
-use log::debug;
+use log::warn;
 use rusb::{Context, DeviceHandle};
 use serde_with::DeserializeFromStr;
-use strum_macros::{EnumString, Display, EnumIter, EnumMessage};
+use strum_macros::{AsRefStr, EnumString, Display, EnumIter, EnumMessage};
 
 use itertools::Itertools as _;
 
-const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+/// Delay before first retry of failed write, doubled for each next one.
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Packet size of programming endpoint of most keyboards.
+pub const DEFAULT_PACKET_SIZE: usize = 64;
+
+/// Length of output report keyboards expect, report ID included. Shorter
+/// reports are padded to it even if endpoint takes smaller packets.
+pub const REPORT_SIZE: usize = 64;
+
+/// Parameters of writes to device.
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    /// Timeout of single write.
+    pub timeout: Duration,
+    /// Number of times failed write is retried.
+    pub retries: u32,
+    /// Delay after each message, for devices which can't keep up.
+    pub message_delay: Duration,
+    /// Size reports are padded and split to, it is taken from
+    /// endpoint descriptor when device is opened.
+    pub packet_size: usize,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(100),
+            retries: 3,
+            message_delay: Duration::ZERO,
+            packet_size: DEFAULT_PACKET_SIZE,
+        }
+    }
+}
+
+/// Keyboard protocol family, determined by USB product ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, DeserializeFromStr)]
+#[strum(ascii_case_insensitive)]
+pub enum Model {
+    #[strum(serialize="884x")]
+    K884x,
+    #[strum(serialize="8890")]
+    K8890,
+}
+
+/// Number of buttons and knobs keyboard has, written like `12+4`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DeserializeFromStr)]
+pub struct Geometry {
+    pub buttons: usize,
+    pub knobs: usize,
+}
+
+impl FromStr for Geometry {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parsed = s.split_once('+')
+            .and_then(|(buttons, knobs)| Some(Self { buttons: buttons.trim().parse().ok()?, knobs: knobs.trim().parse().ok()? }));
+        parsed.ok_or_else(|| format!("expected number of buttons and knobs like '12+4', got '{s}'"))
+    }
+}
+
+impl Display for Geometry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}+{}", self.buttons, self.knobs)
+    }
+}
+
+/// Allocation of key IDs used in binding messages. It differs between
+/// keyboard variants sharing the same product ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyIdLayout {
+    /// Number of button IDs, buttons are numbered from 1.
+    pub buttons: u8,
+    /// ID of first action (counterclockwise rotation) of each knob,
+    /// IDs of press and clockwise rotation follow it.
+    pub knobs: &'static [u8],
+}
+
+/// Layouts of 884x variants which differ from default one.
+const K884X_LAYOUTS: &[(Geometry, KeyIdLayout)] = &[
+    (Geometry { buttons: 6, knobs: 1 }, KeyIdLayout { buttons: 6, knobs: &[7] }),
+    // Fourth knob takes IDs of buttons 13-15 missing in this variant.
+    (Geometry { buttons: 12, knobs: 4 }, KeyIdLayout { buttons: 12, knobs: &[16, 19, 22, 13] }),
+    (Geometry { buttons: 15, knobs: 3 }, K884X_DEFAULT_LAYOUT),
+];
+const K884X_DEFAULT_LAYOUT: KeyIdLayout = KeyIdLayout { buttons: 15, knobs: &[16, 19, 22] };
+
+const K8890_LAYOUTS: &[(Geometry, KeyIdLayout)] = &[];
+const K8890_DEFAULT_LAYOUT: KeyIdLayout = KeyIdLayout { buttons: 12, knobs: &[13, 16, 19] };
+
+impl Model {
+    pub fn from_product_id(product_id: u16) -> Option<Self> {
+        match product_id {
+            0x8840 | 0x8842 => Some(Self::K884x),
+            0x8890 => Some(Self::K8890),
+            _ => None,
+        }
+    }
+
+    /// Key ID layout of variant with given geometry, default one is used
+    /// if geometry is unknown or doesn't match any known variant.
+    pub fn key_id_layout(self, geometry: Option<Geometry>) -> KeyIdLayout {
+        let (layouts, default) = match self {
+            Self::K884x => (K884X_LAYOUTS, K884X_DEFAULT_LAYOUT),
+            Self::K8890 => (K8890_LAYOUTS, K8890_DEFAULT_LAYOUT),
+        };
+        layouts.iter()
+            .find(|(g, _)| Some(*g) == geometry)
+            .map_or(default, |(_, layout)| *layout)
+    }
+
+    /// Builds messages binding macro to key, without report ID and padding.
+    pub fn bind_key_messages(self, layout: KeyIdLayout, layer: u8, key: Key, expansion: &Macro) -> Result<Vec<Vec<u8>>> {
+        match self {
+            Self::K884x => k884x::bind_key_messages(layout, layer, key, expansion),
+            Self::K8890 => k8890::bind_key_messages(layout, layer, key, expansion),
+        }
+    }
+
+    /// Programming endpoint used unless another one is given.
+    pub fn preferred_endpoint(self) -> u8 {
+        match self {
+            Self::K884x => k884x::Keyboard884x::preferred_endpoint(),
+            Self::K8890 => k8890::Keyboard8890::preferred_endpoint(),
+        }
+    }
+
+    /// Starts programming keyboard of this model through given transport.
+    pub fn open(
+        self,
+        transport: Box<dyn Transport>,
+        endpoint: u8,
+        report_id: Option<u8>,
+        transfer: TransferOptions,
+        key_ids: KeyIdLayout,
+    ) -> Result<Box<dyn Keyboard>> {
+        Ok(match self {
+            Self::K884x => Box::new(k884x::Keyboard884x::new(transport, endpoint, report_id, transfer, key_ids)?),
+            Self::K8890 => Box::new(k8890::Keyboard8890::new(transport, endpoint, report_id, transfer, key_ids)?),
+        })
+    }
+}
+
+/// Channel reports are written to device through, real device is written
+/// through USB handle, see `mock` for in-memory one.
+pub trait Transport {
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+}
+
+impl Transport for DeviceHandle<Context> {
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_interrupt(self, endpoint, buf, timeout)
+    }
+}
+
+/// Transport shared by keyboards opened one after another, see `device::Session`.
+impl<T: Transport + ?Sized> Transport for std::rc::Rc<T> {
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        T::write_interrupt(self, endpoint, buf, timeout)
+    }
+}
+
+/// Context of error of write which failed even after retries.
+#[derive(Debug)]
+pub struct WriteFailed {
+    pub attempts: u32,
+}
+
+impl Display for WriteFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "write failed after {} attempts", self.attempts)
+    }
+}
 
 pub trait Keyboard {
     fn bind_key(&mut self, layer: u8, key: Key, expansion: &Macro) -> Result<()>;
-    fn set_led(&mut self, n: u8) -> Result<()>;
+    fn set_led(&mut self, layer: u8, n: u8) -> Result<()>;
 
     fn preferred_endpoint() -> u8 where Self: Sized;
-    fn get_handle(&self) -> &DeviceHandle<Context>;
+    fn get_transport(&self) -> &dyn Transport;
     fn get_endpoint(&self) -> u8;
+    fn get_report_id(&self) -> Option<u8>;
+    fn get_transfer_options(&self) -> &TransferOptions;
+
+    /// Binds all macros from rendered config, returns number of bound keys.
+    fn upload(&mut self, layers: &[FlatLayer]) -> Result<usize> {
+        self.upload_with_progress(layers, &mut |_, _, _| {})
+    }
+
+    /// Same as `upload`, but calls `progress` with layer index, key and macro
+    /// before binding each key.
+    fn upload_with_progress(&mut self, layers: &[FlatLayer], progress: &mut dyn FnMut(u8, Key, &Macro)) -> Result<usize> {
+        let mut count = 0;
+        for (layer_idx, layer) in layers.iter().enumerate() {
+            for (key, macro_) in layer.bindings() {
+                progress(layer_idx as u8, key, macro_);
+                self.bind_key(layer_idx as u8, key, macro_).context("bind key")?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
 
+    /// Sends message prefixed with report ID, if device uses it.
     fn send(&mut self, msg: &[u8]) -> Result<()> {
-        let mut buf = [0; 64];
-        buf[..msg.len()].copy_from_slice(msg);
-
-        debug!("send: {:02x?}", buf);
-        let written = self
-            .get_handle()
-            .write_interrupt(self.get_endpoint(), &buf, DEFAULT_TIMEOUT)?;
-        ensure!(written == buf.len(), "not all data written");
+        match self.get_report_id() {
+            Some(report_id) => self.send_report(&[&[report_id], msg].concat()),
+            None => self.send_report(msg),
+        }
+    }
+
+    /// Sends report padded with zeros to report size and split to packets.
+    /// Failed and partial writes are retried with exponential backoff.
+    fn send_report(&mut self, report: &[u8]) -> Result<()> {
+        let options = self.get_transfer_options();
+        for buf in packets(report, options.packet_size) {
+            traffic::record(Direction::Out, self.get_endpoint(), &buf);
+            let mut attempt = 0;
+            loop {
+                let error = match self.get_transport().write_interrupt(self.get_endpoint(), &buf, options.timeout) {
+                    Ok(written) if written == buf.len() => break,
+                    Ok(written) => anyhow!("only {written} of {} bytes written", buf.len()),
+                    Err(err @ (rusb::Error::Timeout | rusb::Error::Io | rusb::Error::Pipe
+                              | rusb::Error::Busy | rusb::Error::Interrupted | rusb::Error::Overflow)) => err.into(),
+                    Err(err) => return Err(err.into()),
+                };
+                if attempt == options.retries {
+                    return Err(error.context(WriteFailed { attempts: attempt + 1 }));
+                }
+                let delay = RETRY_DELAY * 2u32.pow(attempt);
+                attempt += 1;
+                warn!("write failed: {error}, retrying in {delay:?} (attempt {attempt} of {})", options.retries);
+                std::thread::sleep(delay);
+            }
+            if attempt > 0 {
+                warn!("write succeeded after {attempt} retries");
+            }
+        }
+
+        if !options.message_delay.is_zero() {
+            std::thread::sleep(options.message_delay);
+        }
         Ok(())
     }
 }
 
+/// Pads report with zeros to report size and splits it to packets of given size.
+/// Longer report is padded to multiple of packet size.
+fn packets(report: &[u8], size: usize) -> Vec<Vec<u8>> {
+    let mut report = report.to_vec();
+    report.resize(padded_len(report.len(), size), 0);
+    report.chunks(size.max(1)).map(<[u8]>::to_vec).collect()
+}
+
+/// Number of bytes sent for report of given length.
+pub fn padded_len(report_len: usize, packet_size: usize) -> usize {
+    report_len.max(REPORT_SIZE).next_multiple_of(packet_size.max(1))
+}
+
 #[allow(unused)]
-#[derive(Debug, Clone, Copy, Display)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+#[strum(ascii_case_insensitive)]
 #[repr(u8)]
 pub enum KnobAction {
     #[strum(serialize="ccw")]
@@ -49,7 +290,7 @@ pub enum KnobAction {
     RotateCW,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Key {
     Button(u8),
     #[allow(unused)]
@@ -66,12 +307,14 @@ impl Display for Key {
 }
 
 impl Key {
-    fn to_key_id(self, base: u8) -> Result<u8> {
+    /// Key identifier used in binding messages.
+    pub fn to_key_id(self, layout: KeyIdLayout) -> Result<u8> {
         match self {
-            Key::Button(n) if n >= base => Err(anyhow!("invalid key index")),
+            Key::Button(n) if n >= layout.buttons => Err(anyhow!("invalid key index")),
             Key::Button(n) => Ok(n + 1),
-            Key::Knob(n, _) if n >= 3 => Err(anyhow!("invalid knob index")),
-            Key::Knob(n, action) => Ok(base + 1 + 3 * n + (action as u8)),
+            Key::Knob(n, action) => layout.knobs.get(n as usize)
+                .map(|first| first + action as u8)
+                .ok_or_else(|| anyhow!("invalid knob index")),
         }
     }
 }
@@ -99,26 +342,75 @@ pub enum Modifier {
 
 pub type Modifiers = EnumSet<Modifier>;
 
-
+// Microphone mute isn't here: it is Phone Mute usage (0x2f) of Telephony page,
+// and keyboards send consumer page usages only.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumIter, EnumMessage, Display)]
 #[repr(u16)]
 #[strum(serialize_all="lowercase")]
 #[strum(ascii_case_insensitive)]
 pub enum MediaCode {
+	Sleep = 0x32,
+	BrightnessUp = 0x6f,
+	BrightnessDown = 0x70,
+	FastForward = 0xb3,
+	Rewind = 0xb4,
 	Next = 0xb5,
     #[strum(serialize="previous", serialize="prev")]
 	Previous = 0xb6,
 	Stop = 0xb7,
+	Eject = 0xb8,
 	Play = 0xcd,
 	Mute = 0xe2,
+	BassBoost = 0xe5,
 	VolumeUp = 0xe9,
 	VolumeDown = 0xea,
 	Favorites = 0x182,
+	MediaSelect = 0x183,
+	Email = 0x18a,
 	Calculator = 0x192,
+	MyComputer = 0x194,
 	ScreenLock = 0x19e,
+	Search = 0x221,
+	Homepage = 0x223,
+	BrowserBack = 0x224,
+	BrowserForward = 0x225,
+	BrowserStop = 0x226,
+	BrowserRefresh = 0x227,
+	Bookmarks = 0x22a,
 }
 
+/// Consumer page (media) usage code.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsumerCode {
+    WellKnown(MediaCode),
+    Custom(u16),
+}
+
+impl Display for ConsumerCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConsumerCode::WellKnown(code) => write!(f, "{}", code),
+            ConsumerCode::Custom(code) => write!(f, "media<{:#x}>", code),
+        }
+    }
+}
+
+impl From<MediaCode> for ConsumerCode {
+    fn from(code: MediaCode) -> Self {
+        Self::WellKnown(code)
+    }
+}
+
+impl ConsumerCode {
+    pub fn value(self) -> u16 {
+        match self {
+            Self::WellKnown(code) => code as u16,
+            Self::Custom(code) => code,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DeserializeFromStr)]
 pub enum Code {
     WellKnown(WellKnownCode),
     Custom(u8),
@@ -140,7 +432,7 @@ impl From<WellKnownCode> for Code {
 }
 
 impl FromStr for Code {
-    type Err = nom::error::Error<String>;
+    type Err = parse::Error;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         parse::from_str(parse::code, s)
@@ -296,7 +588,7 @@ impl From<(Modifiers, Option<Code>)> for Accord {
 }
 
 impl FromStr for Accord {
-    type Err = nom::error::Error<String>;
+    type Err = parse::Error;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         parse::from_str(parse::accord, s)
@@ -318,6 +610,7 @@ impl Display for Accord {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
 #[strum(ascii_case_insensitive)]
+#[strum(serialize_all="lowercase")]
 #[repr(u8)]
 pub enum MouseModifier {
     Ctrl = 0x01,
@@ -371,11 +664,12 @@ impl Display for MouseEvent {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr)]
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, AsRefStr)]
+#[strum(serialize_all="lowercase")]
 pub enum Macro {
     Keyboard(Vec<Accord>),
     #[allow(unused)]
-    Media(MediaCode),
+    Media(ConsumerCode),
     #[allow(unused)]
     Mouse(MouseEvent),
 }
@@ -388,10 +682,34 @@ impl Macro {
             Macro::Mouse(_) => 3,
         }
     }
+
+    /// Number of steps macro takes in keyboard's macro sequence: one per chord,
+    /// plus one per modifier on keyboards where modifiers take separate steps.
+    pub fn steps(&self, modifier_steps: bool) -> usize {
+        match self {
+            Macro::Keyboard(accords) => accords.iter()
+                .map(|accord| 1 + if modifier_steps { accord.modifiers.len() } else { 0 })
+                .sum(),
+            _ => 1,
+        }
+    }
+
+    /// Modifiers of macro consisting of single modifier-only accord, like `ctrl`.
+    /// Such macro holds modifiers while key is pressed instead of typing
+    /// them, so it may be combined with other keys.
+    pub fn held_modifiers(&self) -> Option<Modifiers> {
+        match self {
+            Macro::Keyboard(accords) => match accords.as_slice() {
+                [Accord { modifiers, code: None }] => Some(*modifiers),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 }
 
 impl FromStr for Macro {
-    type Err = nom::error::Error<String>;
+    type Err = parse::Error;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         parse::from_str(parse::r#macro, s)
@@ -413,3 +731,77 @@ impl Display for Macro {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packets() {
+        assert_eq!(packets(&[], DEFAULT_PACKET_SIZE), vec![vec![0; 64]]);
+        assert_eq!(packets(&[1; 64], DEFAULT_PACKET_SIZE), vec![vec![1; 64]]);
+        assert_eq!(packets(&[1; 65], DEFAULT_PACKET_SIZE), vec![vec![1; 64], [vec![1], vec![0; 63]].concat()]);
+
+        // Short report is padded to full length for endpoint with small packets.
+        let split = packets(&[3, 1, 2], 8);
+        assert_eq!(split.len(), 8);
+        assert!(split.iter().all(|packet| packet.len() == 8));
+        assert_eq!(split.concat(), [vec![3, 1, 2], vec![0; 61]].concat());
+    }
+
+    #[test]
+    fn test_key_ids() -> anyhow::Result<()> {
+        let standard = Model::K884x.key_id_layout(Some(Geometry { buttons: 12, knobs: 3 }));
+        assert_eq!(Key::Button(11).to_key_id(standard)?, 12);
+        assert_eq!(Key::Knob(0, KnobAction::RotateCCW).to_key_id(standard)?, 16);
+        assert_eq!(Key::Knob(2, KnobAction::RotateCW).to_key_id(standard)?, 24);
+
+        let mini = Model::K884x.key_id_layout(Some(Geometry { buttons: 6, knobs: 1 }));
+        assert_eq!(Key::Button(5).to_key_id(mini)?, 6);
+        assert_eq!(Key::Knob(0, KnobAction::RotateCCW).to_key_id(mini)?, 7);
+        assert_eq!(Key::Knob(0, KnobAction::RotateCW).to_key_id(mini)?, 9);
+        assert!(Key::Button(6).to_key_id(mini).is_err());
+        assert!(Key::Knob(1, KnobAction::Press).to_key_id(mini).is_err());
+
+        let four_knobs = Model::K884x.key_id_layout(Some("12+4".parse().unwrap()));
+        assert_eq!(Key::Knob(2, KnobAction::RotateCW).to_key_id(four_knobs)?, 24);
+        assert_eq!(Key::Knob(3, KnobAction::RotateCCW).to_key_id(four_knobs)?, 13);
+        assert_eq!(Key::Knob(3, KnobAction::RotateCW).to_key_id(four_knobs)?, 15);
+        assert!(Key::Button(12).to_key_id(four_knobs).is_err());
+
+        let k8890 = Model::K8890.key_id_layout(Some(Geometry { buttons: 6, knobs: 1 }));
+        assert_eq!(Key::Knob(0, KnobAction::RotateCCW).to_key_id(k8890)?, 13);
+        Ok(())
+    }
+
+    #[test]
+    fn test_modifier_only_macro() -> anyhow::Result<()> {
+        let held: Macro = "ctrl-shift".parse()?;
+        assert_eq!(held.held_modifiers(), Some(Modifier::Ctrl | Modifier::Shift));
+        assert_eq!("ctrl-c".parse::<Macro>()?.held_modifiers(), None);
+        assert_eq!("ctrl,a".parse::<Macro>()?.held_modifiers(), None);
+
+        let key = Key::Button(0);
+        let layout = Model::K884x.key_id_layout(None);
+        let messages = Model::K884x.bind_key_messages(layout, 0, key, &held)?;
+        assert_eq!(messages[0][9..12], [0, 0x03, 0]);
+
+        let layout = Model::K8890.key_id_layout(None);
+        let err = Model::K8890.bind_key_messages(layout, 0, key, &held).unwrap_err();
+        assert!(err.to_string().contains("modifier-only"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_macro_steps() -> anyhow::Result<()> {
+        let macro_: Macro = "ctrl-shift-t,a,alt-b".parse()?;
+        assert_eq!(macro_.steps(false), 3);
+        assert_eq!(macro_.steps(true), 6);
+        assert_eq!("volumeup".parse::<Macro>()?.steps(true), 1);
+
+        let layout = Model::K8890.key_id_layout(None);
+        let err = Model::K8890.bind_key_messages(layout, 0, Key::Button(0), &"a,b,c,d,e,f".parse()?).unwrap_err();
+        assert_eq!(err.to_string(), "macro sequence of 6 chords is too long, keyboard accepts 5 at most");
+        Ok(())
+    }
+}
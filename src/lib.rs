@@ -0,0 +1,45 @@
+//! Library for programming ch57x-based macro keyboards.
+//!
+//! Typical usage is to load and render config, find keyboard and upload it:
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use ch57x_keyboard::config::Config;
+//! use ch57x_keyboard::device::{Device, DeviceFilter, EndpointOptions};
+//! use ch57x_keyboard::keyboard::{Key, Keyboard as _};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let config = Config::load(Path::new("example-mapping.yaml"))?;
+//! let geometry = config.geometry();
+//! let layers = config.render()?;
+//!
+//! // Geometry selects key numbering for keyboard variant.
+//! let mut keyboard = Device::find(&DeviceFilter::default())?
+//!     .open(&EndpointOptions::default(), Some(geometry))?;
+//! keyboard.upload(&layers)?;
+//!
+//! // Single keys may be bound too.
+//! keyboard.bind_key(0, Key::Button(0), &"ctrl-c".parse()?)?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod backup;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod config;
+pub mod consts;
+pub mod device;
+pub mod exit;
+pub mod hid;
+pub mod keyboard;
+pub mod lint;
+pub mod monitor;
+pub mod parse;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod quirks;
+pub mod secrets;
+pub mod state;
+pub mod traffic;
@@ -0,0 +1,105 @@
+//! Checks for configs which are valid, but likely don't do what user wants.
+
+use std::fmt::Display;
+
+use itertools::Itertools as _;
+
+use crate::config::FlatLayer;
+use crate::consts::LAYER_COUNT;
+use crate::keyboard::{Accord, Key, Macro, Modifier, Modifiers, WellKnownCode};
+use crate::secrets::SecretNames;
+
+/// Longest sequence accepted by all keyboards, 8890 ones don't take more.
+const PORTABLE_SEQUENCE_LENGTH: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub layer: usize,
+    /// Key warning is about, `None` if it is about whole layer.
+    pub key: Option<Key>,
+    pub message: String,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "layer {}, {}: {}", self.layer, key, self.message),
+            None => write!(f, "layer {}: {}", self.layer, self.message),
+        }
+    }
+}
+
+/// Lints rendered layers, warnings are returned in layer order.
+/// Macros typing secrets are shown by secret names.
+pub fn lint(layers: &[FlatLayer], secrets: &SecretNames) -> Vec<Warning> {
+    let mut warnings = vec![];
+    for (layer_idx, layer) in layers.iter().enumerate() {
+        if layer_idx >= LAYER_COUNT as usize {
+            warnings.push(Warning {
+                layer: layer_idx,
+                key: None,
+                message: format!("can't be selected, keyboards have {LAYER_COUNT} layers"),
+            });
+        }
+
+        let bindings = layer.bindings().collect_vec();
+        for (i, (key, macro_)) in bindings.iter().enumerate() {
+            let mut warn = |message: String| warnings.push(Warning { layer: layer_idx, key: Some(*key), message });
+
+            if let Macro::Keyboard(accords) = macro_ {
+                if accords.len() > PORTABLE_SEQUENCE_LENGTH {
+                    warn(format!(
+                        "sequence of {} chords is too long for 8890 keyboards, they accept at most {PORTABLE_SEQUENCE_LENGTH}",
+                        accords.len(),
+                    ));
+                }
+                if let Some(lock) = accords.iter().rev().skip(1).find(|a| is_screen_lock(a)) {
+                    warn(format!("'{lock}' locks screen, so the rest of sequence is typed into lock screen"));
+                }
+            }
+
+            // Knob press often duplicates some button on purpose, so only buttons are compared.
+            let same = bindings[..i].iter().find(|(other, m)| m == macro_ && matches!(other, Key::Button(_)));
+            if let (Key::Button(_), Some((other, _))) = (key, same) {
+                warn(format!("'{}' is already bound to {other}", secrets.show(macro_)));
+            }
+        }
+    }
+    warnings
+}
+
+/// Whether chord is default screen lock shortcut on Windows or macOS.
+fn is_screen_lock(accord: &Accord) -> bool {
+    let lock = |modifiers: Modifiers, code: WellKnownCode| accord.modifiers == modifiers && accord.code == Some(code.into());
+    lock(Modifier::Win.into(), WellKnownCode::L) || lock(Modifier::Ctrl | Modifier::Win, WellKnownCode::Q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Knob;
+
+    fn layer(buttons: &[Option<&str>]) -> FlatLayer {
+        FlatLayer {
+            buttons: buttons.iter().map(|m| m.map(|m| m.parse().unwrap())).collect(),
+            knobs: vec![Knob { ccw: None, press: None, cw: None }],
+        }
+    }
+
+    #[test]
+    fn test_lint() {
+        let layers = vec![
+            layer(&[Some("a,b,c,d,e,f"), Some("win-l,b"), Some("win-l")]),
+            layer(&[Some("ctrl-c"), None, Some("ctrl-c")]),
+            layer(&[None, None, None]),
+            layer(&[None, None, None]),
+        ];
+        let warnings = lint(&layers, &SecretNames::default()).iter().map(|w| w.to_string()).collect_vec();
+        assert_eq!(warnings, vec![
+            "layer 0, button 0: sequence of 6 chords is too long for 8890 keyboards, they accept at most 5",
+            "layer 0, button 1: 'cmd-l' locks screen, so the rest of sequence is typed into lock screen",
+            "layer 1, button 2: 'ctrl-c' is already bound to button 0",
+            "layer 3: can't be selected, keyboards have 3 layers",
+        ]);
+    }
+}
@@ -1,61 +1,137 @@
-mod config;
-mod consts;
-mod keyboard;
+mod cheatsheet;
+mod daemon;
+mod edit;
+mod fetch;
+mod i18n;
+mod init;
 mod options;
-mod parse;
+mod progress;
+mod replay;
+mod report;
+mod schema;
+mod script;
+mod serve;
+mod udev;
+mod upgrade;
 
-use std::io::{BufReader, Read, StdinLock};
+use std::ffi::OsStr;
+use std::cell::RefCell;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::config::Config;
-use crate::consts::PRODUCT_IDS;
-use crate::keyboard::{
-    k884x, k8890, Keyboard, KnobAction, MediaCode, Modifier, MouseAction, MouseButton,
+use ch57x_keyboard::backup;
+use ch57x_keyboard::config::{self, Config, ConfigFormat, DeviceChoice, DeviceConfig, DeviceSelector, FlatLayer, Hooks, LoadOptions, Orientation, TargetOs};
+use ch57x_keyboard::device::{Device, DeviceFilter, EndpointOptions, Session};
+use ch57x_keyboard::hid::DEFAULT_REPORT_ID;
+use ch57x_keyboard::lint;
+use ch57x_keyboard::monitor;
+use ch57x_keyboard::quirks::Quirks;
+use ch57x_keyboard::secrets::{Keychain, SecretNames};
+use ch57x_keyboard::traffic;
+use ch57x_keyboard::state::{self, Bindings, Change, DeviceState, WRITE_COUNT_WARNING};
+use ch57x_keyboard::keyboard::{
+    Accord, Geometry, Key, Keyboard, KnobAction, Macro, MediaCode, Model, Modifier, Modifiers, MouseAction, MouseButton, TransferOptions,
     WellKnownCode,
 };
-use crate::options::{Command, LedCommand};
-use crate::{keyboard::Key, options::Options};
+use ch57x_keyboard::keyboard::mock::MockDevice;
+use crate::options::{
+    Backend, BindCommand, CheatsheetCommand, Command, LayerAction, LayerCommand, LayerCopyCommand, LayerSwapCommand, ConfigParams, DaemonCommand, EncodeCommand, InitCommand, KnobSelector, LedCommand, Options, OrientCommand, OutputFormat, RenderCommand,
+    ReplayCommand, ReportCommand, RestoreCommand, RunCommand, ServeCommand, SetupPermissionsCommand, TestmapCommand, UploadCommand, ValidateCommand,
+};
+use ch57x_keyboard::exit::{ConfigError, ExitCode};
+use crate::i18n::{Lang, Message};
+use crate::progress::{Progress, Verbosity};
 
-use anyhow::{anyhow, ensure, Result};
-use indoc::indoc;
+use anyhow::{anyhow, bail, ensure, Result};
 use itertools::Itertools;
-use log::debug;
-use options::{ConfigParams, DevelOptions};
-use rusb::{Context, Device, DeviceDescriptor, TransferType};
+use log::LevelFilter;
 
 use anyhow::Context as _;
 use clap::Parser as _;
-use rusb::UsbContext as _;
 use strum::EnumMessage as _;
 use strum::IntoEnumIterator as _;
 
-fn main() -> Result<()> {
-    env_logger::init();
-    let options = Options::parse();
+/// How often config file is checked for changes in watch mode.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Set by `--quiet`, human-readable messages aren't printed then.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Device session kept while script runs, so that all its commands program device
+    /// through the same handle: `None` if no script runs, `Some(None)` until device
+    /// is opened for the first time.
+    static SESSION: RefCell<Option<Option<Session>>> = const { RefCell::new(None) };
+}
+
+/// Prints human-readable message, unless `--quiet` is given.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Hides human-readable messages and progress, also used by commands running unattended.
+fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+fn main() {
+    let options = Options::try_parse().unwrap_or_else(|err| {
+        // Clap exits with 2 for invalid command line, which is config error code here.
+        if err.use_stderr() {
+            let _ = err.print();
+            std::process::exit(ExitCode::Usage as i32);
+        }
+        err.exit()
+    });
+    set_quiet(options.quiet);
+    init_logger(options.log_format);
+    if let Err(err) = run(&options) {
+        eprintln!("Error: {err:?}");
+        if let Some(hint) = i18n::hint(options.lang.unwrap_or_else(Lang::from_env), &err).filter(|_| !options.quiet) {
+            eprintln!();
+            eprintln!("{hint}");
+        }
+        std::process::exit(ExitCode::of(&err) as i32);
+    }
+}
 
-    match options.command {
+fn run(options: &Options) -> Result<()> {
+    match &options.command {
         Command::ShowKeys => {
-            println!("Modifiers: ");
+            let lang = options.lang.unwrap_or_else(Lang::from_env);
+            let heading = |message| i18n::tr(lang, message);
+
+            println!("{}: ", heading(Message::Modifiers));
             for m in Modifier::iter() {
                 println!(" - {}", m.get_serializations().iter().join(" / "));
             }
 
             println!();
-            println!("Keys:");
+            println!("{}:", heading(Message::Keys));
             for c in WellKnownCode::iter() {
                 println!(" - {c}");
             }
 
             println!();
-            println!("Custom key syntax (use decimal code): <110>");
+            println!("{}: <110>", heading(Message::CustomKeySyntax));
 
             println!();
-            println!("Media keys:");
+            println!("{}:", heading(Message::MediaKeys));
             for c in MediaCode::iter() {
                 println!(" - {}", c.get_serializations().iter().join(" / "));
             }
 
             println!();
-            println!("Mouse actions:");
+            println!("{}: media<0x223>", heading(Message::CustomMediaSyntax));
+
+            println!();
+            println!("{}:", heading(Message::MouseActions));
             println!(" - {}", MouseAction::WheelDown);
             println!(" - {}", MouseAction::WheelUp);
             for b in MouseButton::iter() {
@@ -63,236 +139,1281 @@ fn main() -> Result<()> {
             }
         }
 
-        Command::Validate(params) => {
-            let config: Config = load_config(&params)
-                .context("load mapping config")?;
-            let _ = config.render().context("render mappings config")?;
-            println!("config is valid 👌")
+        Command::Validate(ValidateCommand { config_params, output, strict, on_success }) => {
+            let result = lint_configs(config_params);
+            match output {
+                OutputFormat::Text => {
+                    let (warnings, hooks) = result?;
+                    for LocatedWarning { device, warning, .. } in &warnings {
+                        match device {
+                            Some(device) => eprintln!("warning: device {device}, {warning}"),
+                            None => eprintln!("warning: {warning}"),
+                        }
+                    }
+                    if *strict && !warnings.is_empty() {
+                        return Err(anyhow!("config has {} warnings, they are errors in strict mode", warnings.len())
+                            .context(ConfigError("validate config")));
+                    }
+                    say!("config is valid 👌");
+                    run_hooks(config_params, &hooks, on_success.as_deref(), &[("CH57X_WARNINGS", warnings.len().to_string())])?;
+                }
+                OutputFormat::Json => {
+                    let (failure, diagnostics, hooks) = match result {
+                        Ok((warnings, hooks)) => {
+                            let failure = (*strict && !warnings.is_empty()).then_some(ExitCode::Config);
+                            (failure, warnings.iter().map(LocatedWarning::to_json).collect_vec(), hooks)
+                        }
+                        Err(err) => (Some(ExitCode::of(&err)), vec![serde_json::json!({ "severity": "error", "message": format!("{err:#}") })], vec![]),
+                    };
+                    let report = serde_json::json!({ "valid": failure.is_none(), "diagnostics": diagnostics });
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                    if let Some(code) = failure {
+                        std::process::exit(code as i32);
+                    }
+                    run_hooks(config_params, &hooks, on_success.as_deref(), &[("CH57X_WARNINGS", diagnostics.len().to_string())])?;
+                }
+            }
         }
 
-        Command::Upload(params) => {
-            let config: Config = load_config(&params)
-                .context("load mapping config")?;
-            let layers = config.render().context("render mapping config")?;
+        Command::Upload(command @ UploadCommand { config_params, watch: false, .. }) => {
+            let targets = load_targets(options, config_params)?;
+            for (device, rendered) in &targets {
+                if targets.len() > 1 {
+                    say!("{}:", describe_device(device));
+                }
+                let uploaded = upload(options, device, rendered, command)?;
+                match &uploaded {
+                    Uploaded::UpToDate => say!("device already up to date 👌"),
+                    Uploaded::Bindings { count, changes: Some(changes) } => {
+                        say!("uploaded {count} bindings to {} layers 👌", rendered.layers.len());
+                        print_changes(changes);
+                    }
+                    Uploaded::Bindings { changes: None, .. } => {}
+                }
+                run_upload_hooks(command, device, rendered, &uploaded)?;
+            }
+        }
 
-            let mut keyboard = open_keyboard(&options.devel_options)?;
+        Command::Upload(command @ UploadCommand { config_params, watch: true, .. }) => {
+            let path = config_params.config_path.as_ref()
+                .ok_or_else(|| anyhow!("config file path is required to watch it"))?;
+            ensure!(!path.to_str().is_some_and(fetch::is_url), "config downloaded from URL can't be watched");
 
-            // Apply keyboard mapping.
-            for (layer_idx, layer) in layers.iter().enumerate() {
-                for (button_idx, macro_) in layer.buttons.iter().enumerate() {
-                    if let Some(macro_) = macro_ {
-                        keyboard.bind_key(layer_idx as u8, Key::Button(button_idx as u8), macro_)
-                            .context("bind key")?;
+            let mut last_modified = None;
+            loop {
+                let modified = Some(modification_times(Path::new(path)));
+                if modified != last_modified {
+                    last_modified = modified;
+                    match load_targets(options, config_params) {
+                        Ok(targets) => for (device, rendered) in &targets {
+                            if targets.len() > 1 {
+                                say!("{}:", describe_device(device));
+                            }
+                            let uploaded = upload(options, device, rendered, command);
+                            match &uploaded {
+                                Ok(Uploaded::UpToDate) => say!("device already up to date 👌"),
+                                Ok(Uploaded::Bindings { count, changes }) => {
+                                    say!("uploaded {count} bindings to {} layers 👌", rendered.layers.len());
+                                    if let Some(changes) = changes {
+                                        print_changes(changes);
+                                    }
+                                }
+                                Err(err) => eprintln!("upload failed: {err:#}"),
+                            }
+                            if let Ok(uploaded) = uploaded {
+                                if let Err(err) = run_upload_hooks(command, device, rendered, &uploaded) {
+                                    eprintln!("warning: {err:#}");
+                                }
+                            }
+                        },
+                        Err(err) => eprintln!("config is invalid, not uploaded: {err:#}"),
                     }
                 }
+                std::thread::sleep(WATCH_INTERVAL);
+            }
+        }
 
-                for (knob_idx, knob) in layer.knobs.iter().enumerate() {
-                    if let Some(macro_) = &knob.ccw {
-                        keyboard.bind_key(layer_idx as u8, Key::Knob(knob_idx as u8, KnobAction::RotateCCW), macro_)?;
+        Command::Status => {
+            let device = find_device(options)?;
+            let state = load_state(&device);
+            println!("device:      {}", describe_device(&device));
+            match &state.last_upload {
+                Some(upload) => {
+                    let source = upload.source.as_deref().unwrap_or("stdin");
+                    println!("last upload: {} UTC from {source}", backup::format_timestamp(upload.time));
+                    // Backups have OS-specific macros resolved already.
+                    let uploaded = Config::from_reader(upload.config.as_bytes(), ConfigFormat::Yaml, None)
+                        .and_then(Config::render)
+                        .map(|layers| Bindings::from_layers(&layers));
+                    if uploaded.ok() != state.bindings {
+                        println!("             some keys were bound separately after it");
                     }
-                    if let Some(macro_) = &knob.press {
-                        keyboard.bind_key(layer_idx as u8, Key::Knob(knob_idx as u8, KnobAction::Press), macro_)?;
+                }
+                None => println!("last upload: unknown"),
+            }
+            println!("writes:      {}", state.write_count);
+        }
+
+        Command::Reapply => {
+            let device = find_device(options)?;
+            let last_upload = load_state(&device).last_upload
+                .ok_or_else(|| anyhow!("no config is known to be uploaded to this device"))?;
+            let config = Config::from_reader(last_upload.config.as_bytes(), ConfigFormat::Yaml, None)
+                .context("load last uploaded config")?;
+            let rendered = render(config, TargetOs::host()).context("render last uploaded config")?;
+
+            // Device state can't be checked, so config is uploaded even if it looks up to date.
+            let command = UploadCommand {
+                force: true,
+                ..UploadCommand::defaults(ConfigParams {
+                    config_path: last_upload.source.map(Into::into), format: None, allow_env: false, allow_secrets: false, sha256: None,
+                    target_os: TargetOs::host(), device: None,
+                })
+            };
+            if let Uploaded::Bindings { count, .. } = upload(options, &device, &rendered, &command)? {
+                say!("uploaded {count} bindings to {} layers again 👌", rendered.layers.len());
+            }
+        }
+
+        Command::Restore(RestoreCommand { backup: None }) => {
+            let device = find_device(options)?;
+            let backups = backup::list(&device)?;
+            if backups.is_empty() {
+                println!("there are no backups for this device");
+            }
+            for timestamp in backups {
+                println!("{timestamp}");
+            }
+        }
+
+        Command::Restore(RestoreCommand { backup: Some(timestamp) }) => {
+            let device = find_device(options)?;
+            let path = backup::path(&device, timestamp)?;
+            let config = Config::load(&path).context("load backup")?;
+            // Backups have OS-specific macros resolved already.
+            let rendered = render(config, TargetOs::host()).context("render backup")?;
+
+            let command = UploadCommand {
+                what_changed: true,
+                ..UploadCommand::defaults(ConfigParams {
+                    config_path: Some(path.into()), format: None, allow_env: false, allow_secrets: false, sha256: None,
+                    target_os: TargetOs::host(), device: None,
+                })
+            };
+            match upload(options, &device, &rendered, &command)? {
+                Uploaded::UpToDate => say!("device already has this backup 👌"),
+                Uploaded::Bindings { count, changes } => {
+                    say!("restored {count} bindings to {} layers 👌", rendered.layers.len());
+                    if let Some(changes) = changes {
+                        print_changes(&changes);
                     }
-                    if let Some(macro_) = &knob.cw {
-                        keyboard.bind_key(layer_idx as u8, Key::Knob(knob_idx as u8, KnobAction::RotateCW), macro_)?;
+                }
+            }
+        }
+
+        Command::Render(RenderCommand { config_params, output, model }) => {
+            let config: Config = load_config(config_params)
+                .context(ConfigError("load mapping config"))?;
+            let orientations = config.layers.iter()
+                .map(|layer| layer.orientation.unwrap_or(config.orientation))
+                .collect_vec();
+            let knob_count = config.knobs as usize;
+            let key_ids = model.map(|model| model.key_id_layout(Some(config.geometry())));
+            let secrets = config.secrets.clone();
+            let layers = config.render_for(config_params.target_os).context(ConfigError("render mapping config"))?;
+
+            // Physical knob description, since knob indices depend on orientation.
+            let position = |layer_idx: usize, key: Key| match key {
+                Key::Knob(k, _) => {
+                    let orientation = orientations[layer_idx];
+                    Some(orientation.knob_label(orientation.knob_index(k as usize, knob_count), knob_count))
+                }
+                Key::Button(_) => None,
+            };
+
+            let mut bindings = vec![];
+            for (layer_idx, layer) in layers.iter().enumerate() {
+                for (key, macro_) in layer.bindings() {
+                    let encoded = model.zip(key_ids).map(|(model, key_ids)| -> Result<_> {
+                        let key_id = key.to_key_id(key_ids)?;
+                        let mut messages = model.bind_key_messages(key_ids, layer_idx as u8, key, macro_)?;
+                        // Messages would reveal secret text.
+                        if secrets.name(macro_).is_some() {
+                            messages.clear();
+                        }
+                        // Show messages as they are sent to default report.
+                        let messages = messages
+                            .into_iter()
+                            .map(|msg| [&[DEFAULT_REPORT_ID], msg.as_slice()].concat())
+                            .collect_vec();
+                        Ok((key_id, messages))
+                    }).transpose().with_context(|| match position(layer_idx, key) {
+                        Some(position) => format!("encode {key} ({position}) on layer {layer_idx}"),
+                        None => format!("encode {key} on layer {layer_idx}"),
+                    })?;
+                    bindings.push((layer_idx, key, macro_, encoded));
+                }
+            }
+
+            match output {
+                OutputFormat::Text => {
+                    for (layer_idx, layer_bindings) in &bindings.iter().group_by(|(layer_idx, ..)| *layer_idx) {
+                        println!("Layer {layer_idx}:");
+                        for (_, key, macro_, encoded) in layer_bindings {
+                            let details = position(layer_idx, *key).into_iter()
+                                .chain(encoded.iter().map(|(key_id, _)| format!("key ID {key_id}")))
+                                .collect_vec();
+                            let macro_ = secrets.show(macro_);
+                            if details.is_empty() {
+                                println!("  {key}: {macro_}");
+                            } else {
+                                println!("  {key} ({}): {macro_}", details.join(", "));
+                            }
+                            for msg in encoded.iter().flat_map(|(_, messages)| messages) {
+                                println!("    {:02x}", msg.iter().format(" "));
+                            }
+                        }
                     }
                 }
+                OutputFormat::Json => {
+                    let bindings = bindings.iter().map(|(layer_idx, key, macro_, encoded)| {
+                        let mut binding = serde_json::json!({
+                            "layer": layer_idx,
+                            "key": key.to_string(),
+                            "type": macro_.as_ref(),
+                            "macro": secrets.show(macro_),
+                        });
+                        if let Some(position) = position(*layer_idx, *key) {
+                            binding["position"] = position.into();
+                        }
+                        if let Some((key_id, messages)) = encoded {
+                            binding["key_id"] = (*key_id).into();
+                            binding["messages"] = messages.iter()
+                                .map(|msg| format!("{:02x}", msg.iter().format("")))
+                                .collect();
+                        }
+                        binding
+                    }).collect::<Vec<_>>();
+                    println!("{}", serde_json::to_string_pretty(&bindings)?);
+                }
             }
         }
 
+        Command::Cheatsheet(CheatsheetCommand { config_params, output }) => {
+            let config: Config = load_config(config_params)
+                .context(ConfigError("load mapping config"))?;
+            // Render just to check config, so that sheet isn't printed for broken one.
+            config.clone().render_for(config_params.target_os).context(ConfigError("render mapping config"))?;
+            print!("{}", cheatsheet::render(&config, config_params.target_os, *output));
+        }
+
+        Command::Layer(LayerCommand { action }) => {
+            // Keyboards can't be read back, so only config may be edited.
+            let path = match action {
+                LayerAction::Copy(LayerCopyCommand { config_path, .. }) => config_path,
+                LayerAction::Swap(LayerSwapCommand { config_path, .. }) => config_path,
+            };
+            let text = std::fs::read_to_string(path).context("read config file")?;
+            let edited = match action {
+                LayerAction::Copy(LayerCopyCommand { from, to, .. }) =>
+                    edit::copy_layer(&text, *from as usize - 1, *to as usize - 1)?,
+                LayerAction::Swap(LayerSwapCommand { first, second, .. }) =>
+                    edit::swap_layers(&text, *first as usize - 1, *second as usize - 1)?,
+            };
+            std::fs::write(path, edited).context("write config file")?;
+        }
+
         Command::Led(LedCommand { index }) => {
-            let mut keyboard = open_keyboard(&options.devel_options)?;
-            keyboard.set_led(index)?;
+            set_led(options, &find_device(options)?, *index)?;
+        }
+
+        Command::Serve(ServeCommand { listen }) => {
+            serve::serve(options, listen)?;
+        }
+
+        Command::Run(RunCommand { script_path }) => {
+            script::run(options, Path::new(script_path))?;
+        }
+
+        Command::Daemon(DaemonCommand { profiles_path }) => {
+            daemon::run(options, Path::new(profiles_path))?;
+        }
+
+        Command::Schema => {
+            println!("{}", serde_json::to_string_pretty(&schema::schema())?);
+        }
+
+        Command::Probe => {
+            let device = find_device(options)?;
+            let key = device.key();
+            let (bus, address) = device.address();
+            println!("device:        {:04x}:{:04x} at {bus}:{address} (port {})", key.vendor_id, key.product_id, device.location());
+            // Keyboards don't answer any known version request, so release number
+            // from device descriptor is the only firmware revision available.
+            println!("firmware:      {} (bcdDevice {:#06x})", key.release(), key.bcd_device);
+            println!("interfaces:    {}", key.interfaces);
+            match device.capabilities() {
+                Some(caps) => {
+                    println!("model:         {}", caps.model);
+                    if let Some(endpoint) = caps.endpoint {
+                        println!("endpoint:      {endpoint:#04x}");
+                    }
+                    if let Some(max) = caps.max_macro_length {
+                        let modifiers = if caps.modifier_steps { ", modifiers take steps too" } else { "" };
+                        println!("max macro:     {max} steps{modifiers}");
+                    }
+                    println!("LED:           {}", if caps.led { "supported" } else { "not supported" });
+                }
+                None => println!("model:         unknown, add it to quirks.toml"),
+            }
+        }
+
+        Command::Report(ReportCommand { log, lines }) => {
+            print!("{}", report::build(options, log.as_deref().map(Path::new), *lines)?);
+        }
+
+        Command::SetupPermissions(SetupPermissionsCommand { print }) => {
+            let rule = udev::rule(&Quirks::load()?.device_ids());
+            if *print {
+                print!("{rule}");
+            } else {
+                ensure!(cfg!(target_os = "linux"), "udev rules are only used on Linux");
+                udev::install(&rule)?;
+                say!("installed {}", udev::RULE_PATH);
+
+                let devices = enumerate_devices(options, &device_filter(options))?;
+                if devices.is_empty() {
+                    say!("connect keyboard to check its permissions");
+                }
+                for device in devices {
+                    let node = udev::device_node(device.address());
+                    match std::fs::OpenOptions::new().write(true).open(&node) {
+                        Ok(_) => say!("{} is writable 👌", node.display()),
+                        Err(err) => println!("{} is still not writable: {err}, try to reconnect keyboard or log in again", node.display()),
+                    }
+                }
+            }
+        }
+
+        Command::Monitor => {
+            let device = find_device(options)?;
+            let state = load_state(&device);
+            if state.bindings.is_none() {
+                println!("bindings uploaded to this device are unknown, keys won't be matched to them");
+            }
+
+            let mut input = device.open_input()?;
+            println!("press keys on keyboard, Ctrl-C to stop");
+            println!("(key presses don't reach other applications while monitoring)");
+            loop {
+                let (protocol, report) = input.read()?;
+                let event = monitor::decode(protocol, &report);
+                if event.is_release() {
+                    continue;
+                }
+
+                // Layer is not reported, so show matching bindings from all layers.
+                let event = event.to_string();
+                let bound = state.bindings.iter()
+                    .flat_map(|bindings| bindings.find_by_first_chord(&event))
+                    .map(|(layer, binding)| format!("layer {layer}, {}: {}", binding.key, binding.macro_))
+                    .collect_vec();
+                if bound.is_empty() {
+                    println!("{event}");
+                } else {
+                    println!("{event}  ← {}", bound.join("; "));
+                }
+            }
+        }
+
+        Command::Orient(OrientCommand { config_path, device }) => {
+            let config = load_config_at(config_path, device.as_ref())?;
+            let (rows, cols) = (config.rows as usize, config.columns as usize);
+            ensure!(rows > 0 && cols > 0, "keyboard has no buttons");
+
+            // Any orientation has one of these buttons in top left corner.
+            let corners = [0, cols - 1, (rows - 1) * cols, rows * cols - 1].into_iter().unique().collect_vec();
+            let letters = [WellKnownCode::A, WellKnownCode::B, WellKnownCode::C, WellKnownCode::D];
+
+            let device = find_device(options)?;
+            let mut keyboard = open_device(options, &device, Some(config.geometry()))?;
+            let mut state = load_state(&device);
+            let temporary = corners.iter().zip(letters)
+                .map(|(&button, letter)| (Key::Button(button as u8), Macro::Keyboard(vec![Accord::new(Modifiers::empty(), Some(letter.into()))])))
+                .collect_vec();
+            if let Err(err) = bind_temporary(keyboard.as_mut(), &mut state, 0, &temporary) {
+                record_write(&device, state);
+                return Err(err);
+            }
+
+            let orientations = detect_orientation(rows, cols, &corners, &letters);
+
+            // Restore corner buttons, even if detection failed.
+            restore_bindings(keyboard.as_mut(), &mut state, 0, &temporary)?;
+            record_write(&device, state);
+
+            match orientations?.as_slice() {
+                [orientation] => println!("orientation: {orientation}"),
+                [] => bail!("pressed keys don't match any orientation"),
+                orientations => println!("any of orientations fits: {}", orientations.iter().format(", ")),
+            }
+        }
+
+        Command::Testmap(TestmapCommand { config_path, device, layer }) => {
+            let config = load_config_at(config_path, device.as_ref())?;
+            let (rows, cols, knobs) = (config.rows as usize, config.columns as usize, config.knobs as usize);
+            let orientation = config.layers.get(*layer as usize - 1)
+                .and_then(|l| l.orientation)
+                .unwrap_or(config.orientation);
+            let (_, ocols) = orientation.grid_size(rows, cols);
+
+            // Buttons type their number in config reading order, knobs type `k<number>`
+            // followed by action letter. Space separates presses.
+            let typing = |text: String| -> Result<Macro> {
+                Ok(text.chars().map(|c| c.to_string()).chain(["space".to_owned()]).join(",").parse()?)
+            };
+            let mut temporary = vec![];
+            for button in 0..rows * cols {
+                let (row, col) = orientation.button_position(rows, cols, button)
+                    .expect("every button has position");
+                temporary.push((Key::Button(button as u8), typing((row * ocols + col + 1).to_string())?));
+            }
+            for knob in 0..knobs {
+                let number = orientation.knob_index(knob, knobs) + 1;
+                for (action, letter) in [(KnobAction::RotateCCW, 'l'), (KnobAction::Press, 'p'), (KnobAction::RotateCW, 'r')] {
+                    temporary.push((Key::Knob(knob as u8, action), typing(format!("k{number}{letter}"))?));
+                }
+            }
+
+            let device = find_device(options)?;
+            let mut keyboard = open_device(options, &device, Some(config.geometry()))?;
+            let mut state = load_state(&device);
+            if let Err(err) = bind_temporary(keyboard.as_mut(), &mut state, layer - 1, &temporary) {
+                record_write(&device, state);
+                return Err(err);
+            }
+
+            println!("open text editor and press every key on layer {layer}:");
+            println!(" - buttons type their numbers, counting from top left one as seen in config,");
+            println!(" - knobs type 'k' with knob number, then 'l' for ccw, 'p' for press, 'r' for cw.");
+            print!("press Enter here when done ");
+            std::io::stdout().flush()?;
+            let result = std::io::stdin().read_line(&mut String::new()).context("wait for Enter");
+
+            // Restore bindings even if waiting failed.
+            restore_bindings(keyboard.as_mut(), &mut state, layer - 1, &temporary)?;
+            record_write(&device, state);
+            result?;
+        }
+
+        Command::Init(InitCommand { rows, columns, knobs }) => {
+            print!("{}", init::starter_config(*rows, *columns, *knobs));
+        }
+
+        Command::Replay(ReplayCommand { capture_path }) => {
+            let capture = std::fs::read_to_string(capture_path).context("read capture")?;
+            let reports = replay::parse_hex_lines(&capture).context("parse capture")?;
+
+            let device = find_device(options)?;
+            let mut keyboard = open_device(options, &device, None)?;
+            for (i, report) in reports.iter().enumerate() {
+                println!("{:02x}", report.iter().format(" "));
+                keyboard.send_report(report).with_context(|| format!("send report {}", i + 1))?;
+            }
+
+            // Whatever was programmed, remembered bindings are not valid anymore.
+            let mut state = load_state(&device);
+            state.bindings = None;
+            record_write(&device, state);
+        }
+
+        Command::Encode(EncodeCommand { model, variant, layer, button, knob, action, macro_ }) => {
+            let key = match (button, knob, action) {
+                (Some(button), _, _) => Key::Button(*button),
+                (None, Some(knob), Some(action)) => Key::Knob(*knob, *action),
+                _ => unreachable!("either button or knob with action is required by options"),
+            };
+            let key_ids = model.key_id_layout(*variant);
+            println!("{key} (key ID {}) on layer {layer}: {macro_}", key.to_key_id(key_ids)?);
+            // Show messages as they are sent to default report.
+            for msg in model.bind_key_messages(key_ids, layer - 1, key, macro_)? {
+                println!("  {:02x} {:02x}", DEFAULT_REPORT_ID, msg.iter().format(" "));
+            }
+        }
+
+        Command::UpgradeCheck => {
+            // Device is only used to filter changelog, so it's optional.
+            let model = find_device(options).ok().and_then(|device| device.model());
+
+            let releases = upgrade::fetch_releases()?;
+            let current = env!("CARGO_PKG_VERSION");
+            let newer = upgrade::newer_releases(&releases, current);
+            if newer.is_empty() {
+                say!("{current} is the latest version 👌");
+            }
+            for release in newer {
+                println!("{}: {}", release.tag_name, release.html_url);
+                if let (Some(model), Some(body)) = (model, &release.body) {
+                    for line in upgrade::relevant_lines(body, model) {
+                        println!("  {line}");
+                    }
+                }
+            }
+        }
+
+        Command::Bind(command @ BindCommand { layer, macro_, config, device, .. }) => {
+            let config = config.as_ref()
+                .map(|path| load_config_at(path, device.as_ref()))
+                .transpose()?;
+            let key = bound_key(command, config.as_ref())?;
+
+            let device = find_device(options)?;
+            let mut keyboard = open_device(options, &device, config.as_ref().map(Config::geometry))?;
+            keyboard.bind_key(layer - 1, key, macro_)
+                .with_context(|| format!("bind {key} on layer {layer}"))?;
+
+            // Keep remembered bindings in sync, if they are known.
+            let mut state = load_state(&device);
+            if let Some(bindings) = &mut state.bindings {
+                bindings.set((layer - 1) as usize, key.to_string(), macro_.to_string());
+            }
+            record_write(&device, state);
         }
     }
 
     Ok(())
 }
 
-fn find_interface_and_endpoint(
-    device: &Device<Context>,
-    interface_num: Option<u8>,
-    endpoint_addr: u8,
-) -> Result<(u8, u8)> {
-    let conf_desc = device
-        .config_descriptor(0)
-        .context("get config #0 descriptor")?;
+/// Key chosen by `bind` command options, config is needed to find knob by position.
+fn bound_key(command: &BindCommand, config: Option<&Config>) -> Result<Key> {
+    let BindCommand { layer, button, knob, action, .. } = command;
+    Ok(match (button, knob, action) {
+        (Some(button), _, _) => Key::Button(*button),
+        (None, Some(KnobSelector::Index(knob)), Some(action)) => Key::Knob(*knob, *action),
+        (None, Some(KnobSelector::Position(position)), Some(action)) => {
+            let config = config.ok_or_else(|| anyhow!("--config is required to find knob by position"))?;
+            let orientation = config.layers.get((layer - 1) as usize)
+                .and_then(|l| l.orientation)
+                .unwrap_or(config.orientation);
+            let count = config.knobs as usize;
+            let index = (0..count)
+                .find(|&k| orientation.knob_position(k, count) == Some(*position))
+                .ok_or_else(|| anyhow!("there is no {position} knob in {orientation} orientation"))?;
+            Key::Knob(orientation.knob_index(index, count) as u8, *action)
+        }
+        _ => unreachable!("either button or knob with action is required by options"),
+    })
+}
+
+/// Rendered config with geometry needed to upload it.
+struct Rendered {
+    layers: Vec<FlatLayer>,
+    columns: usize,
+    geometry: Geometry,
+    led: Option<u8>,
+    hooks: Hooks,
+    secrets: SecretNames,
+}
 
-    // Get the numbers of interfaces to explore
-    let interface_nums = match interface_num {
-        Some(iface_num) => vec![iface_num],
-        None => conf_desc.interfaces().map(|iface| iface.number()).collect(),
+/// Sets LED mode and remembers it. Mode is set for the first layer only,
+/// since messages setting it for other layers aren't known.
+fn set_led(options: &Options, device: &Device, index: u8) -> Result<()> {
+    let mut keyboard = open_device(options, device, None)?;
+    let mut state = load_state(device);
+    keyboard.set_led(0, index).context("set LED mode")?;
+    state.set_led(0, index);
+    record_write(device, state);
+    Ok(())
+}
+
+/// Loads and renders configs of all devices described by config and finds these devices.
+/// Nothing is uploaded if any config is invalid or any device isn't found.
+fn load_targets(options: &Options, params: &ConfigParams) -> Result<Vec<(Device, Rendered)>> {
+    let configs = load_device_configs(params).context(ConfigError("load mapping config"))?;
+    let count = configs.len();
+    let mut targets: Vec<(Device, Rendered)> = vec![];
+    for (i, DeviceConfig { selector, config }) in configs.into_iter().enumerate() {
+        let context = || if count > 1 { format!("device {i}") } else { "device".to_owned() };
+        let rendered = render(config, params.target_os).with_context(context)?;
+        let device = find_selected_device(options, &selector).with_context(context)?;
+        ensure!(!targets.iter().any(|(other, _)| other.address() == device.address()),
+                "device {i} is also selected by previous device config");
+        targets.push((device, rendered));
+    }
+    Ok(targets)
+}
+
+fn describe_device(device: &Device) -> String {
+    let (bus, address) = device.address();
+    format!("device {:04x}:{:04x} at {bus}:{address}", device.vendor_id(), device.product_id())
+}
+
+fn render(config: Config, os: TargetOs) -> Result<Rendered> {
+    let (columns, geometry, led, hooks) = (config.columns as usize, config.geometry(), config.led, config.hooks.clone());
+    let secrets = config.secrets.clone();
+    let layers = config.render_for(os).context(ConfigError("render mapping config"))?;
+    print_warnings(&layers, &secrets);
+    Ok(Rendered { layers, columns, geometry, led, hooks, secrets })
+}
+
+/// Lint warning with location of key in config.
+struct LocatedWarning {
+    /// Index of device in config describing several ones.
+    device: Option<usize>,
+    warning: lint::Warning,
+    /// Row and column of button as written in config.
+    position: Option<(usize, usize)>,
+}
+
+impl LocatedWarning {
+    fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "severity": "warning",
+            "layer": self.warning.layer,
+            "message": self.warning.message,
+        });
+        if let Some(device) = self.device {
+            json["device"] = device.into();
+        }
+        if let Some(key) = self.warning.key {
+            json["key"] = key.to_string().into();
+        }
+        if let Some((row, column)) = self.position {
+            json["row"] = row.into();
+            json["column"] = column.into();
+        }
+        json
+    }
+}
+
+/// Loads and lints configs of all devices described by config,
+/// validation hooks of all configs are returned too.
+fn lint_configs(params: &ConfigParams) -> Result<(Vec<LocatedWarning>, Vec<String>)> {
+    let configs = load_device_configs(params).context(ConfigError("load mapping config"))?;
+    let count = configs.len();
+    let mut located = vec![];
+    // Devices share hooks written at top level, so the same hook is run once.
+    let hooks = configs.iter().filter_map(|c| c.config.hooks.validate.clone()).unique().collect();
+    for (i, DeviceConfig { config, .. }) in configs.into_iter().enumerate() {
+        let (rows, cols) = (config.rows as usize, config.columns as usize);
+        let orientations = config.layers.iter()
+            .map(|layer| layer.orientation.unwrap_or(config.orientation))
+            .collect_vec();
+        let secrets = config.secrets.clone();
+        let layers = config.render_for(params.target_os)
+            .context(ConfigError("render mapping config"))
+            .with_context(|| format!("device {i}"))?;
+        for warning in lint::lint(&layers, &secrets) {
+            let position = match (warning.key, orientations.get(warning.layer)) {
+                (Some(Key::Button(button)), Some(orientation)) => orientation.button_position(rows, cols, button as usize),
+                _ => None,
+            };
+            located.push(LocatedWarning { device: (count > 1).then_some(i), warning, position });
+        }
+    }
+    Ok((located, hooks))
+}
+
+/// Runs hook commands from config and `--on-success` through shell, with summary in
+/// `CH57X_*` environment variables. Hooks of config downloaded from URL aren't run,
+/// since they are commands from remote server.
+fn run_hooks(params: &ConfigParams, from_config: &[String], on_success: Option<&str>, env: &[(&str, String)]) -> Result<()> {
+    let downloaded = params.config_path.as_ref().and_then(|path| path.to_str()).is_some_and(fetch::is_url);
+    let from_config = if downloaded && !from_config.is_empty() {
+        eprintln!("warning: hooks of config downloaded from URL aren't run");
+        &[]
+    } else {
+        from_config
+    };
+
+    for command in from_config.iter().map(String::as_str).chain(on_success) {
+        let mut shell = if cfg!(windows) {
+            let mut shell = std::process::Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = std::process::Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        shell.arg(command);
+        if let Some(path) = &params.config_path {
+            shell.env("CH57X_CONFIG", path);
+        }
+        shell.envs(env.iter().map(|(name, value)| (name, value)));
+        let status = shell.status().with_context(|| format!("run hook '{command}'"))?;
+        ensure!(status.success(), "hook '{command}' failed: {status}");
+    }
+    Ok(())
+}
+
+fn run_upload_hooks(command: &UploadCommand, device: &Device, rendered: &Rendered, uploaded: &Uploaded) -> Result<()> {
+    let count = match uploaded {
+        Uploaded::UpToDate => 0,
+        Uploaded::Bindings { count, .. } => *count,
+    };
+    run_hooks(&command.config_params, rendered.hooks.upload.as_slice(), command.on_success.as_deref(), &[
+        ("CH57X_DEVICE", describe_device(device)),
+        ("CH57X_LAYERS", rendered.layers.len().to_string()),
+        ("CH57X_UPLOADED", count.to_string()),
+    ])
+}
+
+/// Prints lint warnings, they don't prevent config from being used.
+fn print_warnings(layers: &[FlatLayer], secrets: &SecretNames) {
+    for warning in lint::lint(layers, secrets) {
+        eprintln!("warning: {warning}");
+    }
+}
+
+enum Uploaded {
+    /// Config is the same as previously uploaded one, nothing is done.
+    UpToDate,
+    /// Bindings are uploaded, changes are given if they were asked for and
+    /// previous upload is known.
+    Bindings { count: usize, changes: Option<Vec<Change>> },
+}
+
+/// Uploads layers and remembers them in state cache, backing up config after success.
+/// Upload is skipped if device is known to have the same bindings already.
+fn upload(options: &Options, device: &Device, rendered: &Rendered, command: &UploadCommand) -> Result<Uploaded> {
+    let Rendered { layers, columns, geometry, led, secrets, .. } = rendered;
+    let bindings = Bindings::from_layers(layers);
+
+    let mut state = load_state(device);
+    if !command.force && state.bindings.as_ref() == Some(&bindings) && state.has_led(&[*led]) {
+        return Ok(Uploaded::UpToDate);
+    }
+
+    let changes = match (command.what_changed, &state.bindings) {
+        (true, Some(previous)) => {
+            let changes = previous.changes(&bindings).into_iter()
+                .map(|change| Change { new: change.new.map(|new| secrets.show_text(&new)), ..change })
+                .collect_vec();
+            println!("changes to upload:");
+            print_changes(&changes);
+            Some(changes)
+        }
+        (true, None) => {
+            println!("no previous upload to this device is known, all bindings are new");
+            None
+        }
+        (false, _) => None,
+    };
+
+    // Bindings uploaded last time are restored if upload fails midway.
+    let previous = match &state.bindings {
+        Some(previous) => backup::find(device, previous).unwrap_or_else(|err| {
+            eprintln!("warning: can't look for previous config in backups: {err:#}");
+            None
+        }),
+        None => None,
+    };
+
+    // Secrets are typed by plain macros, so config having them isn't stored anywhere.
+    let keep_config = secrets.is_empty();
+
+    // Make sure every macro can be encoded, so that config isn't uploaded partially.
+    if let Some(caps) = device.capabilities() {
+        ensure!(caps.led || led.is_none(), "this keyboard doesn't support setting LED mode");
+        let key_ids = caps.model.key_id_layout(Some(*geometry));
+        for (layer_idx, layer) in layers.iter().enumerate() {
+            for (key, macro_) in layer.bindings() {
+                if let Some(max) = caps.max_macro_length {
+                    let steps = macro_.steps(caps.modifier_steps);
+                    let note = if caps.modifier_steps { " (each modifier takes a step on this keyboard)" } else { "" };
+                    ensure!(steps <= max,
+                            "bind {key} on layer {layer_idx}: macro '{}' takes {steps} steps, keyboard accepts {max} at most{note}",
+                            secrets.show(macro_));
+                }
+                caps.model.bind_key_messages(key_ids, layer_idx as u8, key, macro_)
+                    .with_context(|| format!("bind {key} on layer {layer_idx}"))?;
+            }
+        }
+    }
+
+    if let Some(idle) = command.wait_idle {
+        wait_idle(device, idle)?;
+    }
+
+    let mut keyboard = open_device(options, device, Some(*geometry))?;
+    if command.safe_mode {
+        check_single_binding(keyboard.as_mut(), layers)?;
+    }
+
+    let verbosity = match (QUIET.load(Ordering::Relaxed), command.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (false, true) => Verbosity::Verbose,
+        (false, false) => Verbosity::Normal,
+    };
+    let model = device.model().expect("opened device has known model");
+    let key_ids = model.key_id_layout(Some(*geometry));
+    let total = layers.iter().map(|layer| layer.bindings().count()).sum();
+    let mut progress = Progress::new(verbosity, keyboard.get_report_id(), keyboard.get_transfer_options().packet_size, total, secrets.clone());
+    let result = keyboard.upload_with_progress(layers, &mut |layer, key, macro_| {
+        let messages = model.bind_key_messages(key_ids, layer, key, macro_).unwrap_or_default();
+        progress.key(layer, key, macro_, &messages);
+    });
+    progress.finish();
+    let count = match result {
+        Ok(count) => count,
+        Err(err) => {
+            rollback(keyboard.as_mut(), device, state, previous.as_deref());
+            return Err(err);
+        }
     };
+    // LED mode is set in separate session, like by `led` command, since it isn't
+    // known whether keyboard accepts it right after bindings on the same handle.
+    drop(keyboard);
+    if let Some(mode) = *led {
+        open_device(options, device, Some(*geometry))?.set_led(0, mode).context("set LED mode")?;
+        state.set_led(0, mode);
+    }
+
+    // Only config which is on device is backed up, so that restoring it makes sense.
+    let config = backup::render_config(*columns, *geometry, *led, layers);
+    if command.keep_backups > 0 && keep_config && !device.is_mock() {
+        if let Err(err) = backup::save(device, &config, command.keep_backups) {
+            eprintln!("warning: can't back up config: {err:#}");
+        }
+    }
+
+    if keep_config {
+        state.bindings = Some(bindings);
+        state.last_upload = Some(state::Upload {
+            time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            source: command.config_params.config_path.as_ref().map(|path| path.to_string_lossy().into_owned()),
+            config,
+        });
+    } else {
+        state.bindings = None;
+        state.last_upload = None;
+    }
+    record_write(device, state);
+
+    Ok(Uploaded::Bindings { count, changes })
+}
+
+/// Waits until keyboard sends no input reports for `idle` time.
+fn wait_idle(device: &Device, idle: Duration) -> Result<()> {
+    let mut input = device.open_input()?;
+    let mut last_activity = Instant::now();
+    let mut reported = false;
+    loop {
+        let left = idle.saturating_sub(last_activity.elapsed());
+        if left.is_zero() {
+            return Ok(());
+        }
+        if input.read_timeout(left)?.is_some() {
+            if !reported {
+                say!("keyboard is in use, waiting until it is idle for {}s", idle.as_secs_f64());
+                reported = true;
+            }
+            last_activity = Instant::now();
+        }
+    }
+}
+
+/// Uploads previous bindings to half-programmed device. Keys which weren't bound
+/// before keep new bindings, since keys can't be unbound. If previous bindings
+/// can't be restored, they are forgotten, so that next upload isn't skipped.
+fn rollback(keyboard: &mut dyn Keyboard, device: &Device, mut state: DeviceState, previous: Option<&[FlatLayer]>) {
+    match previous.map(|layers| keyboard.upload(layers)) {
+        Some(Ok(_)) => eprintln!("upload failed, previous bindings are restored"),
+        Some(Err(err)) => {
+            eprintln!("warning: can't restore previous bindings, device is programmed partially: {err:#}");
+            state.bindings = None;
+        }
+        None => {
+            eprintln!("warning: previous bindings are unknown, device is programmed partially");
+            state.bindings = None;
+        }
+    }
+    record_write(device, state);
+}
 
-    for iface_num in interface_nums {
-        debug!("Probing interface {iface_num}");
-
-        // Look for an interface with the given number
-        let intf = conf_desc
-            .interfaces()
-            .find(|iface| iface_num == iface.number())
-            .ok_or_else(|| {
-                anyhow!(
-                    "interface #{} not found, interface numbers:\n{:#?}",
-                    iface_num,
-                    conf_desc.interfaces().map(|i| i.number()).format(", ")
-                )
-            })?;
-
-        // Check that it's a HID device
-        let intf_desc = intf.descriptors().exactly_one().map_err(|_| {
-            anyhow!(
-                "only one interface descriptor is expected, got:\n{:#?}",
-                intf.descriptors().format("\n")
-            )
-        })?;
-
-        // Look for suitable endpoints
-        if let Some(endpt_desc) = intf_desc.endpoint_descriptors().find(|ep| {
-            ep.transfer_type() == TransferType::Interrupt && ep.address() == endpoint_addr
-        }) {
-            debug!("Found endpoint {endpt_desc:?}");
-            if intf_desc.class_code() == 0x03
-                && intf_desc.sub_class_code() == 0x00
-                && intf_desc.protocol_code() == 0x00
-            {
-                return Ok((iface_num, endpt_desc.address()));
+fn init_logger(format: OutputFormat) {
+    let mut builder = env_logger::Builder::new();
+    if format == OutputFormat::Json {
+        traffic::set_json(true);
+        builder.filter_level(LevelFilter::Error);
+        builder.filter_module(traffic::TARGET, LevelFilter::Debug);
+        builder.format(|buf, record| {
+            // Traffic messages are JSON objects already.
+            if record.target() == traffic::TARGET {
+                writeln!(buf, "{}", record.args())
             } else {
-                debug!("unexpected interface parameters: {:#?}", intf_desc);
+                let object = serde_json::json!({
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{object}")
             }
+        });
+    }
+    builder.parse_default_env();
+    builder.init();
+}
+
+/// Loads remembered device state, problems with it are not fatal.
+fn load_state(device: &Device) -> DeviceState {
+    DeviceState::load(device).unwrap_or_else(|err| {
+        eprintln!("warning: can't load remembered device state: {err:#}");
+        DeviceState::default()
+    })
+}
+
+/// Counts programming cycle and saves device state.
+fn record_write(device: &Device, mut state: DeviceState) {
+    state.write_count += 1;
+    if state.write_count >= WRITE_COUNT_WARNING {
+        eprintln!(
+            "warning: device was programmed {} times, keyboard flash endures limited number of writes",
+            state.write_count,
+        );
+    }
+    if let Err(err) = state.save(device) {
+        eprintln!("warning: can't remember device state: {err:#}");
+    }
+}
+
+/// Temporarily binds keys to given macros. If binding fails, keys bound so far are restored,
+/// including failed one, which may be bound partially.
+fn bind_temporary(keyboard: &mut dyn Keyboard, state: &mut DeviceState, layer: u8, temporary: &[(Key, Macro)]) -> Result<()> {
+    for (i, (key, macro_)) in temporary.iter().enumerate() {
+        if let Err(err) = keyboard.bind_key(layer, *key, macro_) {
+            if let Err(restore_err) = restore_bindings(keyboard, state, layer, &temporary[..=i]) {
+                eprintln!("warning: can't restore bindings: {restore_err:#}");
+            }
+            return Err(err).context("bind temporary macro");
         }
     }
+    Ok(())
+}
+
+/// Binds keys back to bindings remembered in state after they were temporarily bound
+/// to given macros. Keys which previous bindings are unknown keep temporary ones.
+fn restore_bindings(keyboard: &mut dyn Keyboard, state: &mut DeviceState, layer: u8, temporary: &[(Key, Macro)]) -> Result<()> {
+    let mut unknown = vec![];
+    for (key, macro_) in temporary {
+        let name = key.to_string();
+        let previous = state.bindings.as_ref()
+            .and_then(|bindings| bindings.0.get(layer as usize)?.iter().find(|b| b.key == name))
+            .and_then(|binding| binding.macro_.parse::<Macro>().ok());
+        match previous {
+            Some(previous) => keyboard.bind_key(layer, *key, &previous).context("restore binding")?,
+            None => {
+                if let Some(bindings) = &mut state.bindings {
+                    bindings.set(layer as usize, name.clone(), macro_.to_string());
+                }
+                unknown.push(name);
+            }
+        }
+    }
+    if !unknown.is_empty() {
+        eprintln!(
+            "warning: previous bindings of {} on layer {} are unknown, upload config to restore them",
+            unknown.iter().format(", "),
+            layer + 1,
+        );
+    }
+    Ok(())
+}
+
+/// Asks user to press top left and, if needed, top right keys to find orientation.
+/// Corner buttons are expected to be bound to corresponding letters.
+fn detect_orientation(rows: usize, cols: usize, corners: &[usize], letters: &[WellKnownCode]) -> Result<Vec<Orientation>> {
+    let ask = |prompt: &str| -> Result<usize> {
+        print!("{prompt}, then press Enter: ");
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).context("read answer")?;
+        let answer = answer.trim().to_lowercase();
+        corners.iter().zip(letters)
+            .find(|(_, letter)| letter.to_string() == answer)
+            .map(|(&button, _)| button)
+            .ok_or_else(|| anyhow!("expected one of letters {}", letters.iter().format(", ")))
+    };
 
-    Err(anyhow!("No valid interface/endpoint combination found!"))
+    println!("switch keyboard to first layer, corner keys are temporarily bound to letters");
+    let top_left = ask("press key in top left corner of keyboard as you hold it")?;
+    let mut orientations = Orientation::ALL.into_iter()
+        .filter(|o| o.button_index(rows, cols, 0, 0) == Some(top_left))
+        .collect_vec();
+
+    // On single-row keyboards top left key doesn't tell vertical orientations from horizontal ones.
+    if orientations.len() > 1 {
+        let top_right = ask("now press key in top right corner")?;
+        orientations.retain(|o| {
+            let (_, ocols) = o.grid_size(rows, cols);
+            o.button_index(rows, cols, 0, ocols - 1) == Some(top_right)
+        });
+    }
+    Ok(orientations)
 }
 
-fn open_keyboard(devel_options: &DevelOptions) -> Result<Box<dyn Keyboard>> {
-    // Find USB device based on the product id
-    let (device, desc, id_product) = find_device(devel_options).context("find USB device")?;
+/// Binds first key only and asks user to check it, so that device with
+/// unexpected protocol doesn't get the whole config in case of problems.
+fn check_single_binding(keyboard: &mut dyn Keyboard, layers: &[FlatLayer]) -> Result<()> {
+    let Some((key, macro_)) = layers.first().and_then(|layer| layer.bindings().next()) else {
+        return Ok(());
+    };
+    keyboard.bind_key(0, key, macro_).context("bind key")?;
 
+    print!("safe mode: {key} on first layer is bound to '{macro_}', press it. Did it work? [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).context("read answer")?;
     ensure!(
-        desc.num_configurations() == 1,
-        "only one device configuration is expected"
+        answer.trim().eq_ignore_ascii_case("y"),
+        "device didn't respond as expected, the rest of config is not uploaded"
     );
+    Ok(())
+}
 
-    let preferred_endpint = match id_product {
-        0x8840 | 0x8842 => k884x::Keyboard884x::preferred_endpoint(),
-        0x8890 => k8890::Keyboard8890::preferred_endpoint(),
-        _ => unreachable!("unsupported device"),
-    };
+fn print_changes(changes: &[Change]) {
+    if changes.is_empty() {
+        println!("  no changes");
+    }
+    for change in changes {
+        println!("  {change}");
+    }
+}
 
-    // Find correct endpoint
-    let (intf_num, endpt_addr) = find_interface_and_endpoint(
-        &device,
-        devel_options.interface_number,
-        devel_options.endpoint_address.unwrap_or(preferred_endpint),
-    )?;
-
-    // Open device.
-    let mut handle = device.open().context("open USB device")?;
-    let _ = handle.set_auto_detach_kernel_driver(true);
-    handle
-        .claim_interface(intf_num)
-        .context("claim interface")?;
-
-    match id_product {
-        0x8840 | 0x8842 => {
-            k884x::Keyboard884x::new(handle, endpt_addr).map(|v| Box::new(v) as Box<dyn Keyboard>)
-        }
-        0x8890 => {
-            k8890::Keyboard8890::new(handle, endpt_addr).map(|v| Box::new(v) as Box<dyn Keyboard>)
-        }
-        _ => unreachable!("unsupported device"),
-    }
-}
-
-fn find_device(devel_options: &DevelOptions) -> Result<(Device<Context>, DeviceDescriptor, u16)> {
-    let options = vec![
-        #[cfg(windows)] rusb::UsbOption::use_usbdk(),
-    ];
-    let usb_context = rusb::Context::with_options(&options)?;
-
-    let mut found = vec![];
-    for device in usb_context.devices().context("get USB device list")?.iter() {
-        let desc = device.device_descriptor().context("get USB device info")?;
-        debug!(
-            "Bus {:03} Device {:03} ID {:04x}:{:04x}",
-            device.bus_number(),
-            device.address(),
-            desc.vendor_id(),
-            desc.product_id()
+fn device_filter(options: &Options) -> DeviceFilter {
+    let devel_options = &options.devel_options;
+    DeviceFilter {
+        vendor_id: devel_options.vendor_id,
+        product_id: devel_options.product_id,
+        address: devel_options.address,
+    }
+}
+
+/// Lists devices matching filter, or in-memory one if mock backend is chosen.
+fn enumerate_devices(options: &Options, filter: &DeviceFilter) -> Result<Vec<Device>> {
+    match options.backend {
+        Backend::Usb => Device::enumerate(filter),
+        Backend::Mock => {
+            // Mock keyboard is of expected model, so that it passes the check.
+            let model = options.expect_model.unwrap_or(Model::K884x);
+            Ok(vec![Device::mock(MockDevice::new(model), filter.product_id)?])
+        }
+    }
+}
+
+fn find_device(options: &Options) -> Result<Device> {
+    find_selected_device(options, &DeviceSelector::default())
+}
+
+/// Finds device matching both command line options and selector from config.
+fn find_selected_device(options: &Options, selector: &DeviceSelector) -> Result<Device> {
+    let mut filter = device_filter(options);
+    filter.product_id = selector.product_id.or(filter.product_id);
+    filter.address = selector.address.or(filter.address);
+
+    // Find USB device based on the product id
+    let device = enumerate_devices(options, &filter)
+        .and_then(|mut found| {
+            found.retain(|device| selector.matches(device));
+            Device::choose(found, filter.address)
+        })
+        .context("find USB device")?;
+
+    // Nothing has been written to device yet, so it's safe to stop here.
+    if let Some(expected_model) = options.expect_model {
+        let product_id = device.product_id();
+        let model = device.model()
+            .ok_or_else(|| anyhow!("unsupported product ID {product_id:04x}"))?;
+        ensure!(
+            model == expected_model,
+            "expected {expected_model} keyboard model, but found {model} (product ID {product_id:04x})"
         );
-        let product_id = desc.product_id();
-        if desc.vendor_id() == devel_options.vendor_id
-            && match devel_options.product_id {
-                Some(prod_id) => prod_id == product_id,
-                None => PRODUCT_IDS.contains(&product_id),
-            }
-        {
-            found.push((device, desc, product_id));
-        }
-    }
-
-    match found.len() {
-        0 => Err(anyhow!(
-            "CH57x keyboard device not found. Use --vendor-id and --product-id to override settings."
-        )),
-        1 => Ok(found.pop().unwrap()),
-        _ => {
-            let mut addresses = vec![];
-            for (device, desc, product_id) in found {
-                /*let handle = device.open().context("open device")?;
-                let langs = handle.read_languages(DEFAULT_TIMEOUT).context("get langs")?;
-                dbg!(&langs);
-                let lang =
-                    // First try to find US English language
-                    langs.iter().find(|l| {
-                        l.primary_language() == PrimaryLanguage::English &&
-                        l.sub_language() == SubLanguage::UnitedStates
-                    })
-                    // Then any English sublanguage
-                    .or_else(|| langs.iter().find(|l| l.primary_language() == PrimaryLanguage::English))
-                    // Then just first available language
-                    .or_else(|| langs.first())
-                    // Ok, give up
-                    .ok_or_else(|| anyhow!("No languages found"))?;
-                dbg!(lang);
-                let serial = handle.read_serial_number_string(*lang, &desc, DEFAULT_TIMEOUT)
-                    .context("read serial")?;*/
-                let address = (device.bus_number(), device.address());
-                if devel_options.address.as_ref() == Some(&address) {
-                    return Ok((device, desc, product_id))
-                }
+    }
 
-                addresses.push(address);
-            }
+    Ok(device)
+}
 
-            Err(anyhow!(indoc! {"
-                Several compatible devices are found.
-                Unfortunately, this model of keyboard doesn't have serial number.
-                So specify USB address using --address option.
+fn open_device(options: &Options, device: &Device, geometry: Option<Geometry>) -> Result<Box<dyn Keyboard>> {
+    SESSION.with_borrow_mut(|session| match session {
+        Some(Some(session)) => session.keyboard(geometry),
+        Some(session) => session.insert(device.open_session(&endpoint_options(options))?).keyboard(geometry),
+        None => device.open(&endpoint_options(options), geometry),
+    })
+}
 
-                Addresses:
-                {}
-            "}, addresses.iter().map(|(bus, addr)| format!("{bus}:{addr}")).join("\n")))
-        }
+/// Runs `f` with device opened at most once, by first command needing it.
+fn with_session<T>(f: impl FnOnce() -> T) -> T {
+    SESSION.set(Some(None));
+    let result = f();
+    SESSION.set(None);
+    result
+}
+
+fn endpoint_options(options: &Options) -> EndpointOptions {
+    let devel_options = &options.devel_options;
+    EndpointOptions {
+        interface_number: devel_options.interface_number,
+        endpoint_address: devel_options.endpoint_address,
+        report_id: match (devel_options.report_id, devel_options.no_report_id) {
+            (_, true) => Some(None),
+            (Some(report_id), false) => Some(Some(report_id)),
+            (None, false) => None,
+        },
+        transfer: transfer_options(options),
+        force_protocol: options.force_protocol,
     }
 }
 
-fn load_config(params: &ConfigParams) -> Result<Config> {
-    // Load and validate mapping.
-    let mut stdin_reader: BufReader<StdinLock<'static>>;
-    let mut file_reader: BufReader<std::fs::File>;
-    let reader: &mut dyn Read = match &params.config_path {
+fn transfer_options(options: &Options) -> TransferOptions {
+    TransferOptions {
+        timeout: Duration::from_millis(options.timeout_ms),
+        retries: options.retries,
+        message_delay: Duration::from_millis(options.msg_delay_ms),
+        ..Default::default()
+    }
+}
+
+/// Modification times of config file or directory with fragments, and of every
+/// file they extend, `None` for missing ones. Directory itself is included, so that
+/// added and removed fragments are noticed.
+fn modification_times(path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut times = config::config_files(path).into_iter().map(|file| {
+        let time = modified(&file);
+        (file, time)
+    }).collect_vec();
+    if path.is_dir() {
+        times.push((path.to_owned(), modified(path)));
+    }
+    times
+}
+
+/// Downloads config if path is URL.
+fn fetch_config(params: &ConfigParams) -> Result<Option<(Vec<u8>, ConfigFormat)>> {
+    let Some(url) = params.config_path.as_ref().and_then(|path| path.to_str()).filter(|path| fetch::is_url(path)) else {
+        ensure!(params.sha256.is_none(), "--sha256 may only be given for config downloaded from URL");
+        return Ok(None);
+    };
+    let data = fetch::fetch(url, params.sha256.as_deref())?;
+    Ok(Some((data, params.format.unwrap_or_else(|| fetch::format_of(url)))))
+}
+
+/// Loads configs of all devices described by config, or only one chosen by `--device`.
+fn load_device_configs(params: &ConfigParams) -> Result<Vec<DeviceConfig>> {
+    let configs = read_device_configs(params)?;
+    match &params.device {
+        Some(choice) => Ok(vec![DeviceConfig::choose(configs, Some(choice))?]),
+        None => Ok(configs),
+    }
+}
+
+fn read_device_configs(params: &ConfigParams) -> Result<Vec<DeviceConfig>> {
+    let options = LoadOptions { allow_env: params.allow_env, secrets: params.allow_secrets.then_some(&Keychain) };
+    if let Some((data, format)) = fetch_config(params)? {
+        return Config::load_devices(data.as_slice(), format, None, options);
+    }
+    match &params.config_path {
+        Some(path) if Path::new(path).is_dir() => Config::load_devices_from_dir(Path::new(path), options),
         Some(path) => {
+            let path = Path::new(path);
+            let format = params.format.unwrap_or_else(|| ConfigFormat::from_path(path));
             let file = std::fs::File::open(path).context("open config file")?;
-            file_reader = BufReader::new(file);
-            &mut file_reader
-        }
-        None => {
-            stdin_reader = BufReader::new(std::io::stdin().lock());
-            &mut stdin_reader
+            Config::load_devices(std::io::BufReader::new(file), format, Some(path), options)
         }
+        None => Config::load_devices(std::io::stdin().lock(), params.format.unwrap_or(ConfigFormat::Yaml), None, options),
+    }
+}
+
+/// Loads config of single device, one chosen by `--device` if config describes several ones.
+fn load_config(params: &ConfigParams) -> Result<Config> {
+    Ok(DeviceConfig::choose(load_device_configs(params)?, params.device.as_ref())?.config)
+}
+
+/// Loads config which only geometry and orientation are taken from.
+fn load_config_at(path: &OsStr, device: Option<&DeviceChoice>) -> Result<Config> {
+    let params = ConfigParams {
+        config_path: Some(path.to_owned()), format: None, allow_env: false, allow_secrets: false, sha256: None,
+        target_os: TargetOs::host(), device: device.cloned(),
     };
-    Ok(serde_yaml::from_reader(reader)?)
+    load_config(&params).context(ConfigError("load mapping config"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ch57x_keyboard::keyboard::mock::DecodedBindings;
+
+    const CONFIG: &str = "orientation: normal\nrows: 1\ncolumns: 2\nknobs: 0\nled: 2\nlayers:\n  - buttons: [[a, ctrl-c]]\n    knobs: []\n";
+
+    fn parse(args: &[&str]) -> Options {
+        Options::parse_from(["ch57x-keyboard-tool", "--backend", "mock", "--retries", "0"].iter().chain(args))
+    }
+
+    fn rendered(config: &str) -> Result<Rendered> {
+        render(Config::from_reader(config.as_bytes(), ConfigFormat::Yaml, None)?, TargetOs::host())
+    }
+
+    /// Bindings mock device decodes from messages binding given layers.
+    fn expected_bindings(model: Model, layers: &[FlatLayer]) -> Result<DecodedBindings> {
+        let key_ids = model.key_id_layout(None);
+        let mut expected = DecodedBindings::new();
+        for (layer_idx, layer) in layers.iter().enumerate() {
+            for (key, macro_) in layer.bindings() {
+                let mut messages = model.bind_key_messages(key_ids, layer_idx as u8, key, macro_)?;
+                if model == Model::K8890 {
+                    // Device keeps item messages only.
+                    messages = messages[1..messages.len() - 1].to_vec();
+                }
+                for msg in &mut messages {
+                    msg.resize(63, 0);
+                }
+                expected.insert((layer_idx as u8, key.to_key_id(key_ids)?), messages);
+            }
+        }
+        Ok(expected)
+    }
+
+    #[test]
+    fn test_upload() -> Result<()> {
+        let options = parse(&["upload", "config.yaml"]);
+        let Command::Upload(command) = &options.command else { unreachable!() };
+        let rendered = rendered(CONFIG)?;
+        let mock = MockDevice::new(Model::K8890);
+        let device = Device::mock(mock.clone(), None)?;
+
+        assert!(matches!(upload(&options, &device, &rendered, command)?, Uploaded::Bindings { count: 2, .. }));
+        assert_eq!(mock.bindings(), expected_bindings(Model::K8890, &rendered.layers)?);
+        assert_eq!(mock.led(), Some(2));
+        // Neither state nor backups are kept for mock device, so upload is repeated.
+        assert!(backup::list(&device)?.is_empty());
+        assert!(matches!(upload(&options, &device, &rendered, command)?, Uploaded::Bindings { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_led() -> Result<()> {
+        let options = parse(&["led", "1"]);
+        let mock = MockDevice::new(Model::K8890);
+        set_led(&options, &Device::mock(mock.clone(), None)?, 3)?;
+        assert_eq!(mock.led(), Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback() -> Result<()> {
+        let options = parse(&["led", "1"]);
+        let previous = rendered(CONFIG)?.layers;
+        let new = rendered(&CONFIG.replace("[a, ctrl-c]", "[b, ctrl-v]"))?.layers;
+        // Initial report and first binding are sent, second binding fails.
+        let mock = MockDevice::failing_at(Model::K884x, 2);
+        let device = Device::mock(mock.clone(), None)?;
+
+        let mut keyboard = open_device(&options, &device, None)?;
+        assert!(keyboard.upload(&new).is_err());
+        assert_eq!(mock.bindings().len(), 1);
+        rollback(keyboard.as_mut(), &device, DeviceState::default(), Some(&previous));
+        assert_eq!(mock.bindings(), expected_bindings(Model::K884x, &previous)?);
+        Ok(())
+    }
 }
@@ -0,0 +1,126 @@
+//! Decoding of input reports keyboard sends to host on key presses.
+//!
+//! Report descriptors are not parsed, layouts used by known keyboards are
+//! recognized instead: boot protocol keyboard and mouse reports, keyboard
+//! report prefixed with report ID and two-byte consumer (media) report.
+//! Anything else is reported as raw bytes.
+
+use std::fmt::Display;
+
+use enumset::EnumSet;
+use itertools::Itertools as _;
+use strum::IntoEnumIterator as _;
+
+use crate::keyboard::{Code, ConsumerCode, MediaCode, Modifiers, MouseButton, MouseButtons, WellKnownCode};
+
+/// HID interface protocol codes.
+pub const PROTOCOL_KEYBOARD: u8 = 1;
+pub const PROTOCOL_MOUSE: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Pressed modifiers and keys, both empty when everything is released.
+    Keyboard(Modifiers, Vec<Code>),
+    /// Pressed consumer control, `None` on release.
+    Media(Option<ConsumerCode>),
+    /// Pressed mouse buttons and wheel movement.
+    Mouse(MouseButtons, i8),
+    Unknown(Vec<u8>),
+}
+
+impl Event {
+    /// Whether event releases everything, such events are sent after each press.
+    pub fn is_release(&self) -> bool {
+        match self {
+            Event::Keyboard(modifiers, codes) => modifiers.is_empty() && codes.is_empty(),
+            Event::Media(code) => code.is_none(),
+            Event::Mouse(buttons, wheel) => buttons.is_empty() && *wheel == 0,
+            Event::Unknown(_) => false,
+        }
+    }
+}
+
+/// Formats event using config syntax, so it may be compared with bound macros.
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_release() {
+            return write!(f, "release");
+        }
+        match self {
+            Event::Keyboard(modifiers, codes) => {
+                let parts = modifiers.iter().map(|m| m.to_string())
+                    .chain(codes.iter().map(|c| c.to_string()));
+                write!(f, "{}", parts.format("-"))
+            }
+            Event::Media(code) => write!(f, "{}", code.unwrap()),
+            Event::Mouse(buttons, wheel) => {
+                let wheel = match wheel {
+                    0 => None,
+                    w if *w > 0 => Some("wheelup"),
+                    _ => Some("wheeldown"),
+                };
+                let parts = buttons.iter().map(|b| b.to_string()).chain(wheel.map(str::to_owned));
+                write!(f, "{}", parts.format("+"))
+            }
+            Event::Unknown(report) => write!(f, "unknown report {:02x}", report.iter().format(" ")),
+        }
+    }
+}
+
+/// Decodes input report received from interface with given HID protocol.
+pub fn decode(protocol: u8, report: &[u8]) -> Event {
+    match (protocol, report.len()) {
+        (PROTOCOL_KEYBOARD, 8) => keyboard(report),
+        (PROTOCOL_MOUSE, 4..) => mouse(report),
+        // Report ID followed by boot keyboard report.
+        (_, 9) => keyboard(&report[1..]),
+        // Report ID followed by consumer usage code.
+        (_, 3) => {
+            let code = u16::from_le_bytes([report[1], report[2]]);
+            Event::Media((code != 0).then(|| consumer_code(code)))
+        }
+        _ => Event::Unknown(report.to_vec()),
+    }
+}
+
+fn keyboard(report: &[u8]) -> Event {
+    let modifiers = Modifiers::from_u8_truncated(report[0]);
+    let codes = report[2..].iter().filter(|&&c| c != 0).map(|&c| code(c)).collect();
+    Event::Keyboard(modifiers, codes)
+}
+
+fn mouse(report: &[u8]) -> Event {
+    let buttons = MouseButton::iter()
+        .enumerate()
+        .filter(|(i, _)| report[0] & (1 << i) != 0)
+        .map(|(_, b)| b)
+        .collect::<EnumSet<_>>();
+    Event::Mouse(buttons, report[3] as i8)
+}
+
+fn code(value: u8) -> Code {
+    WellKnownCode::iter().find(|c| *c as u8 == value)
+        .map_or(Code::Custom(value), Code::WellKnown)
+}
+
+fn consumer_code(value: u16) -> ConsumerCode {
+    MediaCode::iter().find(|c| *c as u16 == value)
+        .map_or(ConsumerCode::Custom(value), ConsumerCode::WellKnown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(PROTOCOL_KEYBOARD, &[0x01, 0, 0x04, 0, 0, 0, 0, 0]).to_string(), "ctrl-a");
+        assert_eq!(decode(PROTOCOL_KEYBOARD, &[0; 8]).to_string(), "release");
+        assert_eq!(decode(0, &[0x01, 0x00, 0, 0xc8, 0, 0, 0, 0, 0]).to_string(), "<200>");
+        assert_eq!(decode(0, &[0x03, 0xe9, 0x00]).to_string(), "volumeup");
+        assert_eq!(decode(0, &[0x03, 0x00, 0x00]).to_string(), "release");
+        assert_eq!(decode(PROTOCOL_MOUSE, &[0x03, 0, 0, 0]).to_string(), "click+rclick");
+        assert_eq!(decode(PROTOCOL_MOUSE, &[0, 0, 0, 0xff]).to_string(), "wheeldown");
+        assert_eq!(decode(0, &[0x05, 0x01]).to_string(), "unknown report 05 01");
+    }
+}
@@ -1,20 +1,77 @@
 use std::ffi::OsString;
 use std::num::ParseIntError;
+use std::str::FromStr;
+use std::time::Duration;
 
-use clap::{Args, Parser, Subcommand};
-use crate::consts::VENDOR_ID;
-use crate::parse;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use strum_macros::{Display, EnumString};
+use ch57x_keyboard::backup;
+use ch57x_keyboard::config::{ConfigFormat, DeviceChoice, KnobPosition, TargetOs};
+use ch57x_keyboard::consts::{LAYER_COUNT, VENDOR_ID};
+use ch57x_keyboard::keyboard::{Geometry, KnobAction, Macro, Model};
+use ch57x_keyboard::parse;
+
+use crate::i18n::Lang;
 
 #[derive(Parser)]
 pub struct Options {
     #[command(subcommand)]
     pub command: Command,
 
+    /// Refuse to program device unless it is of given model (884x or 8890)
+    #[arg(long)]
+    pub expect_model: Option<Model>,
+
+    /// Timeout of single write to device, in milliseconds
+    #[arg(long, default_value_t=100)]
+    pub timeout_ms: u64,
+
+    /// Number of times failed write is retried, with increasing delays
+    #[arg(long, default_value_t=3)]
+    pub retries: u32,
+
+    /// Delay after each message sent to device, in milliseconds
+    #[arg(long, default_value_t=0)]
+    pub msg_delay_ms: u64,
+
+    /// Program device even if its USB endpoints don't look like ones of model
+    /// it is configured as, use it only if you are sure quirks are right
+    #[arg(long)]
+    pub force_protocol: bool,
+
+    /// Format of log: text or json, JSON log also includes every message
+    /// exchanged with device without RUST_LOG set
+    #[arg(long, default_value_t=OutputFormat::Text)]
+    pub log_format: OutputFormat,
+
+    /// Device to program: usb or mock, mock one decodes messages in memory and
+    /// keeps neither state nor backups, it is of --expect-model model, 884x by default
+    #[arg(long, default_value_t=Backend::Usb)]
+    pub backend: Backend,
+
+    /// Language of show-keys output and hints for common errors: en, de, fr, ru or zh,
+    /// by default it is taken from locale
+    #[arg(long)]
+    pub lang: Option<Lang>,
+
+    /// Don't print human-readable messages and progress, only errors and requested output,
+    /// for scripts checking exit code
+    #[arg(long, global=true)]
+    pub quiet: bool,
+
     #[clap(flatten)]
     pub devel_options: DevelOptions,
 }
 
-#[derive(Args)]
+#[derive(Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all="lowercase")]
+#[strum(ascii_case_insensitive)]
+pub enum Backend {
+    Usb,
+    Mock,
+}
+
+#[derive(Args, Clone)]
 #[clap(next_help_heading = "Internal options (use with caution)")]
 pub struct DevelOptions {
     #[arg(long, default_value_t=VENDOR_ID, value_parser=hex_or_decimal)]
@@ -31,6 +88,14 @@ pub struct DevelOptions {
 
     #[arg(long)]
     pub interface_number: Option<u8>,
+
+    /// Report ID to prefix messages with, detected from HID report descriptor by default
+    #[arg(long)]
+    pub report_id: Option<u8>,
+
+    /// Send messages without report ID
+    #[arg(long, conflicts_with="report_id")]
+    pub no_report_id: bool,
 }
 
 pub fn hex_or_decimal(s: &str) -> Result<u16, ParseIntError>
@@ -38,11 +103,22 @@ pub fn hex_or_decimal(s: &str) -> Result<u16, ParseIntError>
     if s.to_ascii_lowercase().starts_with("0x") {
         u16::from_str_radix(&s[2..], 16)
     } else {
-        u16::from_str_radix(s, 10)
+        s.parse()
     }
 }
 
-fn parse_address(s: &str) -> std::result::Result<(u8, u8), nom::error::Error<String>> {
+/// Parses duration like `2s`, `1.5s` or `500ms`, number without unit is seconds.
+fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let (number, scale) = match s.strip_suffix("ms") {
+        Some(number) => (number, 0.001),
+        None => (s.strip_suffix('s').unwrap_or(s), 1.0),
+    };
+    number.trim().parse::<f64>().ok()
+        .and_then(|n| Duration::try_from_secs_f64(n * scale).ok())
+        .ok_or_else(|| format!("invalid duration '{s}', expected like 2s or 500ms"))
+}
+
+fn parse_address(s: &str) -> std::result::Result<(u8, u8), parse::Error> {
     parse::from_str(parse::address, s)
 }
 
@@ -52,20 +128,456 @@ pub enum Command {
     ShowKeys,
 
     /// Validate key mappings config on stdin
-    Validate(ConfigParams),
+    Validate(ValidateCommand),
 
     /// Upload key mappings from stdin to device
-    Upload(ConfigParams),
+    Upload(UploadCommand),
+
+    /// Upload config backed up by one of previous uploads
+    Restore(RestoreCommand),
+
+    /// Show when config was last uploaded to device and from which file
+    Status,
+
+    /// Upload last uploaded config again, e.g. after firmware reset wiped bindings
+    Reapply,
 
     /// Select LED backlight mode
     Led(LedCommand),
+
+    /// Bind single key without uploading whole config
+    Bind(BindCommand),
+
+    /// Print key events keyboard sends, until interrupted
+    Monitor,
+
+    /// Find out orientation by asking to press corner keys
+    Orient(OrientCommand),
+
+    /// Temporarily bind keys to type their numbers, to check geometry and orientation
+    Testmap(TestmapCommand),
+
+    /// Check GitHub for newer releases and changes relevant to connected keyboard
+    UpgradeCheck,
+
+    /// Print starter config for keyboard with given number of buttons and knobs
+    Init(InitCommand),
+
+    /// Send reports captured from vendor software to device as is, for reverse engineering
+    Replay(ReplayCommand),
+
+    /// Print messages binding single key would send, without device
+    Encode(EncodeCommand),
+
+    /// Print device identification, firmware release and capabilities
+    Probe,
+
+    /// Print report with OS, tool version, device descriptors and log tail
+    /// to paste into GitHub issue, user-specific data is redacted
+    Report(ReportCommand),
+
+    /// Install udev rule allowing logged-in users to program keyboards (Linux only)
+    SetupPermissions(SetupPermissionsCommand),
+
+    /// Serve local HTTP API with JSON responses for GUI frontends
+    Serve(ServeCommand),
+
+    /// Print JSON Schema of config for editors with YAML language server
+    Schema,
+
+    /// Run commands from script file, one per line, against the same device
+    Run(RunCommand),
+
+    /// Keep running and upload configs to keyboards as they are connected
+    Daemon(DaemonCommand),
+
+    /// Print key bindings from config as they will be uploaded,
+    /// with orientation applied
+    Render(RenderCommand),
+
+    /// Print cheat sheet with labels or macros of keys by layer, as keys are placed on keyboard
+    Cheatsheet(CheatsheetCommand),
+
+    /// Copy or swap layers in config file, keeping its comments
+    Layer(LayerCommand),
 }
 
 #[derive(Parser)]
 pub struct ConfigParams {
-    /// Path to config file to upload.
+    /// Path or http(s) URL of config file to upload.
     /// If not given, read from stdin.
     pub config_path: Option<OsString>,
+
+    /// Config format: yaml or json.
+    /// By default it is detected by file extension, stdin is read as YAML.
+    #[arg(long)]
+    pub format: Option<ConfigFormat>,
+
+    /// Substitute `${NAME}` in macros with values of environment variables
+    #[arg(long)]
+    pub allow_env: bool,
+
+    /// Type secrets from OS keychain for `secret:NAME` macros. Configs with secrets
+    /// aren't backed up and bindings aren't remembered
+    #[arg(long)]
+    pub allow_secrets: bool,
+
+    /// Expected SHA-256 of config downloaded from URL, it isn't used if content differs
+    #[arg(long, value_name="HASH", requires="config_path")]
+    pub sha256: Option<String>,
+
+    /// OS to choose OS-specific macros for: macos, linux or windows
+    #[arg(long, default_value_t=TargetOs::host())]
+    pub target_os: TargetOs,
+
+    /// Device to use config of, if config describes several devices: its index in
+    /// `devices` list or selector like `serial=A1B2`, `product_id=0x8890` or `address=1:5`
+    #[arg(long)]
+    pub device: Option<DeviceChoice>,
+}
+
+#[derive(Parser)]
+pub struct ValidateCommand {
+    #[clap(flatten)]
+    pub config_params: ConfigParams,
+
+    /// Output format: text or json
+    #[arg(long, default_value_t=OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// Treat warnings as errors
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Shell command to run if config is valid, in addition to `hooks.validate` from config
+    #[arg(long, value_name="CMD")]
+    pub on_success: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct UploadCommand {
+    #[clap(flatten)]
+    pub config_params: ConfigParams,
+
+    /// Keep running and upload config again each time config file is changed
+    #[arg(long, requires="config_path")]
+    pub watch: bool,
+
+    /// Print bindings changed since last upload to the same device
+    #[arg(long)]
+    pub what_changed: bool,
+
+    /// Bind only first key and ask whether it works before uploading the rest,
+    /// use it for first contact with unknown devices
+    #[arg(long, requires="config_path", conflicts_with="watch")]
+    pub safe_mode: bool,
+
+    /// Upload even if device is known to have the same bindings already
+    #[arg(long)]
+    pub force: bool,
+
+    /// Number of config backups to keep for device, 0 disables backups
+    #[arg(long, default_value_t=backup::DEFAULT_KEEP)]
+    pub keep_backups: usize,
+
+    /// Wait until keyboard sends no key presses for given time (like 2s) before programming,
+    /// since some firmwares corrupt bindings received while keyboard is used
+    #[arg(long, value_parser=parse_duration)]
+    pub wait_idle: Option<Duration>,
+
+    /// Shell command to run after each successful upload, in addition to `hooks.upload` from config
+    #[arg(long, value_name="CMD")]
+    pub on_success: Option<String>,
+
+    /// Print each key with messages sent to bind it instead of progress bar
+    #[arg(long)]
+    pub verbose: bool,
+}
+
+impl UploadCommand {
+    /// Command uploading config with given parameters once, with default options,
+    /// for uploads which aren't requested by `upload` command.
+    pub fn defaults(config_params: ConfigParams) -> Self {
+        Self {
+            config_params,
+            watch: false,
+            what_changed: false,
+            safe_mode: false,
+            force: false,
+            keep_backups: backup::DEFAULT_KEEP,
+            wait_idle: None,
+            on_success: None,
+            verbose: false,
+        }
+    }
+}
+
+#[derive(Parser)]
+pub struct RestoreCommand {
+    /// Timestamp of backup to restore, available backups are listed if not given
+    #[arg(long)]
+    pub backup: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct RenderCommand {
+    #[clap(flatten)]
+    pub config_params: ConfigParams,
+
+    /// Output format: text or json
+    #[arg(long, default_value_t=OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// Keyboard model (884x or 8890) to also print key IDs and
+    /// encoded messages for
+    #[arg(long)]
+    pub model: Option<Model>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all="lowercase")]
+#[strum(ascii_case_insensitive)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Parser)]
+pub struct CheatsheetCommand {
+    #[clap(flatten)]
+    pub config_params: ConfigParams,
+
+    /// Output format: text, markdown, html or svg
+    #[arg(long, default_value_t=SheetFormat::Text)]
+    pub output: SheetFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all="lowercase")]
+#[strum(ascii_case_insensitive)]
+pub enum SheetFormat {
+    Text,
+    Markdown,
+    Html,
+    Svg,
+}
+
+#[derive(Parser)]
+#[command(group(ArgGroup::new("key").required(true).args(["button", "knob"])))]
+pub struct BindCommand {
+    /// Layer to bind key on (one-based)
+    #[arg(long, default_value_t=1, value_parser=clap::value_parser!(u8).range(1..=16))]
+    pub layer: u8,
+
+    /// Index of button to bind (zero-based, in keyboard order, orientation is not applied)
+    #[arg(long)]
+    pub button: Option<u8>,
+
+    /// Index of knob to bind (zero-based) or its position: left, right, top, bottom
+    /// or middle (requires --config to know orientation)
+    #[arg(long, requires="action")]
+    pub knob: Option<KnobSelector>,
+
+    /// Knob action to bind: ccw, press or cw
+    #[arg(long, requires="knob")]
+    pub action: Option<KnobAction>,
+
+    /// Macro to bind, using the same syntax as config
+    #[arg(value_name="MACRO")]
+    pub macro_: Macro,
+
+    /// Config to take orientation and number of knobs from
+    #[arg(long)]
+    pub config: Option<OsString>,
+
+    /// Device to use config of, if config describes several devices, like `--device` of `upload`
+    #[arg(long, requires="config")]
+    pub device: Option<DeviceChoice>,
+}
+
+#[derive(Clone, Copy)]
+pub enum KnobSelector {
+    Index(u8),
+    Position(KnobPosition),
+}
+
+impl FromStr for KnobSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(index) => Ok(Self::Index(index)),
+            Err(_) => s.parse().map(Self::Position)
+                .map_err(|_| format!("expected knob index or position, got '{s}'")),
+        }
+    }
+}
+
+#[derive(Parser)]
+pub struct InitCommand {
+    /// Number of button rows, with knobs on the right
+    #[arg(long)]
+    pub rows: u8,
+
+    /// Number of button columns, with knobs on the right
+    #[arg(long)]
+    pub columns: u8,
+
+    /// Number of knobs
+    #[arg(long, default_value_t=0)]
+    pub knobs: u8,
+}
+
+#[derive(Parser)]
+#[command(group(ArgGroup::new("key").required(true).args(["button", "knob"])))]
+pub struct EncodeCommand {
+    /// Keyboard model: 884x or 8890
+    #[arg(long)]
+    pub model: Model,
+
+    /// Keyboard variant as number of buttons and knobs, like 12+4,
+    /// for models numbering keys differently in some variants
+    #[arg(long)]
+    pub variant: Option<Geometry>,
+
+    /// Layer to bind key on (one-based)
+    #[arg(long, default_value_t=1, value_parser=clap::value_parser!(u8).range(1..=16))]
+    pub layer: u8,
+
+    /// Index of button (zero-based, in keyboard order)
+    #[arg(long)]
+    pub button: Option<u8>,
+
+    /// Index of knob (zero-based, in keyboard order)
+    #[arg(long, requires="action")]
+    pub knob: Option<u8>,
+
+    /// Knob action: ccw, press or cw
+    #[arg(long, requires="knob")]
+    pub action: Option<KnobAction>,
+
+    /// Macro to encode, using the same syntax as config
+    #[arg(value_name="MACRO")]
+    pub macro_: Macro,
+}
+
+#[derive(Parser)]
+pub struct ServeCommand {
+    /// Loopback address to listen on, requests must name loopback address in Host header
+    #[arg(long, default_value="127.0.0.1:9867")]
+    pub listen: String,
+}
+
+#[derive(Parser)]
+pub struct RunCommand {
+    /// Script with one command per line, like `upload work.yaml` or `led 2`,
+    /// empty lines and lines starting with `#` are skipped
+    pub script_path: OsString,
+}
+
+#[derive(Parser)]
+pub struct ReportCommand {
+    /// Log file to include tail of, like one written with `RUST_LOG=debug ... 2> log.txt`
+    #[arg(long)]
+    pub log: Option<OsString>,
+
+    /// Number of log lines to include
+    #[arg(long, default_value_t=50, requires="log")]
+    pub lines: usize,
+}
+
+#[derive(Parser)]
+pub struct DaemonCommand {
+    /// TOML file with profiles choosing config for each keyboard
+    pub profiles_path: OsString,
+}
+
+/// Command line of script run by `run` command.
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+pub struct ScriptLine {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Parser)]
+pub struct SetupPermissionsCommand {
+    /// Only print rule instead of installing it
+    #[arg(long)]
+    pub print: bool,
+}
+
+#[derive(Parser)]
+pub struct ReplayCommand {
+    /// File with one report per line as hex bytes, including report ID if device uses it
+    pub capture_path: OsString,
+}
+
+#[derive(Parser)]
+pub struct LayerCommand {
+    #[command(subcommand)]
+    pub action: LayerAction,
+}
+
+#[derive(Subcommand)]
+pub enum LayerAction {
+    /// Copy layer over another one, or add it as new layer after the last one
+    Copy(LayerCopyCommand),
+
+    /// Swap two layers
+    Swap(LayerSwapCommand),
+}
+
+#[derive(Parser)]
+pub struct LayerCopyCommand {
+    /// Path to config file, it is rewritten
+    pub config_path: OsString,
+
+    /// Layer to copy (one-based)
+    #[arg(long, value_parser=clap::value_parser!(u8).range(1..=16))]
+    pub from: u8,
+
+    /// Layer to replace (one-based)
+    #[arg(long, value_parser=clap::value_parser!(u8).range(1..=16))]
+    pub to: u8,
+}
+
+#[derive(Parser)]
+pub struct LayerSwapCommand {
+    /// Path to config file, it is rewritten
+    pub config_path: OsString,
+
+    /// Layers to swap (one-based)
+    #[arg(value_parser=clap::value_parser!(u8).range(1..=16))]
+    pub first: u8,
+
+    #[arg(value_parser=clap::value_parser!(u8).range(1..=16))]
+    pub second: u8,
+}
+
+#[derive(Parser)]
+pub struct TestmapCommand {
+    /// Path to config file to take geometry and orientation from
+    pub config_path: OsString,
+
+    /// Device to use config of, if config describes several devices, like `--device` of `upload`
+    #[arg(long)]
+    pub device: Option<DeviceChoice>,
+
+    /// Layer to bind keys on temporarily (one-based)
+    #[arg(long, default_value_t=1, value_parser=clap::value_parser!(u8).range(1..=LAYER_COUNT as i64))]
+    pub layer: u8,
+}
+
+#[derive(Parser)]
+pub struct OrientCommand {
+    /// Path to config file to take number of rows and columns from,
+    /// its orientation is ignored
+    pub config_path: OsString,
+
+    /// Device to use config of, if config describes several devices, like `--device` of `upload`
+    #[arg(long)]
+    pub device: Option<DeviceChoice>,
 }
 
 #[derive(Parser)]
@@ -73,3 +585,18 @@ pub struct LedCommand {
     /// Index of LED mode (zero-based)
     pub index: u8,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("3"), Ok(Duration::from_secs(3)));
+        assert!(parse_duration("2m").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+}
@@ -1,52 +1,207 @@
-///! Collection of NOM parsers for various things.
-///! Generally only `parse` and `from_str` functions should be called
-///! from outside of this module, they ensures that whole input is
-///! consumed.
-///! Other functions are composable parsers for use within this module
-///! or as parameters for functions mentioned above.
+//! Collection of NOM parsers for various things.
+//! Generally only `parse` and `from_str` functions should be called
+//! from outside of this module, they ensures that whole input is
+//! consumed.
+//! Other functions are composable parsers for use within this module
+//! or as parameters for functions mentioned above.
 
 use nom::{
-    Parser, IResult, InputLength,
+    Parser, InputLength,
     branch::alt,
-    sequence::{tuple, terminated, separated_pair, delimited, pair},
+    sequence::{tuple, terminated, separated_pair, delimited, pair, preceded},
     multi::{separated_list1, fold_many0},
-    bytes::complete::tag,
-    character::complete::{char, alpha1, alphanumeric1, digit1},
-    combinator::{map, map_res, opt, all_consuming, value},
-    error::ParseError,
+    bytes::complete::{tag_no_case, take_while1},
+    character::complete::{char, satisfy, digit1, hex_digit1, space0},
+    combinator::{map, map_opt, map_res, not, opt, all_consuming, value},
+    error::{ErrorKind, FromExternalError, ParseError},
 };
 
-use crate::keyboard::{Accord, Modifier, Modifiers, Macro, MouseEvent, MouseModifier, MouseButton, MouseButtons, MouseAction, MediaCode, Code, WellKnownCode};
+use crate::config::MacroRef;
+use crate::keyboard::{Accord, Modifier, Modifiers, Macro, MouseEvent, MouseModifier, MouseButton, MouseButtons, MouseAction, MediaCode, ConsumerCode, Code, WellKnownCode};
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::str::FromStr;
 
-fn mouse_modifier(s: &str) -> IResult<&str, MouseModifier> {
-    map_res(alpha1, MouseModifier::from_str)(s)
+use itertools::Itertools as _;
+
+type IResult<'a, O> = nom::IResult<&'a str, O, Failure<'a>>;
+
+/// Parser error pointing to the furthest position parser managed to reach,
+/// with descriptions of tokens expected there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure<'a> {
+    pub input: &'a str,
+    pub kind: ErrorKind,
+    pub expected: Vec<&'static str>,
+}
+
+impl<'a> ParseError<&'a str> for Failure<'a> {
+    fn from_error_kind(input: &'a str, kind: ErrorKind) -> Self {
+        Self { input, kind, expected: vec![] }
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    /// Keeps error of alternative which got further, so that error points
+    /// to the actual mistake and not to the start of input.
+    fn or(mut self, other: Self) -> Self {
+        match self.input.len().cmp(&other.input.len()) {
+            std::cmp::Ordering::Less => self,
+            std::cmp::Ordering::Greater => other,
+            std::cmp::Ordering::Equal => {
+                for expected in other.expected {
+                    if !self.expected.contains(&expected) {
+                        self.expected.push(expected);
+                    }
+                }
+                self
+            }
+        }
+    }
+}
+
+impl<'a, E> FromExternalError<&'a str, E> for Failure<'a> {
+    fn from_external_error(input: &'a str, kind: ErrorKind, _e: E) -> Self {
+        Self::from_error_kind(input, kind)
+    }
+}
+
+/// Describes what `parser` expects, for errors where it fails without consuming anything.
+fn expect<'a, O>(expected: &'static str, mut parser: impl FnMut(&'a str) -> IResult<'a, O>) -> impl FnMut(&'a str) -> IResult<'a, O> {
+    move |s| parser(s).map_err(|err| err.map(|mut failure| {
+        if failure.input.len() == s.len() {
+            failure.expected = vec![expected];
+        }
+        failure
+    }))
+}
+
+/// Parse error with owned input, returned by `FromStr` implementations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub input: String,
+    /// Byte offset where parsing stopped.
+    pub offset: usize,
+    pub kind: ErrorKind,
+    pub expected: Vec<&'static str>,
+}
+
+impl Error {
+    /// Rest of input starting at position where parsing stopped.
+    pub fn rest(&self) -> &str {
+        &self.input[self.offset..]
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rest = self.rest();
+        let column = self.input[..self.offset].chars().count() + 1;
+        match (self.kind, rest.chars().next()) {
+            // Whole value is parsed, but something is left after it.
+            (ErrorKind::Eof, Some(c)) => write!(f, "unexpected '{c}' at column {column} in '{}'", self.input),
+            _ => {
+                match self.expected.as_slice() {
+                    [] => write!(f, "invalid value")?,
+                    [expected] => write!(f, "expected {expected}")?,
+                    [init @ .., last] => write!(f, "expected {} or {last}", init.iter().format(", "))?,
+                }
+                if rest.is_empty() {
+                    write!(f, " at end of '{}'", self.input)
+                } else {
+                    write!(f, " at column {column} in '{}'", self.input)
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+thread_local! {
+    /// Key names defined by config being parsed, see `with_keycodes`.
+    static KEYCODES: RefCell<HashMap<String, Code>> = RefCell::new(HashMap::new());
+}
+
+/// Calls `f` with given custom key names accepted by `code` parser.
+/// Macros are parsed by `FromStr` implementations during deserialization,
+/// so there is no other way to pass names defined by config to them.
+pub fn with_keycodes<R>(keycodes: &HashMap<String, Code>, f: impl FnOnce() -> R) -> R {
+    let previous = KEYCODES.with(|k| k.replace(keycodes.clone()));
+    let result = f();
+    KEYCODES.with(|k| k.replace(previous));
+    result
+}
+
+/// Whether name is defined by config being parsed.
+pub fn is_keycode_name(name: &str) -> bool {
+    KEYCODES.with(|k| k.borrow().contains_key(name))
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Parses name consisting of letters, digits and underscores. Key names are
+/// parsed as whole words, so that custom names may start with built-in ones.
+pub fn name(s: &str) -> IResult<'_, &str> {
+    take_while1(is_name_char)(s)
+}
+
+/// Parses separator character, allowing spaces around it.
+fn sep<'a>(c: char) -> impl FnMut(&'a str) -> IResult<'a, char> {
+    delimited(space0, char(c), space0)
 }
 
-fn media_code(s: &str) -> IResult<&str, MediaCode> {
-    map_res(alpha1, MediaCode::from_str)(s)
+fn mouse_modifier(s: &str) -> IResult<'_, MouseModifier> {
+    map_res(name, MouseModifier::from_str)(s)
 }
 
-pub fn code(s: &str) -> IResult<&str, Code> {
+/// Parses 16-bit number, either decimal or hexadecimal with '0x' prefix.
+fn number_u16(s: &str) -> IResult<'_, u16> {
     let mut parser = alt((
+        map_res(preceded(tag_no_case("0x"), hex_digit1),
+                |digits| u16::from_str_radix(digits, 16)),
+        map_res(digit1, str::parse),
+    ));
+    parser(s)
+}
+
+fn media_code(s: &str) -> IResult<'_, ConsumerCode> {
+    let mut parser = expect("media key", alt((
+        // media<0x223> or consumer<547>
+        map(
+            preceded(alt((tag_no_case("media"), tag_no_case("consumer"))),
+                     delimited(char('<'), number_u16, char('>'))),
+            ConsumerCode::Custom),
+        map_res(name, |word| MediaCode::from_str(word).map(ConsumerCode::WellKnown)),
+    )));
+    parser(s)
+}
+
+pub fn code(s: &str) -> IResult<'_, Code> {
+    let mut parser = expect("key name", alt((
         map(
             delimited(char('<'),
                       map_res(digit1, str::parse),
                       char('>')),
             Code::Custom),
-        map_res(alphanumeric1,
-                |word| WellKnownCode::from_str(word).map(Code::WellKnown)),
-    ));
+        map_opt(name, |name| KEYCODES.with(|k| k.borrow().get(name).copied())),
+        map_res(name, |word| WellKnownCode::from_str(word).map(Code::WellKnown)),
+    )));
     parser(s)
 }
 
-pub fn modifier(s: &str) -> IResult<&str, Modifier> {
-    let mut parser = map_res(alpha1, Modifier::from_str);
+pub fn modifier(s: &str) -> IResult<'_, Modifier> {
+    let mut parser = expect("modifier", map_res(name, Modifier::from_str));
     parser(s)
 }
 
-pub fn accord(s: &str) -> IResult<&str, Accord> {
+pub fn accord(s: &str) -> IResult<'_, Accord> {
     enum Fix { Modifier(Modifier), Code(Code) }
 
     let mut parser = alt((
@@ -56,7 +211,7 @@ pub fn accord(s: &str) -> IResult<&str, Accord> {
 
         // (<modifier> '-')* (<code>|<modifier>)?
         map(pair(
-            fold_many0(terminated(modifier, char('-')),
+            fold_many0(terminated(modifier, sep('-')),
                        Modifiers::empty,
                        |mods, m| mods | m),
             alt((
@@ -71,24 +226,24 @@ pub fn accord(s: &str) -> IResult<&str, Accord> {
     parser(s)
 }
 
-fn mouse_event(s: &str) -> IResult<&str, MouseEvent> {
+fn mouse_event(s: &str) -> IResult<'_, MouseEvent> {
     let button = alt((
-        value(MouseButton::Left, alt((tag("click"), tag("lclick")))),
-        value(MouseButton::Right, tag("rclick")),
-        value(MouseButton::Middle, tag("mclick")),
+        value(MouseButton::Left, alt((tag_no_case("click"), tag_no_case("lclick")))),
+        value(MouseButton::Right, tag_no_case("rclick")),
+        value(MouseButton::Middle, tag_no_case("mclick")),
     ));
-    let buttons = map(separated_list1(char('+'), button), MouseButtons::from_iter);
+    let buttons = map(separated_list1(sep('+'), expect("mouse button", button)), MouseButtons::from_iter);
     let click = map(buttons, MouseAction::Click);
 
     let wheel = alt((
-        value(MouseAction::WheelUp, tag("wheelup")),
-        value(MouseAction::WheelDown, tag("wheeldown")),
+        value(MouseAction::WheelUp, tag_no_case("wheelup")),
+        value(MouseAction::WheelDown, tag_no_case("wheeldown")),
     ));
 
     let mut event = map(
         tuple((
-            opt(terminated(mouse_modifier, char('-'))),
-            alt((click, wheel)),
+            opt(terminated(mouse_modifier, sep('-'))),
+            terminated(expect("mouse action", alt((click, wheel))), not(satisfy(is_name_char))),
         )),
         |(modifier, action)| MouseEvent(action, modifier)
     );
@@ -96,17 +251,29 @@ fn mouse_event(s: &str) -> IResult<&str, MouseEvent> {
     event(s)
 }
 
-pub fn r#macro(s: &str) -> IResult<&str, Macro> {
-    let mut parser = alt((
+/// Parses macro. Spaces around separators and whole macro, as well as
+/// trailing comma, are allowed, since they are easily introduced by hand editing.
+pub fn r#macro(s: &str) -> IResult<'_, Macro> {
+    let mut parser = delimited(space0, expect("key name, media key or mouse action", alt((
         map(mouse_event, Macro::Mouse),
         map(media_code, Macro::Media),
-        map(separated_list1(char(','), accord), Macro::Keyboard),
+        map(terminated(separated_list1(sep(','), accord), opt(sep(','))), Macro::Keyboard),
+    ))), space0);
+    parser(s)
+}
+
+/// Parses either macro or reference to named macro: `$name`.
+pub fn macro_ref(s: &str) -> IResult<'_, MacroRef> {
+    let name = take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    let mut parser = alt((
+        map(delimited(space0, preceded(char('$'), name), space0), |name: &str| MacroRef::Named(name.to_owned())),
+        map(r#macro, MacroRef::Macro),
     ));
     parser(s)
 }
 
-pub fn address(s: &str) -> IResult<&str, (u8, u8)> {
-    let byte = || map_res(digit1, u8::from_str);
+pub fn address(s: &str) -> IResult<'_, (u8, u8)> {
+    let byte = || expect("number from 0 to 255", map_res(digit1, u8::from_str));
     let mut parser = separated_pair(byte(), char(':'), byte());
     parser(s)
 }
@@ -123,21 +290,23 @@ where
 }
 
 /// Parses string using given parser, as `parse` do, but also converts string reference
-/// in returned error to String, so it may be used in implementations of `FromStr`.
-pub fn from_str<O, P>(parser: P, s: &str) -> std::result::Result<O, nom::error::Error<String>>
+/// in returned error to offset, so it may be used in implementations of `FromStr`.
+pub fn from_str<O, P>(parser: P, s: &str) -> std::result::Result<O, Error>
 where
-    for <'a> P: Parser<&'a str, O, nom::error::Error<&'a str>>,
+    for <'a> P: Parser<&'a str, O, Failure<'a>>,
 {
-    match parse(parser, s) {
-        Ok(value) => Ok(value),
-        Err(nom::error::Error { input, code }) =>
-            Err(nom::error::Error { input: input.to_owned(), code }),
-    }
+    parse(parser, s).map_err(|Failure { input, kind, expected }| Error {
+        input: s.to_owned(),
+        offset: s.len() - input.len(),
+        kind,
+        expected,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::keyboard::{Accord, Modifiers, Code, Modifier, Macro, MouseEvent, MouseModifier, MouseButton, MouseAction, MediaCode, WellKnownCode};
+    use crate::keyboard::{Accord, Modifiers, Code, Modifier, Macro, MouseEvent, MouseModifier, MouseButton, MouseAction, MediaCode, ConsumerCode, WellKnownCode};
+    use strum::IntoEnumIterator as _;
 
     #[test]
     fn parse_custom_code() {
@@ -182,8 +351,143 @@ mod tests {
         )));
     }
 
+    #[test]
+    fn parse_macro_with_spaces() {
+        let canonical = |s: &str| s.parse::<Macro>().map(|m| m.to_string());
+        assert_eq!(canonical(" ctrl - A , B "), Ok("ctrl-a,b".to_owned()));
+        assert_eq!(canonical("ctrl-c,"), Ok("ctrl-c".to_owned()));
+        assert_eq!(canonical("a , b ,"), Ok("a,b".to_owned()));
+        assert_eq!(canonical("CTRL-WHEELUP"), Ok("ctrl-wheelup".to_owned()));
+        assert_eq!(canonical("Click + RClick"), Ok("click+rclick".to_owned()));
+        assert_eq!(canonical(" Play "), Ok("play".to_owned()));
+
+        assert!("a,,b".parse::<Macro>().is_err());
+        assert!(",a".parse::<Macro>().is_err());
+    }
+
+    #[test]
+    fn parse_keycode_names() {
+        let keycodes = [("mute_mic".to_owned(), Code::Custom(239))].into();
+        let parsed = super::with_keycodes(&keycodes, || "ctrl-mute_mic,a".parse::<Macro>());
+        assert_eq!(parsed, Ok(Macro::Keyboard(vec![
+            Accord::new(Modifier::Ctrl, Some(Code::Custom(239))),
+            Accord::new(Modifiers::empty(), Some(WellKnownCode::A.into())),
+        ])));
+        assert!("mute_mic".parse::<Macro>().is_err());
+    }
+
     #[test]
     fn parse_media() {
-        assert_eq!("play".parse(), Ok(Macro::Media(MediaCode::Play)));
+        assert_eq!("play".parse(), Ok(Macro::Media(MediaCode::Play.into())));
+        assert_eq!("BrightnessUp".parse(), Ok(Macro::Media(MediaCode::BrightnessUp.into())));
+        assert_eq!("browserback".parse(), Ok(Macro::Media(MediaCode::BrowserBack.into())));
+        assert_eq!("sleep".parse(), Ok(Macro::Media(MediaCode::Sleep.into())));
+    }
+
+    #[test]
+    fn parse_custom_media() {
+        assert_eq!("media<0x223>".parse(), Ok(Macro::Media(ConsumerCode::Custom(0x223))));
+        assert_eq!("consumer<547>".parse(), Ok(Macro::Media(ConsumerCode::Custom(547))));
+        assert_eq!("media<0x223>".parse::<Macro>().unwrap().to_string(), "media<0x223>");
+
+        assert!("media<70000>".parse::<Macro>().is_err());
+        assert!("media<0x10000>".parse::<Macro>().is_err());
+        assert!("media<>".parse::<Macro>().is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |s: &str| s.parse::<Macro>().unwrap_err().to_string();
+        assert_eq!(error("ctrl-+a"), "expected mouse action, key name or modifier at column 6 in 'ctrl-+a'");
+        assert_eq!(error("ctrl-"), "expected mouse action, key name or modifier at end of 'ctrl-'");
+        assert_eq!(error("+a"), "expected key name, media key or mouse action at column 1 in '+a'");
+        assert_eq!(error("click+x"), "unexpected '+' at column 6 in 'click+x'");
+        assert_eq!(error("a,,b"), "unexpected ',' at column 3 in 'a,,b'");
+        assert_eq!(error(""), "expected key name, media key or mouse action at end of ''");
+
+        let error = super::from_str(super::address, "1:x").unwrap_err();
+        assert_eq!(error.offset, 2);
+        assert_eq!(error.to_string(), "expected number from 0 to 255 at column 3 in '1:x'");
+    }
+
+    /// Simple xorshift generator, so that generated values are the same on every run.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+            items[self.next(items.len())].clone()
+        }
+    }
+
+    fn random_macro(random: &mut Random) -> Macro {
+        let codes = WellKnownCode::iter().map(Code::WellKnown).chain((0..=255).map(Code::Custom)).collect::<Vec<_>>();
+        let modifiers = Modifier::iter().collect::<Vec<_>>();
+        match random.next(3) {
+            0 => Macro::Keyboard((0..1 + random.next(4)).map(|_| {
+                let mods = (0..random.next(4)).map(|_| random.pick(&modifiers)).collect::<Modifiers>();
+                // Accord must have either modifiers or key.
+                let code = if mods.is_empty() || random.next(4) > 0 { Some(random.pick(&codes)) } else { None };
+                Accord::new(mods, code)
+            }).collect()),
+            1 => Macro::Media(if random.next(2) == 0 {
+                ConsumerCode::WellKnown(random.pick(&MediaCode::iter().collect::<Vec<_>>()))
+            } else {
+                ConsumerCode::Custom(random.next(0x10000) as u16)
+            }),
+            _ => {
+                let action = match random.next(3) {
+                    0 => MouseAction::WheelUp,
+                    1 => MouseAction::WheelDown,
+                    _ => MouseAction::Click(MouseButton::iter().filter(|_| random.next(2) == 0).collect()),
+                };
+                let modifier = random.pick(&[None, Some(MouseModifier::Ctrl), Some(MouseModifier::Shift), Some(MouseModifier::Alt)]);
+                Macro::Mouse(MouseEvent(action, modifier))
+            }
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for code in WellKnownCode::iter() {
+            let macro_ = Macro::Keyboard(vec![Accord::new(Modifiers::empty(), Some(code.into()))]);
+            assert_eq!(macro_.to_string().parse(), Ok(macro_));
+        }
+        for code in MediaCode::iter() {
+            let macro_ = Macro::Media(code.into());
+            assert_eq!(macro_.to_string().parse(), Ok(macro_));
+        }
+
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        for _ in 0..10000 {
+            let mut macro_ = random_macro(&mut random);
+            if let Macro::Mouse(MouseEvent(MouseAction::Click(buttons), _)) = &mut macro_ {
+                if buttons.is_empty() {
+                    *buttons = MouseButton::Left.into();
+                }
+            }
+            let s = macro_.to_string();
+            assert_eq!(s.parse(), Ok(macro_), "round trip of '{s}'");
+        }
+    }
+
+    #[test]
+    fn parse_garbage() {
+        // Parser must fail gracefully on any input, error offset must point into it.
+        let pieces = ["a", "ctrl", "-", ",", "+", "<", ">", "0x", "12", "click", "media", " ", "$", "é", "wheelup", "_"];
+        let mut random = Random(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..10000 {
+            let s: String = (0..random.next(8)).map(|_| random.pick(&pieces)).collect();
+            if let Err(error) = s.parse::<Macro>() {
+                assert!(s.is_char_boundary(error.offset), "offset of error in '{s}'");
+                assert!(!error.to_string().is_empty());
+            }
+        }
     }
 }
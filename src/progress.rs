@@ -0,0 +1,89 @@
+//! Upload progress printed to stderr.
+
+use std::io::IsTerminal as _;
+
+use itertools::Itertools as _;
+
+use ch57x_keyboard::keyboard::{padded_len, Key, Macro};
+use ch57x_keyboard::secrets::SecretNames;
+
+const BAR_WIDTH: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    /// Each key with its messages is printed instead of progress bar.
+    /// Macros typing secrets are shown by secret names, without messages.
+    Verbose,
+}
+
+pub struct Progress {
+    verbosity: Verbosity,
+    /// Whether bar is drawn, it is only done on terminal.
+    bar: bool,
+    report_id: Option<u8>,
+    /// Reports are padded to multiple of this size when sent.
+    packet_size: usize,
+    total: usize,
+    done: usize,
+    bytes: usize,
+    secrets: SecretNames,
+}
+
+impl Progress {
+    pub fn new(verbosity: Verbosity, report_id: Option<u8>, packet_size: usize, total: usize, secrets: SecretNames) -> Self {
+        let bar = verbosity == Verbosity::Normal && std::io::stderr().is_terminal();
+        Self { verbosity, bar, report_id, packet_size, total, done: 0, bytes: 0, secrets }
+    }
+
+    /// Called before key is bound with messages which will be sent, without report ID.
+    pub fn key(&mut self, layer: u8, key: Key, macro_: &Macro, messages: &[Vec<u8>]) {
+        self.done += 1;
+        let report_len = |msg: &Vec<u8>| msg.len() + usize::from(self.report_id.is_some());
+        self.bytes += messages.iter().map(|msg| padded_len(report_len(msg), self.packet_size)).sum::<usize>();
+        match self.verbosity {
+            Verbosity::Quiet => {}
+            Verbosity::Normal if self.bar => {
+                // Line is cleared after text, so that shorter one doesn't leave tail of previous one.
+                eprint!("\r{} layer {}, {key}\x1b[K", bar_line(self.done, self.total, self.bytes), layer + 1);
+            }
+            Verbosity::Normal => {}
+            Verbosity::Verbose => {
+                eprintln!("{} layer {}, {key}: {}", bar_line(self.done, self.total, self.bytes), layer + 1, self.secrets.show(macro_));
+                if self.secrets.name(macro_).is_some() {
+                    eprintln!("  (messages hidden)");
+                    return;
+                }
+                for msg in messages {
+                    let report = self.report_id.into_iter().chain(msg.iter().copied());
+                    eprintln!("  {:02x}", report.format(" "));
+                }
+            }
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if self.bar && self.done > 0 {
+            eprintln!();
+        }
+    }
+}
+
+fn bar_line(done: usize, total: usize, bytes: usize) -> String {
+    let filled = (done * BAR_WIDTH).checked_div(total).unwrap_or(BAR_WIDTH).min(BAR_WIDTH);
+    format!("[{}{}] {done}/{total} keys, {bytes} bytes", "#".repeat(filled), " ".repeat(BAR_WIDTH - filled))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bar_line() {
+        assert_eq!(bar_line(0, 4, 0), "[                    ] 0/4 keys, 0 bytes");
+        assert_eq!(bar_line(1, 4, 128), "[#####               ] 1/4 keys, 128 bytes");
+        assert_eq!(bar_line(4, 4, 512), "[####################] 4/4 keys, 512 bytes");
+        assert_eq!(bar_line(0, 0, 0), "[####################] 0/0 keys, 0 bytes");
+    }
+}
@@ -0,0 +1,149 @@
+//! Python bindings, built with `python` feature by maturin, see `pyproject.toml`.
+//!
+//! Failures raise `Ch57xError`, its `code` attribute has the same value as exit
+//! code of command-line tool.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context as _, Result};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::config::{Config, TargetOs};
+use crate::device::{Device, DeviceFilter, EndpointOptions};
+use crate::exit::{ConfigError, ExitCode};
+use crate::hid::DEFAULT_REPORT_ID;
+use crate::keyboard::{Key, Macro, Model};
+use crate::lint;
+
+create_exception!(ch57x_keyboard, Ch57xError, PyException);
+
+fn error(py: Python<'_>, err: anyhow::Error) -> PyErr {
+    let exception = Ch57xError::new_err(format!("{err:#}"));
+    if let Err(set_err) = exception.value(py).setattr("code", ExitCode::of(&err) as i32) {
+        return set_err;
+    }
+    exception
+}
+
+fn load_config(path: &Path) -> Result<Config> {
+    Config::load(path).context(ConfigError("load mapping config"))
+}
+
+/// Bindings of rendered config as `(layer, key, macro)`.
+type Bindings = Vec<(usize, Key, Macro)>;
+
+/// Loads config and renders it for given OS, host one by default.
+fn render_bindings(path: &Path, os: Option<&str>) -> Result<(Config, Bindings)> {
+    let os = match os {
+        Some(os) => os.parse().map_err(|_| anyhow!("unknown OS '{os}'"))?,
+        None => TargetOs::host(),
+    };
+    let config = load_config(path)?;
+    let layers = config.clone().render_for(os).context(ConfigError("render mapping config"))?;
+    let bindings = layers.iter().enumerate()
+        .flat_map(|(layer_idx, layer)| layer.bindings().map(move |(key, macro_)| (layer_idx, key, macro_.clone())))
+        .collect();
+    Ok((config, bindings))
+}
+
+/// Renders config into list of bindings like `{"layer": 0, "key": "button 0", "macro": "ctrl-c"}`.
+/// OS-specific macros are chosen for `os` ("macos", "linux" or "windows"), host OS by default.
+#[pyfunction]
+#[pyo3(signature = (path, os=None))]
+fn render<'py>(py: Python<'py>, path: PathBuf, os: Option<&str>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let (config, bindings) = render_bindings(&path, os).map_err(|err| error(py, err))?;
+    bindings.iter().map(|(layer_idx, key, macro_)| {
+        let binding = PyDict::new(py);
+        binding.set_item("layer", layer_idx)?;
+        binding.set_item("key", key.to_string())?;
+        binding.set_item("macro", config.secrets.show(macro_))?;
+        Ok(binding)
+    }).collect()
+}
+
+/// Same as `render`, with messages binding keys of given model ("884x" or "8890"),
+/// prefixed with default report ID. Messages of macros typing secrets are left out.
+#[pyfunction]
+#[pyo3(signature = (path, model, os=None))]
+fn encode<'py>(py: Python<'py>, path: PathBuf, model: &str, os: Option<&str>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let encoded = || -> Result<_> {
+        let model: Model = model.parse().map_err(|_| anyhow!("unknown model '{model}'"))?;
+        let (config, bindings) = render_bindings(&path, os)?;
+        let key_ids = model.key_id_layout(Some(config.geometry()));
+        let encoded = bindings.into_iter().map(|(layer_idx, key, macro_)| {
+            let key_id = key.to_key_id(key_ids)?;
+            let mut messages = model.bind_key_messages(key_ids, layer_idx as u8, key, &macro_)?;
+            if config.secrets.name(&macro_).is_some() {
+                messages.clear();
+            }
+            let messages = messages.into_iter().map(|msg| [&[DEFAULT_REPORT_ID], msg.as_slice()].concat()).collect::<Vec<_>>();
+            Ok((layer_idx, key, config.secrets.show(&macro_), key_id, messages))
+        }).collect::<Result<Vec<_>>>().context(ConfigError("encode mapping config"))?;
+        Ok(encoded)
+    };
+    let encoded = encoded().map_err(|err| error(py, err))?;
+    encoded.into_iter().map(|(layer_idx, key, macro_, key_id, messages)| {
+        let binding = PyDict::new(py);
+        binding.set_item("layer", layer_idx)?;
+        binding.set_item("key", key.to_string())?;
+        binding.set_item("macro", macro_)?;
+        binding.set_item("key_id", key_id)?;
+        binding.set_item("messages", messages.iter().map(|msg| PyBytes::new(py, msg)).collect::<Vec<_>>())?;
+        Ok(binding)
+    }).collect()
+}
+
+/// Checks config, returns warnings like `validate` command prints.
+#[pyfunction]
+fn validate(py: Python<'_>, path: PathBuf) -> PyResult<Vec<String>> {
+    let warnings = || -> Result<_> {
+        let config = load_config(&path)?;
+        let secrets = config.secrets.clone();
+        let layers = config.render().context(ConfigError("render mapping config"))?;
+        Ok(lint::lint(&layers, &secrets).iter().map(ToString::to_string).collect())
+    };
+    warnings().map_err(|err| error(py, err))
+}
+
+/// Describes the only connected keyboard: `vendor_id`, `product_id`, `release` and
+/// `model`, `max_macro_length` and `led` which are `None` for devices unknown to quirks.
+#[pyfunction]
+fn find_device(py: Python<'_>) -> PyResult<Bound<'_, PyDict>> {
+    let device = Device::find(&DeviceFilter::default()).context("find USB device").map_err(|err| error(py, err))?;
+    let key = device.key();
+    let caps = device.capabilities();
+    let info = PyDict::new(py);
+    info.set_item("vendor_id", key.vendor_id)?;
+    info.set_item("product_id", key.product_id)?;
+    info.set_item("release", key.release())?;
+    info.set_item("model", caps.map(|caps| caps.model.to_string()))?;
+    info.set_item("max_macro_length", caps.and_then(|caps| caps.max_macro_length))?;
+    info.set_item("led", caps.map(|caps| caps.led))?;
+    Ok(info)
+}
+
+/// Uploads config to the only connected keyboard, returns number of bound keys.
+/// Unlike `upload` command, it doesn't keep backups and state of device.
+#[pyfunction]
+fn upload(py: Python<'_>, path: PathBuf) -> PyResult<usize> {
+    let upload = || -> Result<usize> {
+        let config = load_config(&path)?;
+        let device = Device::find(&DeviceFilter::default()).context("find USB device")?;
+        device.upload_config(&EndpointOptions::default(), config)
+    };
+    upload().map_err(|err| error(py, err))
+}
+
+#[pymodule]
+fn ch57x_keyboard(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("Ch57xError", m.py().get_type::<Ch57xError>())?;
+    m.add_function(wrap_pyfunction!(render, m)?)?;
+    m.add_function(wrap_pyfunction!(encode, m)?)?;
+    m.add_function(wrap_pyfunction!(validate, m)?)?;
+    m.add_function(wrap_pyfunction!(find_device, m)?)?;
+    m.add_function(wrap_pyfunction!(upload, m)?)?;
+    Ok(())
+}
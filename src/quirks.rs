@@ -0,0 +1,221 @@
+//! Database of per-device oddities.
+//!
+//! Clones of supported keyboards reuse vendor and product IDs, so quirks may
+//! also be keyed by device release number (`bcdDevice`) and number of USB
+//! interfaces. Built-in quirks may be extended or overridden with
+//! `quirks.toml` in user config directory, so that new clones can be
+//! supported without new release:
+//!
+//! ```toml
+//! [[quirk]]
+//! vendor_id = 0x1189
+//! product_id = 0x8850
+//! model = "884x"
+//! endpoint = 0x04
+//! ```
+
+use std::path::PathBuf;
+
+use anyhow::{Context as _, Result};
+use itertools::Itertools as _;
+use serde::Deserialize;
+
+use crate::consts::VENDOR_ID;
+use crate::keyboard::Model;
+
+/// What device is identified by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceKey {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bcd_device: u16,
+    pub interfaces: u8,
+}
+
+impl DeviceKey {
+    /// Device release as it is usually written, like `1.00` for `0x0100`.
+    pub fn release(&self) -> String {
+        format!("{:x}.{:02x}", self.bcd_device >> 8, self.bcd_device & 0xff)
+    }
+}
+
+/// Properties of matching devices, fields which are not given are taken
+/// from other matching quirks.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quirk {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Device release number, any if not given.
+    #[serde(default)]
+    pub bcd_device: Option<u16>,
+    /// Number of USB interfaces, any if not given.
+    #[serde(default)]
+    pub interfaces: Option<u8>,
+
+    /// Protocol family to program device with.
+    #[serde(default)]
+    pub model: Option<Model>,
+    /// Programming endpoint address, model's default one is used if not given.
+    #[serde(default)]
+    pub endpoint: Option<u8>,
+    /// Maximum number of steps in macro sequence, each chord takes one.
+    #[serde(default)]
+    pub max_macro_length: Option<usize>,
+    /// Whether each modifier of chord takes additional step of macro sequence.
+    #[serde(default)]
+    pub modifier_steps: Option<bool>,
+    /// Whether LED mode may be set.
+    #[serde(default)]
+    pub led: Option<bool>,
+}
+
+impl Quirk {
+    fn matches(&self, key: &DeviceKey) -> bool {
+        self.vendor_id == key.vendor_id
+            && self.product_id == key.product_id
+            && self.bcd_device.is_none_or(|bcd| bcd == key.bcd_device)
+            && self.interfaces.is_none_or(|n| n == key.interfaces)
+    }
+}
+
+/// Resolved properties of device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub model: Model,
+    pub endpoint: Option<u8>,
+    pub max_macro_length: Option<usize>,
+    pub modifier_steps: bool,
+    pub led: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quirks {
+    #[serde(default, rename = "quirk")]
+    quirks: Vec<Quirk>,
+}
+
+impl Quirks {
+    pub fn builtin() -> Self {
+        let quirk = |product_id, model, max_macro_length, led| Quirk {
+            vendor_id: VENDOR_ID,
+            product_id,
+            model: Some(model),
+            max_macro_length: Some(max_macro_length),
+            led: Some(led),
+            ..Default::default()
+        };
+        Self {
+            quirks: vec![
+                quirk(0x8840, Model::K884x, 18, false),
+                quirk(0x8842, Model::K884x, 18, false),
+                quirk(0x8890, Model::K8890, 5, true),
+            ],
+        }
+    }
+
+    /// Built-in quirks followed by user ones, if user file exists.
+    pub fn load() -> Result<Self> {
+        let mut quirks = Self::builtin();
+        let Some(path) = user_quirks_path() else {
+            return Ok(quirks);
+        };
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(quirks),
+            Err(err) => return Err(err).with_context(|| format!("read {}", path.display())),
+        };
+        let user: Quirks = toml::from_str(&content).with_context(|| format!("parse {}", path.display()))?;
+        quirks.quirks.extend(user.quirks);
+        Ok(quirks)
+    }
+
+    /// Vendor and product IDs of all known devices, without duplicates.
+    pub fn device_ids(&self) -> Vec<(u16, u16)> {
+        self.quirks.iter().map(|q| (q.vendor_id, q.product_id)).unique().collect()
+    }
+
+    /// Product ID of first known device of given model.
+    pub fn product_id(&self, model: Model) -> Option<u16> {
+        self.quirks.iter().find(|q| q.model == Some(model)).map(|q| q.product_id)
+    }
+
+    /// Merges all quirks matching device, later ones take precedence.
+    /// Returns `None` if device model is unknown.
+    pub fn capabilities(&self, key: &DeviceKey) -> Option<Capabilities> {
+        let mut merged = Quirk::default();
+        for quirk in self.quirks.iter().filter(|q| q.matches(key)) {
+            merged.model = quirk.model.or(merged.model);
+            merged.endpoint = quirk.endpoint.or(merged.endpoint);
+            merged.max_macro_length = quirk.max_macro_length.or(merged.max_macro_length);
+            merged.modifier_steps = quirk.modifier_steps.or(merged.modifier_steps);
+            merged.led = quirk.led.or(merged.led);
+        }
+        Some(Capabilities {
+            model: merged.model?,
+            endpoint: merged.endpoint,
+            max_macro_length: merged.max_macro_length,
+            modifier_steps: merged.modifier_steps.unwrap_or(false),
+            led: merged.led.unwrap_or(false),
+        })
+    }
+}
+
+fn user_quirks_path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("ch57x-keyboard-tool").join("quirks.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    fn key(product_id: u16, bcd_device: u16) -> DeviceKey {
+        DeviceKey { vendor_id: VENDOR_ID, product_id, bcd_device, interfaces: 3 }
+    }
+
+    #[test]
+    fn test_builtin() {
+        let quirks = Quirks::builtin();
+        let caps = quirks.capabilities(&key(0x8842, 0x0100)).unwrap();
+        assert_eq!(caps, Capabilities {
+            model: Model::K884x, endpoint: None, max_macro_length: Some(18), modifier_steps: false, led: false,
+        });
+        assert_eq!(quirks.capabilities(&key(0x1234, 0x0100)), None);
+        assert_eq!(quirks.device_ids(), vec![(VENDOR_ID, 0x8840), (VENDOR_ID, 0x8842), (VENDOR_ID, 0x8890)]);
+    }
+
+    #[test]
+    fn test_release() {
+        assert_eq!(key(0x8890, 0x0100).release(), "1.00");
+        assert_eq!(key(0x8890, 0x1203).release(), "12.03");
+    }
+
+    #[test]
+    fn test_user_quirks() -> anyhow::Result<()> {
+        let mut quirks = Quirks::builtin();
+        let user: Quirks = toml::from_str(indoc! {r#"
+            [[quirk]]
+            vendor_id = 0x1189
+            product_id = 0x8850
+            model = "884x"
+
+            [[quirk]]
+            vendor_id = 0x1189
+            product_id = 0x8840
+            bcd_device = 0x0200
+            endpoint = 0x02
+            modifier_steps = true
+        "#})?;
+        quirks.quirks.extend(user.quirks);
+
+        assert_eq!(quirks.capabilities(&key(0x8850, 0x0100)).map(|c| c.model), Some(Model::K884x));
+        assert_eq!(quirks.capabilities(&key(0x8840, 0x0100)).unwrap().endpoint, None);
+        let caps = quirks.capabilities(&key(0x8840, 0x0200)).unwrap();
+        assert_eq!((caps.model, caps.endpoint, caps.max_macro_length), (Model::K884x, Some(0x02), Some(18)));
+        assert!(caps.modifier_steps);
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+//! Reading captured reports to replay them to device.
+//!
+//! Captures are expected in simple text format: one report per line as hex
+//! bytes, optionally separated by spaces or colons, like Wireshark's "Copy as
+//! Hex Stream" gives. Empty lines and lines starting with `#` are ignored.
+
+use anyhow::{ensure, Context as _, Result};
+
+pub fn parse_hex_lines(text: &str) -> Result<Vec<Vec<u8>>> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| parse_hex_line(line).with_context(|| format!("line {n}")))
+        .collect()
+}
+
+fn parse_hex_line(line: &str) -> Result<Vec<u8>> {
+    let digits: String = line.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+    ensure!(digits.chars().all(|c| c.is_ascii_hexdigit()), "invalid hex digits in '{line}'");
+    ensure!(digits.len().is_multiple_of(2), "odd number of hex digits");
+    (0..digits.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(Into::into))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex_lines() {
+        let text = "# key 1\n03 fe 01 01\n\n03:fe:02\n03fe03\n";
+        assert_eq!(parse_hex_lines(text).unwrap(), vec![
+            vec![0x03, 0xfe, 0x01, 0x01],
+            vec![0x03, 0xfe, 0x02],
+            vec![0x03, 0xfe, 0x03],
+        ]);
+        assert!(parse_hex_lines("03 f").is_err());
+        assert!(parse_hex_lines("zz").is_err());
+    }
+}
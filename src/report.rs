@@ -0,0 +1,128 @@
+//! Report for issues: environment, connected device and log tail gathered
+//! into single text block, with user-specific data redacted.
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+
+use crate::options::Options;
+
+/// Builds report, problems with gathering some of information are reported in it.
+pub fn build(options: &Options, log: Option<&Path>, lines: usize) -> Result<String> {
+    let mut text = String::new();
+    writeln!(text, "```text")?;
+    writeln!(text, "tool:        ch57x-keyboard-tool {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(text, "os:          {} {}", std::env::consts::OS, std::env::consts::ARCH)?;
+    if let Some(version) = os_version() {
+        writeln!(text, "os version:  {version}")?;
+    }
+
+    let mut serial = None;
+    writeln!(text)?;
+    match crate::find_device(options) {
+        Ok(device) => {
+            serial = device.serial_number().ok().flatten();
+            let key = device.key();
+            writeln!(text, "device:      {:04x}:{:04x}, firmware {}", key.vendor_id, key.product_id, key.release())?;
+            match device.capabilities() {
+                Some(caps) => writeln!(text, "model:       {}", caps.model)?,
+                None => writeln!(text, "model:       unknown")?,
+            }
+            match device.programming_endpoint(&crate::endpoint_options(options)) {
+                Ok((interface, endpoint, packet_size)) => writeln!(text,
+                    "programming: interface {interface}, endpoint {endpoint:#04x}, packet size {packet_size}")?,
+                Err(err) => writeln!(text, "programming: {err:#}")?,
+            }
+            match device.describe_descriptors() {
+                Ok(descriptors) => text += &descriptors,
+                Err(err) => writeln!(text, "descriptors: {err:#}")?,
+            }
+        }
+        Err(err) => writeln!(text, "device:      {err:#}")?,
+    }
+
+    if let Some(log) = log {
+        writeln!(text)?;
+        let content = std::fs::read_to_string(log).with_context(|| format!("read log {}", log.display()))?;
+        let tail = tail(&content, lines);
+        writeln!(text, "last {} lines of log:", tail.len())?;
+        for line in tail {
+            writeln!(text, "{line}")?;
+        }
+    }
+    writeln!(text, "```")?;
+
+    let mut private = vec![];
+    if let Some(home) = dirs::home_dir() {
+        private.push((home.to_string_lossy().into_owned(), "~"));
+    }
+    if let Some(user) = ["USER", "USERNAME"].into_iter().find_map(|name| std::env::var(name).ok()) {
+        private.push((user, "<user>"));
+    }
+    if let Some(serial) = serial {
+        private.push((serial, "<serial>"));
+    }
+    Ok(redact(&text, &private))
+}
+
+/// OS release, like `Ubuntu 24.04 LTS`.
+fn os_version() -> Option<String> {
+    if cfg!(target_os = "linux") {
+        let release = std::fs::read_to_string("/etc/os-release").ok()?;
+        let name = release.lines().find_map(|line| line.strip_prefix("PRETTY_NAME="))?;
+        Some(name.trim_matches('"').to_owned())
+    } else {
+        let mut command = if cfg!(windows) {
+            let mut command = std::process::Command::new("cmd");
+            command.args(["/C", "ver"]);
+            command
+        } else {
+            std::process::Command::new("sw_vers")
+        };
+        let output = command.output().ok()?;
+        let version = String::from_utf8_lossy(&output.stdout).split_whitespace().collect::<Vec<_>>().join(" ");
+        (!version.is_empty()).then_some(version)
+    }
+}
+
+/// Last `count` lines of text.
+fn tail(text: &str, count: usize) -> Vec<&str> {
+    let lines = text.lines().collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(count)..].to_vec()
+}
+
+/// Replaces private strings with placeholders, longer ones first, so that home
+/// directory containing user name is replaced as a whole. Empty and very short
+/// strings are skipped since they would mangle unrelated text.
+fn redact(text: &str, private: &[(String, &str)]) -> String {
+    let mut private = private.iter().filter(|(s, _)| s.len() >= 3).collect::<Vec<_>>();
+    private.sort_by_key(|(s, _)| std::cmp::Reverse(s.len()));
+    private.iter().fold(text.to_owned(), |text, (s, placeholder)| text.replace(s.as_str(), placeholder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tail() {
+        assert_eq!(tail("a\nb\nc\n", 2), ["b", "c"]);
+        assert_eq!(tail("a\nb", 5), ["a", "b"]);
+        assert!(tail("", 5).is_empty());
+    }
+
+    #[test]
+    fn test_redact() {
+        let private = [
+            ("alice".to_owned(), "<user>"),
+            ("/home/alice".to_owned(), "~"),
+            ("A1B2C3".to_owned(), "<serial>"),
+            ("".to_owned(), "<empty>"),
+        ];
+        assert_eq!(
+            redact("config /home/alice/pad.yaml of alice, serial A1B2C3", &private),
+            "config ~/pad.yaml of <user>, serial <serial>",
+        );
+    }
+}
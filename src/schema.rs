@@ -0,0 +1,180 @@
+//! JSON Schema of config, for YAML language servers to validate
+//! and autocomplete configs.
+
+use itertools::Itertools as _;
+use serde_json::{json, Value};
+use strum::{EnumMessage as _, IntoEnumIterator as _};
+
+use ch57x_keyboard::config::Orientation;
+use ch57x_keyboard::keyboard::{MediaCode, MouseAction, MouseButton, WellKnownCode};
+
+pub fn schema() -> Value {
+    let orientation = json!({
+        "enum": Orientation::ALL.iter().map(|o| o.to_string()).collect_vec(),
+    });
+
+    // Plain names are listed for autocompletion, any other macro is accepted as string,
+    // full macro syntax can't be expressed with schema.
+    let names = WellKnownCode::iter().map(|c| c.to_string())
+        .chain(MediaCode::iter().flat_map(|c| c.get_serializations().iter().map(|s| s.to_string()).collect_vec()))
+        .chain(MouseButton::iter().map(|b| b.to_string()))
+        .chain([MouseAction::WheelUp.to_string(), MouseAction::WheelDown.to_string()])
+        .collect_vec();
+    let macro_ = json!({
+        "anyOf": [
+            { "enum": names },
+            {
+                "type": "string",
+                "description": "Comma-separated chords of modifiers and key like 'ctrl-c,alt-tab', \
+                                media key, mouse action or '$name' of named macro",
+            },
+        ],
+    });
+    let by_os = json!({
+        "type": "object",
+        "description": "Macros by OS, 'default' one is used for OSes not listed",
+        "properties": {
+            "macos": { "$ref": "#/$defs/macro" },
+            "linux": { "$ref": "#/$defs/macro" },
+            "windows": { "$ref": "#/$defs/macro" },
+            "default": { "$ref": "#/$defs/macro" },
+        },
+        "additionalProperties": false,
+    });
+    let binding = json!({
+        "anyOf": [
+            { "$ref": "#/$defs/macro" },
+            { "$ref": "#/$defs/byOs" },
+            {
+                "type": "object",
+                "properties": {
+                    "map": { "anyOf": [{ "$ref": "#/$defs/macro" }, { "$ref": "#/$defs/byOs" }] },
+                    "label": { "type": "string", "description": "Description of key for cheat sheet" },
+                },
+                "required": ["map"],
+                "additionalProperties": false,
+            },
+            { "type": "null" },
+        ],
+    });
+    let layer = json!({
+        "type": "object",
+        "properties": {
+            "orientation": { "$ref": "#/$defs/orientation" },
+            "buttons": {
+                "type": "array",
+                "description": "Rows of buttons as seen in config orientation",
+                "items": { "type": "array", "items": { "$ref": "#/$defs/binding" } },
+            },
+            "knobs": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "ccw": { "$ref": "#/$defs/binding" },
+                        "press": { "$ref": "#/$defs/binding" },
+                        "cw": { "$ref": "#/$defs/binding" },
+                        "scroll": {
+                            "enum": ["ctrl", "shift", "alt", "none"],
+                            "description": "Scroll wheel with modifier held on rotation, instead of ccw and cw",
+                        },
+                    },
+                    "additionalProperties": false,
+                },
+            },
+        },
+        "additionalProperties": false,
+    });
+
+    // Required fields aren't listed, since they may come from extended config.
+    let config_properties = json!({
+        "extends": { "type": "string", "description": "Path to config this one extends" },
+        "orientation": { "$ref": "#/$defs/orientation" },
+        "rows": { "type": "integer", "minimum": 1 },
+        "columns": { "type": "integer", "minimum": 1 },
+        "knobs": { "type": "integer", "minimum": 0 },
+        "variant": {
+            "type": "string",
+            "pattern": "^[0-9]+\\+[0-9]+$",
+            "description": "Keyboard variant as number of buttons and knobs, like '12+4'",
+        },
+        "keycodes": {
+            "type": "object",
+            "description": "Custom key names, like 'mute_mic: <239>'",
+            "propertyNames": { "pattern": "^[A-Za-z0-9_]+$" },
+            "additionalProperties": { "type": "string" },
+        },
+        "macros": {
+            "type": "object",
+            "description": "Named macros, referenced as '$name'",
+            "additionalProperties": { "anyOf": [{ "$ref": "#/$defs/macro" }, { "$ref": "#/$defs/byOs" }] },
+        },
+        "led": {
+            "description": "LED mode, it is set for the first layer only",
+            "type": "integer",
+            "minimum": 0,
+        },
+        "hooks": {
+            "type": "object",
+            "description": "Shell commands run after config is validated or uploaded",
+            "properties": {
+                "validate": { "type": "string" },
+                "upload": { "type": "string" },
+            },
+            "additionalProperties": false,
+        },
+        "layers": { "type": "array", "items": { "anyOf": [{ "$ref": "#/$defs/layer" }, { "type": "null" }] } },
+    });
+
+    let mut device_properties = config_properties.clone();
+    let selectors = json!({
+        "product_id": { "type": "integer" },
+        "address": { "type": "string", "pattern": "^[0-9]+:[0-9]+$", "description": "USB bus and address, like '1:5'" },
+        "serial": { "type": "string" },
+    });
+    device_properties.as_object_mut().unwrap().extend(selectors.as_object().unwrap().clone());
+
+    let mut properties = config_properties;
+    properties["devices"] = json!({
+        "type": "array",
+        "items": { "type": "object", "properties": device_properties, "additionalProperties": false },
+    });
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "ch57x-keyboard-tool config",
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+        "$defs": {
+            "orientation": orientation,
+            "macro": macro_,
+            "byOs": by_os,
+            "binding": binding,
+            "layer": layer,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_covers_example() -> anyhow::Result<()> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("example-mapping.yaml");
+        let example: Value = serde_yaml::from_reader(std::fs::File::open(path)?)?;
+        let schema = schema();
+
+        for field in example.as_object().unwrap().keys() {
+            assert!(schema["properties"].get(field).is_some(), "{field}");
+        }
+        for layer in example["layers"].as_array().unwrap() {
+            for field in layer.as_object().unwrap().keys() {
+                assert!(schema["$defs"]["layer"]["properties"].get(field).is_some(), "{field}");
+            }
+        }
+        assert!(schema["$defs"]["macro"]["anyOf"][0]["enum"].as_array().unwrap().contains(&json!("volumeup")));
+        Ok(())
+    }
+}
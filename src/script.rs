@@ -0,0 +1,105 @@
+//! Scripts with one command per line, run by `run` command.
+
+use std::path::Path;
+
+use anyhow::{bail, Context as _, Result};
+use clap::Parser as _;
+
+use crate::options::{Command, DevelOptions, Options, ScriptLine};
+
+pub fn run(options: &Options, path: &Path) -> Result<()> {
+    let script = std::fs::read_to_string(path).context("read script")?;
+
+    // Device is found once, so that all commands program the same one
+    // even if another keyboard is plugged in meanwhile. It is also opened
+    // once, by the first command programming it, and kept open until script ends.
+    let mut devel_options = options.devel_options.clone();
+    let device = crate::find_device(options)?;
+    devel_options.product_id = Some(device.product_id());
+    devel_options.address = Some(device.address());
+
+    crate::with_session(|| run_lines(options, &devel_options, &script))
+}
+
+fn run_lines(options: &Options, devel_options: &DevelOptions, script: &str) -> Result<()> {
+    for (number, line) in script.lines().enumerate() {
+        let Some(args) = split_line(line).with_context(|| format!("line {}", number + 1))? else {
+            continue;
+        };
+        let ScriptLine { command } = ScriptLine::try_parse_from(&args)
+            .with_context(|| format!("line {}", number + 1))?;
+        if matches!(command, Command::Run(_)) {
+            bail!("line {}: scripts can't be nested", number + 1);
+        }
+
+        let line_options = Options {
+            command,
+            expect_model: options.expect_model,
+            timeout_ms: options.timeout_ms,
+            retries: options.retries,
+            msg_delay_ms: options.msg_delay_ms,
+            force_protocol: options.force_protocol,
+            log_format: options.log_format,
+            backend: options.backend,
+            lang: options.lang,
+            quiet: options.quiet,
+            devel_options: devel_options.clone(),
+        };
+        crate::run(&line_options).with_context(|| format!("line {}: {}", number + 1, line.trim()))?;
+    }
+    Ok(())
+}
+
+/// Splits line into arguments, which may be quoted with single or double quotes.
+/// Returns `None` for empty lines and comments.
+fn split_line(line: &str) -> Result<Option<Vec<String>>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => arg.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (None, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("unterminated quote");
+    }
+    args.extend(arg);
+    Ok(Some(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_line() -> Result<()> {
+        assert_eq!(split_line("  ")?, None);
+        assert_eq!(split_line(" # led 1")?, None);
+        assert_eq!(split_line("led 1  --layers 1,3")?, Some(vec!["led".to_owned(), "1".to_owned(), "--layers".to_owned(), "1,3".to_owned()]));
+        assert_eq!(
+            split_line(r#"bind --button 1 "ctrl-c" 'my config.yaml' """#)?,
+            Some(vec!["bind", "--button", "1", "ctrl-c", "my config.yaml", ""].into_iter().map(String::from).collect()),
+        );
+        assert!(split_line("upload 'work.yaml").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert!(matches!(ScriptLine::try_parse_from(["led", "2"]), Ok(ScriptLine { command: Command::Led(_) })));
+        assert!(ScriptLine::try_parse_from(["blink"]).is_err());
+    }
+}
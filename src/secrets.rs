@@ -0,0 +1,156 @@
+//! Secrets referenced from config as `secret:NAME`, so that text like passwords
+//! isn't kept in config file. Secret is read when config is loaded and typed
+//! by macro as text.
+
+use std::process::Command;
+
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+use itertools::Itertools as _;
+
+use crate::keyboard::{Accord, Macro, Modifier, Modifiers, WellKnownCode};
+
+/// Service name secrets are stored under in keychain.
+pub const SERVICE: &str = "ch57x-keyboard-tool";
+
+/// Source of secret values by name.
+pub trait SecretProvider: std::fmt::Debug {
+    fn lookup(&self, name: &str) -> Result<String>;
+}
+
+/// OS keychain: Keychain on macOS, Secret Service on Linux (through `secret-tool`).
+/// Secrets are stored with service `ch57x-keyboard-tool` and account or attribute `name`.
+#[derive(Debug)]
+pub struct Keychain;
+
+impl SecretProvider for Keychain {
+    fn lookup(&self, name: &str) -> Result<String> {
+        let mut command = if cfg!(target_os = "macos") {
+            let mut command = Command::new("security");
+            command.args(["find-generic-password", "-s", SERVICE, "-a", name, "-w"]);
+            command
+        } else if cfg!(windows) {
+            bail!("secrets aren't supported on Windows yet");
+        } else {
+            let mut command = Command::new("secret-tool");
+            command.args(["lookup", "service", SERVICE, "name", name]);
+            command
+        };
+        let output = command.output().with_context(|| format!("run {:?}", command.get_program()))?;
+        ensure!(output.status.success(), "secret '{name}' not found in keychain");
+        let secret = String::from_utf8(output.stdout).context("secret isn't valid UTF-8")?;
+        // Tools print newline after secret.
+        Ok(secret.strip_suffix('\n').unwrap_or(&secret).to_owned())
+    }
+}
+
+/// Names of secrets by macros typing them, so that `secret:NAME` is shown
+/// in messages instead of secret text.
+#[derive(Debug, Clone, Default)]
+pub struct SecretNames(Vec<(Macro, String)>);
+
+impl SecretNames {
+    pub fn insert(&mut self, macro_: Macro, name: &str) {
+        if self.name(&macro_).is_none() {
+            self.0.push((macro_, name.to_owned()));
+        }
+    }
+
+    /// Whether config has no secrets.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Name of secret typed by macro, `None` if macro doesn't type secret.
+    pub fn name(&self, macro_: &Macro) -> Option<&str> {
+        self.0.iter().find(|(m, _)| m == macro_).map(|(_, name)| name.as_str())
+    }
+
+    /// Macro as it may be shown to user.
+    pub fn show(&self, macro_: &Macro) -> String {
+        match self.name(macro_) {
+            Some(name) => format!("secret:{name}"),
+            None => macro_.to_string(),
+        }
+    }
+
+    /// Same as `show`, for macro already formatted as text.
+    pub fn show_text(&self, text: &str) -> String {
+        match self.0.iter().find(|(m, _)| m.to_string() == text) {
+            Some((_, name)) => format!("secret:{name}"),
+            None => text.to_owned(),
+        }
+    }
+}
+
+/// Accord typing given character with US keyboard layout.
+fn accord(c: char) -> Option<Accord> {
+    use WellKnownCode::*;
+
+    let unshifted = |code: WellKnownCode| Accord::new(Modifiers::empty(), Some(code.into()));
+    let shifted = |code: WellKnownCode| Accord::new(Modifier::Shift, Some(code.into()));
+    let digits = [N0, N1, N2, N3, N4, N5, N6, N7, N8, N9];
+    let accord = match c {
+        'a'..='z' | 'A'..='Z' => {
+            let code = c.to_ascii_lowercase().to_string().parse::<WellKnownCode>().ok()?;
+            if c.is_ascii_uppercase() { shifted(code) } else { unshifted(code) }
+        }
+        '0'..='9' => unshifted(digits[c as usize - '0' as usize]),
+        ')' => shifted(N0), '!' => shifted(N1), '@' => shifted(N2), '#' => shifted(N3), '$' => shifted(N4),
+        '%' => shifted(N5), '^' => shifted(N6), '&' => shifted(N7), '*' => shifted(N8), '(' => shifted(N9),
+        ' ' => unshifted(Space),
+        '-' => unshifted(Minus), '_' => shifted(Minus),
+        '=' => unshifted(Equal), '+' => shifted(Equal),
+        '[' => unshifted(LeftBracket), '{' => shifted(LeftBracket),
+        ']' => unshifted(RightBracket), '}' => shifted(RightBracket),
+        '\\' => unshifted(Backslash), '|' => shifted(Backslash),
+        ';' => unshifted(Semicolon), ':' => shifted(Semicolon),
+        '\'' => unshifted(Quote), '"' => shifted(Quote),
+        '`' => unshifted(Grave), '~' => shifted(Grave),
+        ',' => unshifted(Comma), '<' => shifted(Comma),
+        '.' => unshifted(Dot), '>' => shifted(Dot),
+        '/' => unshifted(Slash), '?' => shifted(Slash),
+        _ => return None,
+    };
+    Some(accord)
+}
+
+/// Macro typing given text, as it is written in config.
+pub fn typing_macro(text: &str) -> Result<String> {
+    ensure!(!text.is_empty(), "secret is empty");
+    let accords = text.chars()
+        .map(|c| accord(c).ok_or_else(|| anyhow!("secret contains character which can't be typed")))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(accords.iter().join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::keyboard::Macro;
+
+    #[test]
+    fn test_typing_macro() -> Result<()> {
+        assert_eq!(typing_macro("aB1!")?, "a,shift-b,1,shift-1");
+        assert_eq!(typing_macro("x y")?, "x,space,y");
+        // Typed text must be valid macro.
+        let text = "a-Z_0)[]{}\\|;:'\",.<>/?`~";
+        assert!(typing_macro(text)?.parse::<Macro>().is_ok());
+        assert!(typing_macro("пароль").is_err());
+        assert!(typing_macro("").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_secret_names() -> Result<()> {
+        let mut names = SecretNames::default();
+        assert!(names.is_empty());
+        names.insert(typing_macro("12Ab")?.parse()?, "pin");
+        assert_eq!(names.show(&"1,2,shift-a,b".parse()?), "secret:pin");
+        assert_eq!(names.show(&"1,2".parse()?), "1,2");
+        let text = "1,2,shift-a,b".parse::<Macro>()?.to_string();
+        assert_eq!(names.show_text(&text), "secret:pin");
+        assert!(!names.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+//! Local HTTP API for GUI frontends.
+//!
+//! Requests are served one by one, since device can't be used by several of
+//! them at once. Responses are JSON objects, errors are returned with 4xx or
+//! 5xx status as `{"error": "..."}`. Endpoints:
+//!
+//! * `GET /devices`: connected keyboards.
+//! * `POST /validate`: validates config given as body, returns lint warnings.
+//! * `POST /upload`: uploads config given as body to device chosen by command line options.
+//! * `POST /led`: sets LED mode, body is like `{"index": 1}`.
+//! * `GET /monitor`: streams key events as JSON lines until client disconnects.
+//!
+//! Config is given with `Content-Type: application/json` or `application/yaml`.
+//!
+//! Server listens on loopback address only. Web pages may send requests to local
+//! addresses too, so each request must carry `Authorization: Bearer <token>` with
+//! token printed at start. Requests from browsers (with `Origin` header) and with
+//! non-loopback `Host` (DNS rebinding) are rejected as well.
+//!
+//! Since requests are served one by one, request must be received in time and its
+//! lines and body are limited in size, so that single client can't hold server.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher as _, Hasher as _};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs as _};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use ch57x_keyboard::config::{Config, ConfigFormat, TargetOs};
+use ch57x_keyboard::device::InputReports;
+use ch57x_keyboard::lint;
+use ch57x_keyboard::monitor;
+
+use crate::options::{ConfigParams, Options, UploadCommand};
+use crate::Uploaded;
+
+/// Bodies larger than this are rejected, configs are much smaller.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Request and header lines longer than this are rejected.
+const MAX_LINE_SIZE: usize = 8 * 1024;
+
+/// Requests with more headers than this are rejected.
+const MAX_HEADERS: usize = 64;
+
+/// Time whole request must be received in.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub content_type: Option<String>,
+    pub host: Option<String>,
+    pub origin: Option<String>,
+    /// Token from `Authorization: Bearer <token>` header.
+    pub token: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LedRequest {
+    index: u8,
+}
+
+pub fn serve(options: &Options, listen: &str) -> Result<()> {
+    // Token and Host checks only protect from web pages, not from other machines.
+    let addresses = listen.to_socket_addrs().with_context(|| format!("resolve {listen}"))?.collect::<Vec<_>>();
+    ensure!(!addresses.is_empty() && addresses.iter().all(|address| address.ip().is_loopback()),
+            "API may only listen on loopback address, got '{listen}'");
+    let listener = TcpListener::bind(addresses.as_slice()).with_context(|| format!("listen on {listen}"))?;
+    let token = new_token();
+    // Upload progress isn't shown to API clients.
+    crate::set_quiet(true);
+    println!("listening on http://{}", listener.local_addr()?);
+    println!("token: {token}");
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("warning: can't accept connection: {err}");
+                continue;
+            }
+        };
+        if let Err(err) = handle(options, &token, &mut stream) {
+            eprintln!("warning: request failed: {err:#}");
+        }
+    }
+    Ok(())
+}
+
+/// Random token, hashers are seeded with random keys by standard library.
+fn new_token() -> String {
+    let mut token = String::new();
+    for _ in 0..2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+        token += &format!("{:016x}", hasher.finish());
+    }
+    token
+}
+
+/// Whether host header names loopback address, port is ignored.
+fn is_loopback_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => host.split(':').next().unwrap_or(host),
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Checks that request comes from local program knowing token, not from web page.
+fn check_access(request: &Request, token: &str) -> Result<(), (u16, &'static str)> {
+    if request.origin.is_some() {
+        return Err((403, "requests from web pages are not allowed"));
+    }
+    if !request.host.as_deref().is_some_and(is_loopback_host) {
+        return Err((403, "Host header must name loopback address"));
+    }
+    // Compared without early exit, so that token can't be guessed by timing.
+    let given = request.token.as_deref().unwrap_or("").as_bytes();
+    let matches = given.len() == token.len()
+        && given.iter().zip(token.as_bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    if !matches {
+        return Err((401, "missing or wrong token, pass one printed by serve as 'Authorization: Bearer <token>'"));
+    }
+    Ok(())
+}
+
+fn handle(options: &Options, token: &str, stream: &mut TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let reader = DeadlineReader { inner: &mut *stream, deadline: Instant::now() + REQUEST_TIMEOUT };
+    let request = match read_request(&mut BufReader::new(reader)) {
+        Ok(request) => request,
+        Err(err) => return respond(stream, 400, &json!({ "error": format!("{err:#}") })),
+    };
+    if let Err((status, error)) = check_access(&request, token) {
+        return respond(stream, status, &json!({ "error": error }));
+    }
+
+    let result = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/monitor") => match crate::find_device(options).and_then(|device| device.open_input()) {
+            Ok(input) => return stream_events(input, stream),
+            Err(err) => Err(err),
+        },
+        ("GET", "/devices") => devices(options),
+        ("POST", "/validate") => validate(&request),
+        ("POST", "/upload") => upload(options, &request),
+        ("POST", "/led") => led(options, &request),
+        _ => return respond(stream, 404, &json!({ "error": "not found" })),
+    };
+    match result {
+        Ok(body) => respond(stream, 200, &body),
+        Err(err) => respond(stream, 500, &json!({ "error": format!("{err:#}") })),
+    }
+}
+
+fn devices(options: &Options) -> Result<Value> {
+    let devices = crate::enumerate_devices(options, &crate::device_filter(options))?;
+    Ok(Value::Array(devices.iter().map(|device| {
+        let (bus, address) = device.address();
+        json!({
+            "vendor_id": device.vendor_id(),
+            "product_id": device.product_id(),
+            "address": format!("{bus}:{address}"),
+            "location": device.location(),
+            "firmware": device.key().release(),
+            "model": device.model().map(|model| model.to_string()),
+        })
+    }).collect()))
+}
+
+/// Reader failing once deadline passes, so that client sending request slowly
+/// can't hold server, which read timeout alone doesn't prevent.
+struct DeadlineReader<R> {
+    inner: R,
+    deadline: Instant,
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if Instant::now() >= self.deadline {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "request isn't received in time"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+fn load_config(request: &Request) -> Result<Config> {
+    let media_type = request.content_type.as_deref().map(|t| t.split(';').next().unwrap_or(t).trim());
+    let format = match media_type {
+        Some("application/json") => ConfigFormat::Json,
+        Some("application/yaml" | "application/x-yaml" | "text/yaml") => ConfigFormat::Yaml,
+        _ => bail!("config must be sent with Content-Type application/json or application/yaml"),
+    };
+    // Parents would be read from server's files. JSON is parsed as YAML too.
+    let value: serde_yaml::Value = serde_yaml::from_slice(&request.body).context("parse config")?;
+    ensure!(value.get("extends").is_none(), "'extends' isn't allowed in configs sent through API");
+    Config::from_reader(request.body.as_slice(), format, None)
+}
+
+fn validate(request: &Request) -> Result<Value> {
+    let config = load_config(request)?;
+    let secrets = config.secrets.clone();
+    let layers = config.render()?;
+    let warnings = lint::lint(&layers, &secrets).iter().map(|w| w.to_string()).collect::<Vec<_>>();
+    Ok(json!({ "warnings": warnings }))
+}
+
+fn upload(options: &Options, request: &Request) -> Result<Value> {
+    let rendered = crate::render(load_config(request)?, TargetOs::host())?;
+    let device = crate::find_device(options)?;
+    let command = UploadCommand::defaults(ConfigParams {
+        config_path: None, format: None, allow_env: false, allow_secrets: false, sha256: None, target_os: TargetOs::host(), device: None,
+    });
+    Ok(match crate::upload(options, &device, &rendered, &command)? {
+        Uploaded::UpToDate => json!({ "status": "up-to-date" }),
+        Uploaded::Bindings { count, .. } => json!({ "status": "uploaded", "count": count }),
+    })
+}
+
+fn led(options: &Options, request: &Request) -> Result<Value> {
+    let LedRequest { index } = serde_json::from_slice(&request.body).context("parse request")?;
+    crate::set_led(options, &crate::find_device(options)?, index)?;
+    Ok(json!({ "status": "ok" }))
+}
+
+/// Streams events until client disconnects, which is noticed on next event.
+fn stream_events(mut input: InputReports, stream: &mut TcpStream) -> Result<()> {
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n")?;
+    loop {
+        let (protocol, report) = input.read()?;
+        let event = monitor::decode(protocol, &report);
+        if event.is_release() {
+            continue;
+        }
+        let line = json!({ "event": event.to_string() }).to_string() + "\n";
+        if stream.write_all(line.as_bytes()).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads line, failing if it is longer than `MAX_LINE_SIZE`.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> Result<()> {
+    line.clear();
+    reader.take(MAX_LINE_SIZE as u64 + 1).read_line(line)?;
+    ensure!(line.len() <= MAX_LINE_SIZE, "line is too long");
+    Ok(())
+}
+
+pub fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let mut line = String::new();
+    read_line(reader, &mut line).context("read request line")?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("invalid request line");
+    };
+    // Query isn't used by any endpoint.
+    let path = target.split('?').next().unwrap_or(target).to_owned();
+    let method = method.to_owned();
+
+    let mut content_length = 0;
+    let mut content_type = None;
+    let mut host = None;
+    let mut origin = None;
+    let mut token = None;
+    for count in 0.. {
+        ensure!(count <= MAX_HEADERS, "too many headers");
+        read_line(reader, &mut line).context("read header")?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| anyhow!("invalid header '{header}'"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse().context("invalid Content-Length")?;
+        } else if name.eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.to_ascii_lowercase());
+        } else if name.eq_ignore_ascii_case("host") {
+            host = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("origin") {
+            origin = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("authorization") {
+            token = value.strip_prefix("Bearer ").map(|token| token.trim().to_owned());
+        }
+    }
+    ensure!(content_length <= MAX_BODY_SIZE, "request body is too large");
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).context("read body")?;
+    Ok(Request { method, path, content_type, host, origin, token, body })
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() -> Result<()> {
+        let raw = "POST /validate?x=1 HTTP/1.1\r\nHost: localhost\r\nContent-Type: Application/JSON\r\ncontent-length: 2\r\n\r\n{}";
+        let request = read_request(&mut raw.as_bytes())?;
+        assert_eq!(request, Request {
+            method: "POST".to_owned(),
+            path: "/validate".to_owned(),
+            content_type: Some("application/json".to_owned()),
+            host: Some("localhost".to_owned()),
+            origin: None,
+            token: None,
+            body: b"{}".to_vec(),
+        });
+
+        let request = read_request(&mut "GET /devices HTTP/1.1\r\n\r\n".as_bytes())?;
+        assert_eq!((request.method.as_str(), request.path.as_str(), request.body.len()), ("GET", "/devices", 0));
+
+        assert!(read_request(&mut "\r\n".as_bytes()).is_err());
+        let long = format!("GET /devices HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(MAX_LINE_SIZE));
+        assert!(read_request(&mut long.as_bytes()).is_err());
+        let many = format!("GET /devices HTTP/1.1\r\n{}\r\n", "X: 1\r\n".repeat(MAX_HEADERS + 1));
+        assert!(read_request(&mut many.as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_access() -> Result<()> {
+        let request = |headers: &str| read_request(&mut format!("POST /upload HTTP/1.1\r\n{headers}\r\n").as_bytes());
+        let ok = request("Host: 127.0.0.1:9867\r\nAuthorization: Bearer secret\r\n")?;
+        assert_eq!(check_access(&ok, "secret"), Ok(()));
+        assert_eq!(check_access(&ok, "other!").map_err(|e| e.0), Err(401));
+
+        let no_token = request("Host: localhost:9867\r\n")?;
+        assert_eq!(check_access(&no_token, "secret").map_err(|e| e.0), Err(401));
+        let from_page = request("Host: [::1]:9867\r\nOrigin: https://example.com\r\nAuthorization: Bearer secret\r\n")?;
+        assert_eq!(check_access(&from_page, "secret").map_err(|e| e.0), Err(403));
+        let rebound = request("Host: evil.example.com:9867\r\nAuthorization: Bearer secret\r\n")?;
+        assert_eq!(check_access(&rebound, "secret").map_err(|e| e.0), Err(403));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_content_type() -> Result<()> {
+        let request = |content_type: &str| read_request(&mut format!(
+            "POST /validate HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: 2\r\n\r\n{{}}").as_bytes());
+        assert!(load_config(&request("text/plain")?).unwrap_err().to_string().contains("Content-Type"));
+        // Type is accepted, but config itself is incomplete.
+        assert!(!load_config(&request("application/json; charset=utf-8")?).unwrap_err().to_string().contains("Content-Type"));
+
+        let extends = read_request(&mut concat!(
+            "POST /validate HTTP/1.1\r\nContent-Type: application/yaml\r\nContent-Length: 21\r\n\r\n",
+            "extends: /etc/passwd\n").as_bytes())?;
+        assert!(load_config(&extends).unwrap_err().to_string().contains("'extends' isn't allowed"));
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Cache of bindings last uploaded to each device.
+//!
+//! Keyboards can't be read back, so the only way to know what is programmed
+//! is to remember what was uploaded. State is kept in user cache directory,
+//! one file per device model and USB port it is plugged into, so that
+//! several pads of the same model don't share state.
+
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context as _, Result};
+use itertools::Itertools as _;
+use serde::{Deserialize, Serialize};
+
+use crate::config::FlatLayer;
+use crate::device::Device;
+
+/// Number of programming cycles after which user is warned about flash wear.
+pub const WRITE_COUNT_WARNING: u64 = 5000;
+
+/// What is known about device.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceState {
+    /// Uploaded bindings, `None` if they are unknown.
+    #[serde(default, rename = "layers")]
+    pub bindings: Option<Bindings>,
+    /// Number of times device was programmed by this tool.
+    #[serde(default)]
+    pub write_count: u64,
+    /// LED modes by layer, `None` if unknown.
+    #[serde(default)]
+    pub led: Vec<Option<u8>>,
+    /// Last whole config uploaded successfully, `None` if unknown.
+    #[serde(default)]
+    pub last_upload: Option<Upload>,
+}
+
+/// Whole config uploaded to device, kept to upload it again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Upload {
+    /// UNIX time of upload, in seconds.
+    pub time: u64,
+    /// Path to uploaded config, `None` if it was read from stdin.
+    pub source: Option<String>,
+    /// Config rendered like backup, see `backup::render_config`.
+    pub config: String,
+}
+
+/// Bindings uploaded to device, as they are displayed, by layer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Bindings(pub Vec<Vec<Binding>>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Binding {
+    pub key: String,
+    #[serde(rename = "macro")]
+    pub macro_: String,
+}
+
+/// Binding which differs between two states.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub layer: usize,
+    pub key: String,
+    /// Previous macro, `None` if key was not bound.
+    pub old: Option<String>,
+    /// New macro, `None` if key is not bound anymore.
+    pub new: Option<String>,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |m: &Option<String>| m.clone().unwrap_or_else(|| "(none)".to_owned());
+        write!(f, "layer {}, {}: {} → {}", self.layer, self.key, show(&self.old), show(&self.new))
+    }
+}
+
+impl DeviceState {
+    /// Loads state saved for device, default one if nothing was saved yet
+    /// or device is mock one.
+    pub fn load(device: &Device) -> Result<Self> {
+        if device.is_mock() {
+            return Ok(Self::default());
+        }
+        let path = state_path(device)?;
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).with_context(|| format!("read {}", path.display())),
+        };
+        serde_json::from_str(&content).with_context(|| format!("parse {}", path.display()))
+    }
+
+    pub fn set_led(&mut self, layer: usize, mode: u8) {
+        if self.led.len() <= layer {
+            self.led.resize(layer + 1, None);
+        }
+        self.led[layer] = Some(mode);
+    }
+
+    /// Whether LED modes are already set as given, `None` modes are ignored.
+    pub fn has_led(&self, modes: &[Option<u8>]) -> bool {
+        modes.iter().enumerate().all(|(layer, mode)| mode.is_none() || self.led.get(layer) == Some(mode))
+    }
+
+    /// Saves state for device, nothing is saved for mock one.
+    pub fn save(&self, device: &Device) -> Result<()> {
+        if device.is_mock() {
+            return Ok(());
+        }
+        let path = state_path(device)?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("write {}", path.display()))
+    }
+}
+
+impl Bindings {
+    pub fn from_layers(layers: &[FlatLayer]) -> Self {
+        let layers = layers.iter().map(|layer| {
+            layer.bindings()
+                .map(|(key, macro_)| Binding { key: key.to_string(), macro_: macro_.to_string() })
+                .collect()
+        }).collect();
+        Self(layers)
+    }
+
+    /// Replaces single binding, for keys bound without uploading whole config.
+    pub fn set(&mut self, layer: usize, key: String, macro_: String) {
+        if self.0.len() <= layer {
+            self.0.resize(layer + 1, vec![]);
+        }
+        let bindings = &mut self.0[layer];
+        let existing = bindings.iter_mut().find(|b| b.key == key);
+        let binding = Binding { key, macro_ };
+        match existing {
+            Some(existing) => *existing = binding,
+            None => bindings.push(binding),
+        }
+    }
+
+    /// Finds bindings which produce given chord first, with their layers.
+    pub fn find_by_first_chord<'a>(&'a self, chord: &'a str) -> impl Iterator<Item = (usize, &'a Binding)> + 'a {
+        self.0.iter().enumerate().flat_map(move |(layer, bindings)| {
+            bindings.iter()
+                .filter(move |b| b.macro_.split(',').next() == Some(chord))
+                .map(move |b| (layer, b))
+        })
+    }
+
+    /// Lists bindings changed in `new` ones compared to these.
+    pub fn changes(&self, new: &Bindings) -> Vec<Change> {
+        let mut changes = vec![];
+        for layer in 0..self.0.len().max(new.0.len()) {
+            let old_layer = self.0.get(layer).map_or(&[][..], |l| l.as_slice());
+            let new_layer = new.0.get(layer).map_or(&[][..], |l| l.as_slice());
+            let find = |bindings: &[Binding], key: &str| {
+                bindings.iter().find(|b| b.key == key).map(|b| b.macro_.clone())
+            };
+            for key in new_layer.iter().chain(old_layer).map(|b| &b.key).unique() {
+                let (old, new) = (find(old_layer, key), find(new_layer, key));
+                if old != new {
+                    changes.push(Change { layer, key: key.clone(), old, new });
+                }
+            }
+        }
+        changes
+    }
+}
+
+fn state_path(device: &Device) -> Result<PathBuf> {
+    let dir = dirs::cache_dir().ok_or_else(|| anyhow!("can't determine cache directory"))?;
+    let name = format!("{:04x}-{:04x}-{}.json", device.vendor_id(), device.product_id(), device.location());
+    Ok(dir.join("ch57x-keyboard-tool").join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bindings(layers: &[&[(&str, &str)]]) -> Bindings {
+        Bindings(layers.iter().map(|layer| {
+            layer.iter().map(|(key, macro_)| Binding { key: key.to_string(), macro_: macro_.to_string() }).collect()
+        }).collect())
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = bindings(&[&[("button 0", "a"), ("button 1", "b")], &[("button 0", "c")]]);
+        let new = bindings(&[&[("button 0", "a"), ("button 1", "ctrl-b"), ("button 2", "d")]]);
+
+        let change = |layer, key: &str, old: Option<&str>, new: Option<&str>| Change {
+            layer,
+            key: key.to_owned(),
+            old: old.map(str::to_owned),
+            new: new.map(str::to_owned),
+        };
+        assert_eq!(old.changes(&new), vec![
+            change(0, "button 1", Some("b"), Some("ctrl-b")),
+            change(0, "button 2", None, Some("d")),
+            change(1, "button 0", Some("c"), None),
+        ]);
+        assert_eq!(old.changes(&old), vec![]);
+        assert_eq!(change(0, "button 1", Some("b"), None).to_string(), "layer 0, button 1: b → (none)");
+    }
+
+    #[test]
+    fn test_find_by_first_chord() {
+        let b = bindings(&[&[("button 0", "a"), ("button 1", "a,b")], &[("button 0", "b,a")]]);
+        let found = b.find_by_first_chord("a").map(|(layer, b)| (layer, b.key.as_str())).collect_vec();
+        assert_eq!(found, vec![(0, "button 0"), (0, "button 1")]);
+    }
+
+    #[test]
+    fn test_set_binding() {
+        let mut b = bindings(&[&[("button 0", "a")]]);
+        b.set(0, "button 0".into(), "b".into());
+        b.set(0, "knob 0 cw".into(), "volumeup".into());
+        b.set(2, "button 1".into(), "c".into());
+        assert_eq!(b, bindings(&[
+            &[("button 0", "b"), ("knob 0 cw", "volumeup")],
+            &[],
+            &[("button 1", "c")],
+        ]));
+    }
+
+    #[test]
+    fn test_led() {
+        let mut state = DeviceState::default();
+        assert!(state.has_led(&[]));
+        assert!(!state.has_led(&[None, Some(1)]));
+        state.set_led(1, 1);
+        assert!(state.has_led(&[None, Some(1)]));
+        assert!(!state.has_led(&[Some(0), Some(1)]));
+    }
+
+    #[test]
+    fn test_state_without_write_count() {
+        // Saved before write counter was added.
+        let state: DeviceState = serde_json::from_str(r#"{"layers": [[{"key": "button 0", "macro": "a"}]]}"#).unwrap();
+        assert_eq!(state.bindings, Some(bindings(&[&[("button 0", "a")]])));
+        assert_eq!(state.write_count, 0);
+        assert_eq!(state.last_upload, None);
+    }
+}
@@ -0,0 +1,123 @@
+//! Log of messages exchanged with device.
+//!
+//! Each message is logged at debug level with `TARGET` target, either as
+//! text or, if enabled with `set_json`, as one JSON object per message, so
+//! that logs may be attached to issues and compared between runs. Messages
+//! are annotated with layer and key being bound, see `context`.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use itertools::Itertools as _;
+use log::{debug, log_enabled, Level};
+use serde::Serialize;
+
+use crate::keyboard::Key;
+
+pub const TARGET: &str = "ch57x_keyboard::traffic";
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = const { RefCell::new(Context { layer: None, key: None }) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From host to device.
+    Out,
+    /// From device to host.
+    In,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Context {
+    /// Zero-based layer index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    layer: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    direction: Direction,
+    endpoint: u8,
+    /// Hex string without separators.
+    bytes: String,
+    #[serde(flatten)]
+    context: &'a Context,
+}
+
+/// Switches messages to JSON format.
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+/// Annotates messages logged on current thread until returned guard is dropped.
+pub fn context(layer: u8, key: Option<Key>) -> ContextGuard {
+    let context = Context { layer: Some(layer), key: key.map(|k| k.to_string()) };
+    ContextGuard { previous: CONTEXT.with(|c| c.replace(context)) }
+}
+
+pub struct ContextGuard {
+    previous: Context,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = std::mem::replace(&mut self.previous, Context { layer: None, key: None });
+        CONTEXT.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+/// Logs message sent to or received from endpoint.
+pub fn record(direction: Direction, endpoint: u8, bytes: &[u8]) {
+    if !log_enabled!(target: TARGET, Level::Debug) {
+        return;
+    }
+    let line = CONTEXT.with(|c| format_message(JSON.load(Ordering::Relaxed), direction, endpoint, bytes, &c.borrow()));
+    debug!(target: TARGET, "{line}");
+}
+
+fn format_message(json: bool, direction: Direction, endpoint: u8, bytes: &[u8], context: &Context) -> String {
+    if json {
+        let message = Message { direction, endpoint, bytes: format!("{:02x}", bytes.iter().format("")), context };
+        return serde_json::to_string(&message).expect("message is always serializable");
+    }
+
+    let verb = match direction {
+        Direction::Out => "send to",
+        Direction::In => "receive on",
+    };
+    let mut line = format!("{verb} {endpoint:#04x}: {bytes:02x?}");
+    match (context.layer, &context.key) {
+        (Some(layer), Some(key)) => line.push_str(&format!(" (layer {layer}, {key})")),
+        (Some(layer), None) => line.push_str(&format!(" (layer {layer})")),
+        _ => {}
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_message() {
+        let none = Context { layer: None, key: None };
+        let key = Context { layer: Some(1), key: Some(Key::Button(3).to_string()) };
+
+        assert_eq!(format_message(false, Direction::In, 0x81, &[0x01, 0xab], &none), "receive on 0x81: [01, ab]");
+        assert_eq!(format_message(false, Direction::Out, 0x04, &[0xfe], &key), "send to 0x04: [fe] (layer 1, button 3)");
+        assert_eq!(
+            format_message(true, Direction::Out, 0x04, &[0x03, 0xfe], &key),
+            r#"{"direction":"out","endpoint":4,"bytes":"03fe","layer":1,"key":"button 3"}"#,
+        );
+        assert_eq!(
+            format_message(true, Direction::In, 0x81, &[], &none),
+            r#"{"direction":"in","endpoint":129,"bytes":""}"#,
+        );
+    }
+}
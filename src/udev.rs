@@ -0,0 +1,62 @@
+//! Udev rule giving logged-in users access to keyboards on Linux.
+
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::{ensure, Context as _, Result};
+
+pub const RULE_PATH: &str = "/etc/udev/rules.d/50-ch57x-keyboard-tool.rules";
+
+/// Generates rule for given vendor and product IDs.
+pub fn rule(ids: &[(u16, u16)]) -> String {
+    let mut rule = String::from("# Allows logged-in users to program ch57x macro keyboards, generated by ch57x-keyboard-tool.\n");
+    for (vendor_id, product_id) in ids {
+        rule.push_str(&format!(
+            "SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{vendor_id:04x}\", ATTRS{{idProduct}}==\"{product_id:04x}\", MODE=\"0660\", TAG+=\"uaccess\"\n"
+        ));
+    }
+    rule
+}
+
+/// Writes rule with sudo and applies it to connected devices.
+pub fn install(rule: &str) -> Result<()> {
+    let mut tee = Command::new("sudo")
+        .args(["tee", RULE_PATH])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("run sudo")?;
+    tee.stdin.take().expect("stdin is piped").write_all(rule.as_bytes()).context("write rule")?;
+    ensure!(tee.wait()?.success(), "can't write {RULE_PATH}");
+
+    sudo(&["udevadm", "control", "--reload-rules"])?;
+    sudo(&["udevadm", "trigger", "--subsystem-match=usb"])?;
+    sudo(&["udevadm", "settle"])?;
+    Ok(())
+}
+
+fn sudo(args: &[&str]) -> Result<()> {
+    let status = Command::new("sudo").args(args).status().context("run sudo")?;
+    ensure!(status.success(), "'{}' failed", args.join(" "));
+    Ok(())
+}
+
+/// Device node of USB device with given bus number and address.
+pub fn device_node((bus, address): (u8, u8)) -> PathBuf {
+    PathBuf::from(format!("/dev/bus/usb/{bus:03}/{address:03}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule() {
+        assert_eq!(rule(&[(0x1189, 0x8890)]), concat!(
+            "# Allows logged-in users to program ch57x macro keyboards, generated by ch57x-keyboard-tool.\n",
+            "SUBSYSTEM==\"usb\", ATTRS{idVendor}==\"1189\", ATTRS{idProduct}==\"8890\", MODE=\"0660\", TAG+=\"uaccess\"\n",
+        ));
+        assert_eq!(device_node((1, 23)), PathBuf::from("/dev/bus/usb/001/023"));
+    }
+}
@@ -0,0 +1,79 @@
+//! Check for newer releases on GitHub, done only on explicit request.
+
+use anyhow::{Context as _, Result};
+use serde::Deserialize;
+
+use ch57x_keyboard::keyboard::Model;
+
+const RELEASES_URL: &str = "https://api.github.com/repos/kriomant/ch57x-keyboard-tool/releases";
+
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub html_url: String,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+pub fn fetch_releases() -> Result<Vec<Release>> {
+    let response = ureq::get(RELEASES_URL)
+        .set("User-Agent", concat!("ch57x-keyboard-tool/", env!("CARGO_PKG_VERSION")))
+        .call()
+        .context("request releases from GitHub")?;
+    let body = response.into_string().context("read releases")?;
+    serde_json::from_str(&body).context("parse releases")
+}
+
+/// Parses version like `v1.5.0`, missing components are zeros.
+fn parse_version(s: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = s.trim_start_matches('v').split('.').map(|p| p.parse::<u32>());
+    let mut next = || parts.next().unwrap_or(Ok(0)).ok();
+    Some((next()?, next()?, next()?))
+}
+
+/// Releases newer than `current` version, oldest first.
+pub fn newer_releases<'a>(releases: &'a [Release], current: &str) -> Vec<&'a Release> {
+    let current = parse_version(current);
+    let mut newer: Vec<_> = releases.iter()
+        .filter_map(|r| Some((parse_version(&r.tag_name)?, r)))
+        .filter(|(version, _)| Some(*version) > current)
+        .collect();
+    newer.sort_by_key(|(version, _)| *version);
+    newer.into_iter().map(|(_, r)| r).collect()
+}
+
+/// Changelog lines mentioning given model.
+pub fn relevant_lines(body: &str, model: Model) -> Vec<&str> {
+    let keywords: &[&str] = match model {
+        Model::K884x => &["884x", "8840", "8842"],
+        Model::K8890 => &["8890"],
+    };
+    body.lines()
+        .map(str::trim)
+        .filter(|line| keywords.iter().any(|k| line.to_lowercase().contains(k)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(tag: &str) -> Release {
+        Release { tag_name: tag.to_owned(), html_url: String::new(), body: None }
+    }
+
+    #[test]
+    fn test_newer_releases() {
+        let releases = [release("v1.6.0"), release("v1.4.2"), release("v1.5.1"), release("nightly")];
+        let newer = newer_releases(&releases, "1.5.0");
+        assert_eq!(newer.iter().map(|r| r.tag_name.as_str()).collect::<Vec<_>>(), ["v1.5.1", "v1.6.0"]);
+        assert!(newer_releases(&releases, "1.6").is_empty());
+    }
+
+    #[test]
+    fn test_relevant_lines() {
+        let body = "* Fix uploads to 8842\n* Support media keys\n* 8890: LED modes";
+        assert_eq!(relevant_lines(body, Model::K884x), ["* Fix uploads to 8842"]);
+        assert_eq!(relevant_lines(body, Model::K8890), ["* 8890: LED modes"]);
+    }
+}